@@ -1,14 +1,16 @@
 //! Parsing module for [`Command`]s.
 
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take_until};
-use nom::combinator::{map, value};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
+use nom::combinator::{map, opt, value};
 use nom::multi::{many0, many1};
-use nom::sequence::{terminated, tuple};
+use nom::sequence::{delimited, terminated, tuple};
 use nom::{error::ErrorKind, Err, IResult, Needed};
 
 use crate::error::{Error, Result};
 
+use std::fmt;
+
 type In<'a> = &'a str;
 type Out<'a> = IResult<&'a str, &'a str>;
 type CmdOut<'a> = IResult<&'a str, Command<'a>>;
@@ -19,35 +21,144 @@ type Var<'a> = &'a str;
 pub enum Command<'a> {
     IfDef(Var<'a>),
     IfNDef(Var<'a>),
-    If(Var<'a>, Var<'a>),
+    /// Test, whether the named environment variable is set, even if empty.
+    IfSet(Var<'a>),
+    /// Test, whether the file at the given path exists.
+    IfExists(Var<'a>),
+    IfNExists(Var<'a>),
+    /// Compare both sides, `true` for the case-insensitive `~=`.
+    If(Var<'a>, Var<'a>, bool),
     Else,
     EndIf,
-    Ask(Var<'a>),
-    Option(Var<'a>),
+    /// A question with an optional default answer and tag, see `--answer-tag`.
+    Ask(Var<'a>, Option<Var<'a>>, Option<Var<'a>>),
+    AskText(Var<'a>, Var<'a>, Option<Var<'a>>),
+    /// An option of an `ASK` menu, `true` for the default `OPTION*`.
+    Option(Var<'a>, bool),
     EndAsk,
+    Echo(Var<'a>),
+    Warn(Var<'a>),
+    Abort(Var<'a>),
+    /// Define the substitution with the given name.
+    Set(Var<'a>, Var<'a>),
+    /// Repeat the block up to `ENDFOR` for every item, binding it to the name.
+    For(Var<'a>, Vec<Var<'a>>),
+    EndFor,
+    CommentStart,
+    CommentEnd,
     Comment,
 }
 
 impl<'a> Command<'a> {
     /// Parse a command from the given line.
     ///
+    /// The line may start with any of the `prefixes`, the longest matching prefix
+    /// is used. Whitespace after the prefix is skipped. Lines starting with a
+    /// prefix followed by `comment` are [`Command::Comment`]s.
+    ///
     /// # Returns
-    /// - `None`, if the input does not start with `prefix`,
+    /// - `None`, if the input does not start with any prefix,
     /// - `Some(cmd)`, if the parsing was successful.
     ///
     /// # Errors
-    /// If the line starts with `prefix`, but does not parse
+    /// If the line starts with a prefix, but does not parse
     /// successfully an [`Error`] is returned.
-    pub fn parse_from_line(prefix: In<'a>, input: In<'a>) -> Option<Result<Self>> {
-        let prefix = terminated(tag_from_prefix(prefix), ws_star);
+    pub fn parse_from_line(
+        prefixes: &[&str],
+        comment: In<'a>,
+        input: In<'a>,
+    ) -> Option<Result<Self>> {
+        let prefix = prefixes
+            .iter()
+            .filter(|prefix| input.starts_with(*prefix))
+            .max_by_key(|prefix| prefix.len())?;
+        let prefix = terminated(tag_from_prefix(&input[..prefix.len()]), ws_star);
         match prefix(input) {
-            Ok((rest, _)) => match parse_command(rest) {
+            Ok((rest, _)) => match parse_command(rest, comment) {
                 Ok((_, cmd)) => Some(Ok(cmd)),
                 Err(_) => Some(Err(Error::UnrecognizedPreprocessorInstruction(rest.into()))),
             },
             Err(_) => None,
         }
     }
+    /// Remove one backslash from a line, that escapes any of the `prefixes`.
+    ///
+    /// I.e. `\#~ IFDEF X` becomes `#~ IFDEF X` and `\\#~` becomes `\#~`.
+    /// Returns `None`, if the line does not start with backslashes followed by a prefix.
+    pub fn unescape_line(prefixes: &[&str], line: In<'a>) -> Option<In<'a>> {
+        let rest = line.strip_prefix('\\')?;
+        let unescaped = rest.trim_start_matches('\\');
+        if prefixes.iter().any(|prefix| unescaped.starts_with(prefix)) {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+    /// The arguments of this command, that are expanded before it is evaluated.
+    pub fn expanded_args(&self) -> Vec<Var<'a>> {
+        use Command::*;
+        match self {
+            IfDef(var) | IfNDef(var) | Echo(var) | Warn(var) | Abort(var) => vec![var],
+            If(var1, var2, _) => vec![var1, var2],
+            Set(_, value) => vec![value],
+            For(_, items) => items.clone(),
+            _ => vec![],
+        }
+    }
+    /// The instruction closing the block started by this command, if any.
+    pub fn closing_keyword(&self) -> Option<&'static str> {
+        use Command::*;
+        match self {
+            IfDef(_) | IfNDef(_) | IfSet(_) | IfExists(_) | IfNExists(_) | If(_, _, _) | Else => {
+                Some("ENDIF")
+            }
+            Ask(_, _, _) | Option(_, _) => Some("ENDASK"),
+            CommentStart => Some("ENDCOMMENT"),
+            For(_, _) => Some("ENDFOR"),
+            _ => None,
+        }
+    }
+}
+
+/// Shows the command like it is written after the prefix.
+impl<'a> fmt::Display for Command<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Command::*;
+        let default = |default: &std::option::Option<Var>| match default {
+            Some(default) => format!("[{}] ", default),
+            None => String::new(),
+        };
+        match self {
+            IfDef(var) => write!(f, "IFDEF {}", var),
+            IfNDef(var) => write!(f, "IFNDEF {}", var),
+            IfSet(name) => write!(f, "IFSET {}", name),
+            IfExists(path) => write!(f, "IFEXISTS {}", path),
+            IfNExists(path) => write!(f, "IFNEXISTS {}", path),
+            If(var1, var2, false) => write!(f, "IF {} == {}", var1, var2),
+            If(var1, var2, true) => write!(f, "IF {} ~= {}", var1, var2),
+            Else => write!(f, "ELSE"),
+            EndIf => write!(f, "ENDIF"),
+            Ask(question, def, tag) => {
+                let tag = tag.map(|tag| format!("[{}]", tag)).unwrap_or_default();
+                write!(f, "ASK{} {}{}", tag, default(def), question)
+            }
+            AskText(name, question, def) => {
+                write!(f, "ASK:{} {}{}", name, default(def), question)
+            }
+            Option(option, false) => write!(f, "OPTION {}", option),
+            Option(option, true) => write!(f, "OPTION* {}", option),
+            EndAsk => write!(f, "ENDASK"),
+            Echo(text) => write!(f, "ECHO {}", text),
+            Warn(message) => write!(f, "WARN {}", message),
+            Abort(message) => write!(f, "ABORT {}", message),
+            Set(name, value) => write!(f, "SET {} = {}", name, value),
+            For(name, items) => write!(f, "FOR {} IN {}", name, items.join(",")),
+            EndFor => write!(f, "ENDFOR"),
+            CommentStart => write!(f, "COMMENT"),
+            CommentEnd => write!(f, "ENDCOMMENT"),
+            Comment => write!(f, "#"),
+        }
+    }
 }
 
 fn ws<'a>(input: In<'a>) -> Out<'a> {
@@ -62,10 +173,21 @@ fn ws_star<'a>(input: In<'a>) -> Out<'a> {
     value(" ", many0(ws))(input)
 }
 
+/// Succeeds without consuming anything, if `input` does not continue a word.
+///
+/// Keywords must be followed by this, `ENDIFS` is not an `ENDIF`.
+fn word_boundary<'a>(input: In<'a>) -> Out<'a> {
+    if input.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        Err(Err::Error((input, ErrorKind::Tag)))
+    } else {
+        Ok((input, ""))
+    }
+}
+
 fn tag_from_prefix<'a>(prefix: In<'a>) -> impl Fn(&'a str) -> Out<'a> {
     move |input| {
-        if input.starts_with(prefix) {
-            Ok((&input[prefix.len()..], prefix))
+        if let Some(rest) = input.strip_prefix(prefix) {
+            Ok((rest, prefix))
         } else if input.len() < prefix.len() {
             if prefix.starts_with(input) {
                 Err(Err::Incomplete(Needed::Size(prefix.len())))
@@ -96,65 +218,266 @@ fn cmd_ifndef<'a>(input: In<'a>) -> CmdOut<'a> {
     })(input)
 }
 
+/// Parse `IFSET name`, the name may only be followed by whitespace and a comment,
+/// starting with `comment`.
+fn cmd_ifset<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    move |input| {
+        let tag_ifset = tag_no_case("IFSET");
+        let (input, (_, _, name, _)) = tuple((tag_ifset, ws_plus, name, ws_star))(input)?;
+        let (input, _) = opt(tuple((tag(comment), opt(rest))))(input)?;
+        if input.is_empty() || input.starts_with('\n') || input.starts_with("\r\n") {
+            Ok((input, Command::IfSet(name)))
+        } else {
+            Err(Err::Error((input, ErrorKind::Eof)))
+        }
+    }
+}
+
+fn cmd_ifexists<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_ifexists = tag_no_case("IFEXISTS");
+    map(tuple((tag_ifexists, ws_plus, rest)), |(_, _, path)| {
+        Command::IfExists(path.trim_end())
+    })(input)
+}
+
+fn cmd_ifnexists<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_ifnexists = tag_no_case("IFNEXISTS");
+    map(tuple((tag_ifnexists, ws_plus, rest)), |(_, _, path)| {
+        Command::IfNExists(path.trim_end())
+    })(input)
+}
+
+/// Parse `IF var1 == var2` or the case-insensitive `IF var1 ~= var2`.
+///
+/// The condition is split at the first operator, everything after it is `var2`, even if
+/// it contains another operator. Both sides are trimmed. Operators in the expansion of
+/// the sides are not special, as the condition is split before expanding it.
 fn cmd_if<'a>(input: In<'a>) -> CmdOut<'a> {
     let tag_if = tag_no_case("IF");
-    let tag_equals = tag("==");
-    let until_equals = take_until("==");
+    let operator = alt((value(false, tag("==")), value(true, tag("~="))));
     map(
         tuple((
             tag_if,
             ws_plus,
-            until_equals,
+            until_operator,
             ws_star,
-            tag_equals,
+            operator,
             ws_star,
             rest,
         )),
-        |(_, _, var1, _, _, _, var2)| Command::If(var1.trim(), var2.trim()),
+        |(_, _, var1, _, ignore_case, _, var2)| Command::If(var1.trim(), var2.trim(), ignore_case),
     )(input)
 }
 
-fn cmd_else<'a>(input: In<'a>) -> CmdOut<'a> {
-    value(Command::Else, tag_no_case("ELSE"))(input)
+/// Take everything up to the first operator, `==` or `~=`, whichever comes first.
+fn until_operator<'a>(input: In<'a>) -> Out<'a> {
+    let first = [input.find("=="), input.find("~=")]
+        .iter()
+        .flatten()
+        .min()
+        .copied();
+    match first {
+        Some(idx) => Ok((&input[idx..], &input[..idx])),
+        None => Err(Err::Error((input, ErrorKind::TakeUntil))),
+    }
 }
 
-fn cmd_endif<'a>(input: In<'a>) -> CmdOut<'a> {
-    value(Command::EndIf, tag_no_case("ENDIF"))(input)
+/// Parse a command without arguments, i.e. `ENDIF`.
+///
+/// The `keyword` may only be followed by whitespace and a comment, starting with `comment`.
+/// Anything else is an error, so that `ELSE something` does not silently drop `something`.
+fn nullary<'a>(
+    keyword: &'static str,
+    cmd: Command<'a>,
+    comment: In<'a>,
+) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    move |input| {
+        let (input, _) = terminated(tag_no_case(keyword), word_boundary)(input)?;
+        let (input, _) = ws_star(input)?;
+        let (input, _) = opt(tuple((tag(comment), opt(rest))))(input)?;
+        if input.is_empty() || input.starts_with('\n') || input.starts_with("\r\n") {
+            Ok((input, cmd.clone()))
+        } else {
+            Err(Err::Error((input, ErrorKind::Eof)))
+        }
+    }
+}
+
+fn cmd_else<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("ELSE", Command::Else, comment)
+}
+
+fn cmd_endif<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("ENDIF", Command::EndIf, comment)
+}
+
+fn default_answer<'a>(input: In<'a>) -> Out<'a> {
+    let bracketed = delimited(tag("["), is_not("]\r\n"), tag("]"));
+    terminated(bracketed, ws_plus)(input)
 }
 
 fn cmd_ask<'a>(input: In<'a>) -> CmdOut<'a> {
     let tag_ask = tag_no_case("ASK");
-    map(tuple((tag_ask, ws_plus, rest)), |(_, _, question)| {
-        Command::Ask(question)
-    })(input)
+    let question_tag = delimited(tag("["), name, tag("]"));
+    map(
+        tuple((
+            tag_ask,
+            opt(question_tag),
+            ws_plus,
+            opt(default_answer),
+            rest,
+        )),
+        |(_, question_tag, _, default, question)| Command::Ask(question, default, question_tag),
+    )(input)
+}
+
+fn name<'a>(input: In<'a>) -> Out<'a> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
+}
+
+fn cmd_ask_text<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_ask = tag_no_case("ASK:");
+    map(
+        tuple((tag_ask, name, ws_plus, opt(default_answer), rest)),
+        |(_, var, _, default, question)| Command::AskText(var, question, default),
+    )(input)
 }
 
 fn cmd_option<'a>(input: In<'a>) -> CmdOut<'a> {
     let tag_option = tag_no_case("OPTION");
-    map(tuple((tag_option, ws_plus, rest)), |(_, _, option)| {
-        Command::Option(option)
+    map(
+        tuple((tag_option, opt(tag("*")), ws_plus, rest)),
+        |(_, default, _, option)| Command::Option(option, default.is_some()),
+    )(input)
+}
+
+fn cmd_endask<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("ENDASK", Command::EndAsk, comment)
+}
+
+fn cmd_echo<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_echo = tag_no_case("ECHO");
+    map(tuple((tag_echo, ws_plus, rest)), |(_, _, text)| {
+        Command::Echo(text)
     })(input)
 }
 
-fn cmd_endask<'a>(input: In<'a>) -> CmdOut<'a> {
-    value(Command::EndAsk, tag_no_case("ENDASK"))(input)
+fn cmd_warn<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_warn = tag_no_case("WARN");
+    map(tuple((tag_warn, ws_plus, rest)), |(_, _, message)| {
+        Command::Warn(message)
+    })(input)
+}
+
+fn cmd_abort<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_abort = tag_no_case("ABORT");
+    map(tuple((tag_abort, ws_plus, rest)), |(_, _, message)| {
+        Command::Abort(message)
+    })(input)
+}
+
+fn cmd_set<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_set = tag_no_case("SET");
+    let tag_equals = tag("=");
+    map(
+        tuple((tag_set, ws_plus, name, ws_star, tag_equals, opt(rest))),
+        |(_, _, name, _, _, value)| Command::Set(name, value.unwrap_or_default().trim()),
+    )(input)
+}
+
+fn cmd_for<'a>(input: In<'a>) -> CmdOut<'a> {
+    let tag_for = tag_no_case("FOR");
+    let tag_in = tag_no_case("IN");
+    map(
+        tuple((tag_for, ws_plus, name, ws_plus, tag_in, ws_plus, rest)),
+        |(_, _, name, _, _, _, items)| {
+            Command::For(name, items.split(',').map(str::trim).collect())
+        },
+    )(input)
+}
+
+fn cmd_endfor<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("ENDFOR", Command::EndFor, comment)
+}
+
+fn cmd_comment_start<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("COMMENT", Command::CommentStart, comment)
 }
 
-fn cmd_comment<'a>(input: In<'a>) -> CmdOut<'a> {
-    value(Command::Comment, tag_no_case("#"))(input)
+fn cmd_comment_end<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    nullary("ENDCOMMENT", Command::CommentEnd, comment)
 }
 
-fn parse_command<'a>(input: In<'a>) -> CmdOut<'a> {
+fn cmd_comment<'a>(comment: In<'a>) -> impl Fn(In<'a>) -> CmdOut<'a> {
+    value(Command::Comment, tag_no_case(comment))
+}
+
+/// Remove a trailing comment from the argument `arg`, i.e. `FOO # note` becomes `FOO`.
+///
+/// The `comment` must be surrounded by whitespace (or end the line), so that values like
+/// `#fff` or `a#b` are kept.
+fn strip_comment<'a>(arg: Var<'a>, comment: In) -> Var<'a> {
+    if comment.is_empty() {
+        return arg;
+    }
+    let is_comment = |&(idx, _): &(usize, _)| {
+        let after = &arg[idx + comment.len()..];
+        arg[..idx].ends_with(char::is_whitespace)
+            && (after.is_empty() || after.starts_with(char::is_whitespace))
+    };
+    match arg.match_indices(comment).find(is_comment) {
+        Some((idx, _)) => arg[..idx].trim_end(),
+        None => arg,
+    }
+}
+
+/// Strip trailing comments from the arguments of conditions, questions and options.
+///
+/// Other arguments, like the text of `ECHO`, may contain the comment on purpose.
+fn strip_comments<'a>(cmd: Command<'a>, comment: In) -> Command<'a> {
+    use Command::*;
+    let strip = |arg| strip_comment(arg, comment);
+    match cmd {
+        IfDef(var) => IfDef(strip(var)),
+        IfNDef(var) => IfNDef(strip(var)),
+        IfExists(path) => IfExists(strip(path)),
+        IfNExists(path) => IfNExists(strip(path)),
+        If(var1, var2, ignore_case) => If(var1, strip(var2), ignore_case),
+        Ask(question, default, tag) => Ask(strip(question), default, tag),
+        AskText(name, question, default) => AskText(name, strip(question), default),
+        Option(option, default) => Option(strip(option), default),
+        cmd => cmd,
+    }
+}
+
+fn parse_command<'a>(input: In<'a>, comment: In<'a>) -> CmdOut<'a> {
+    let (rest, cmd) = parse_command_with_comment(input, comment)?;
+    Ok((rest, strip_comments(cmd, comment)))
+}
+
+fn parse_command_with_comment<'a>(input: In<'a>, comment: In<'a>) -> CmdOut<'a> {
     alt((
         cmd_ifdef,
         cmd_ifndef,
+        cmd_ifset(comment),
+        cmd_ifexists,
+        cmd_ifnexists,
         cmd_if,
-        cmd_else,
-        cmd_endif,
+        cmd_else(comment),
+        cmd_endif(comment),
+        cmd_ask_text,
         cmd_ask,
         cmd_option,
-        cmd_endask,
-        cmd_comment,
+        cmd_endask(comment),
+        cmd_echo,
+        cmd_warn,
+        cmd_abort,
+        cmd_set,
+        cmd_for,
+        cmd_endfor(comment),
+        cmd_comment_start(comment),
+        cmd_comment_end(comment),
+        cmd_comment(comment),
     ))(input)
 }
 
@@ -216,68 +539,335 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_cmd_ifset() {
+        let res = cmd_ifset("#")("IFSET XDG_CONFIG_HOME \nblub");
+        assert_eq!(res.unwrap(), ("\nblub", Command::IfSet("XDG_CONFIG_HOME")));
+        let res = cmd_ifset("#")("ifset x");
+        assert_eq!(res.unwrap(), ("", Command::IfSet("x")));
+        let res = cmd_ifset("#")("IFSET x # note");
+        assert_eq!(res.unwrap(), ("", Command::IfSet("x")));
+        // Only a plain name is accepted
+        assert!(cmd_ifset("#")("IFSET $HOME").is_err());
+        assert!(cmd_ifset("#")("IFSET HOME USER").is_err());
+        assert!(cmd_ifset("#")("IFSETHOME").is_err());
+    }
+
+    #[test]
+    fn test_cmd_ifexists() {
+        let res = cmd_ifexists("IFEXISTS ~/.config/app.toml \nblub");
+        assert_eq!(
+            res.unwrap(),
+            ("\nblub", Command::IfExists("~/.config/app.toml"))
+        );
+        let res = cmd_ifnexists("ifnexists $HOME/file");
+        assert_eq!(res.unwrap(), ("", Command::IfNExists("$HOME/file")));
+        assert!(cmd_ifexists("IFEXISTSfile").is_err());
+        assert!(cmd_ifnexists("IFNEXISTS").is_err());
+    }
+
     #[test]
     fn test_cmd_if() {
         let res = cmd_if("iF x\t== \ty");
-        assert_eq!(res.unwrap(), ("", Command::If("x", "y")));
+        assert_eq!(res.unwrap(), ("", Command::If("x", "y", false)));
         let res = cmd_if("iF x == \t");
         assert!(res.is_err());
+        // Split at the first `==`, the rest is kept verbatim
+        let res = cmd_if("IF $A == foo==bar");
+        assert_eq!(res.unwrap(), ("", Command::If("$A", "foo==bar", false)));
+        let res = cmd_if("IF foo==bar == baz");
+        assert_eq!(res.unwrap(), ("", Command::If("foo", "bar == baz", false)));
+        let res = cmd_if("IF $EDITOR ~= vim");
+        assert_eq!(res.unwrap(), ("", Command::If("$EDITOR", "vim", true)));
+        // The first operator splits, regardless of its kind
+        let res = cmd_if("IF x~=y == x~=y");
+        assert_eq!(res.unwrap(), ("", Command::If("x", "y == x~=y", true)));
+        let res = cmd_if("IF $EDITOR ~= a==b");
+        assert_eq!(res.unwrap(), ("", Command::If("$EDITOR", "a==b", true)));
     }
 
     #[test]
     fn test_cmd_else() {
-        assert_eq!(cmd_else("elSExyz").unwrap(), ("xyz", Command::Else));
-        assert!(cmd_else("elSxyz").is_err());
+        assert_eq!(cmd_else("#")("elSE").unwrap(), ("", Command::Else));
+        assert!(cmd_else("#")("elSxyz").is_err());
+        // Only whitespace and comments may follow
+        assert!(cmd_else("#")("elSExyz").is_err());
+        assert!(cmd_else("#")("ELSE something").is_err());
+        assert_eq!(
+            cmd_else("#")("ELSE \t# b\n").unwrap(),
+            ("\n", Command::Else)
+        );
     }
 
     #[test]
     fn test_cmd_endif() {
-        assert_eq!(cmd_endif("ENDifblab").unwrap(), ("blab", Command::EndIf));
-        assert!(cmd_else("EDIF").is_err());
+        assert_eq!(cmd_endif("#")("ENDif ").unwrap(), ("", Command::EndIf));
+        assert_eq!(
+            cmd_endif("//")("ENDIF // done").unwrap(),
+            ("", Command::EndIf)
+        );
+        assert!(cmd_endif("//")("ENDIF # done").is_err());
+        assert!(cmd_endif("#")("ENDifblab").is_err());
+        assert!(cmd_endif("#")("EDIF").is_err());
     }
 
     #[test]
     fn test_cmd_ask() {
         assert_eq!(
             cmd_ask("asK\t\tblamber\nblab").unwrap(),
-            ("\nblab", Command::Ask("blamber"))
+            ("\nblab", Command::Ask("blamber", None, None))
         );
+        assert_eq!(
+            cmd_ask("ASK [y] Install extras?").unwrap(),
+            ("", Command::Ask("Install extras?", Some("y"), None))
+        );
+        assert_eq!(
+            cmd_ask("ASK [no question").unwrap(),
+            ("", Command::Ask("[no question", None, None))
+        );
+        assert_eq!(
+            cmd_ask("ASK[ui] [n] Enable animations?").unwrap(),
+            (
+                "",
+                Command::Ask("Enable animations?", Some("n"), Some("ui"))
+            )
+        );
+        assert!(cmd_ask("ASK[] Empty tag?").is_err());
         assert!(cmd_ask("ASK\t").is_err());
     }
 
+    #[test]
+    fn test_cmd_ask_text() {
+        assert_eq!(
+            cmd_ask_text("ASK:EMAIL What is your git email?").unwrap(),
+            (
+                "",
+                Command::AskText("EMAIL", "What is your git email?", None)
+            )
+        );
+        assert_eq!(
+            cmd_ask_text("ask:NAME\t[Max] Name?\n").unwrap(),
+            ("\n", Command::AskText("NAME", "Name?", Some("Max")))
+        );
+        assert!(cmd_ask_text("ASK: Name?").is_err());
+        assert!(cmd_ask_text("ASK:NAME").is_err());
+    }
+
+    #[test]
+    fn test_unescape_line() {
+        let prefix = &["#~"];
+        assert_eq!(
+            Command::unescape_line(prefix, "\\#~ IFDEF X"),
+            Some("#~ IFDEF X")
+        );
+        assert_eq!(
+            Command::unescape_line(prefix, "\\\\#~ ENDIF"),
+            Some("\\#~ ENDIF")
+        );
+        assert_eq!(Command::unescape_line(prefix, "#~ IFDEF X"), None);
+        assert_eq!(Command::unescape_line(prefix, "\\# text"), None);
+        assert!(Command::parse_from_line(prefix, "#", "\\#~ IFDEF X").is_none());
+    }
+
     #[test]
     fn test_cmd_option() {
         assert_eq!(
             cmd_option("OPTIOn\t one option\nnewline").unwrap(),
-            ("\nnewline", Command::Option("one option"))
+            ("\nnewline", Command::Option("one option", false))
+        );
+        assert_eq!(
+            cmd_option("option* the default").unwrap(),
+            ("", Command::Option("the default", true))
         );
         assert!(cmd_option("OPTIONN").is_err());
     }
 
     #[test]
     fn test_cmd_endask() {
-        assert_eq!(cmd_endask("endASKabc").unwrap(), ("abc", Command::EndAsk));
-        assert!(cmd_endask("endas").is_err());
+        assert_eq!(cmd_endask("#")("endASK#").unwrap(), ("", Command::EndAsk));
+        assert!(cmd_endask("#")("endASKabc").is_err());
+        assert!(cmd_endask("#")("endas").is_err());
+    }
+
+    #[test]
+    fn test_cmd_echo() {
+        assert_eq!(
+            cmd_echo("ECHO export PATH=$(pwd)\n").unwrap(),
+            ("\n", Command::Echo("export PATH=$(pwd)"))
+        );
+        assert!(cmd_echo("ECHO").is_err());
+    }
+
+    #[test]
+    fn test_cmd_warn() {
+        assert_eq!(
+            cmd_warn("WARN This block is obsolete").unwrap(),
+            ("", Command::Warn("This block is obsolete"))
+        );
+        assert!(cmd_warn("WARNING").is_err());
+    }
+
+    #[test]
+    fn test_cmd_abort() {
+        assert_eq!(
+            cmd_abort("abort Invalid combination\n").unwrap(),
+            ("\n", Command::Abort("Invalid combination"))
+        );
+        assert!(cmd_abort("ABORT").is_err());
+    }
+
+    #[test]
+    fn test_cmd_set() {
+        assert_eq!(
+            cmd_set("SET GREETING = Hello world \n").unwrap(),
+            ("\n", Command::Set("GREETING", "Hello world"))
+        );
+        assert_eq!(
+            cmd_set("set user=$USER").unwrap(),
+            ("", Command::Set("user", "$USER"))
+        );
+        assert_eq!(
+            cmd_set("SET EMPTY =").unwrap(),
+            ("", Command::Set("EMPTY", ""))
+        );
+        assert!(cmd_set("SET GREETING Hello").is_err());
+        assert!(cmd_set("SETGREETING = Hello").is_err());
+    }
+
+    #[test]
+    fn test_cmd_for() {
+        assert_eq!(
+            cmd_for("FOR host IN alpha, beta,gamma\n").unwrap(),
+            ("\n", Command::For("host", vec!["alpha", "beta", "gamma"]))
+        );
+        assert_eq!(
+            cmd_for("for host in $(cat hosts)").unwrap(),
+            ("", Command::For("host", vec!["$(cat hosts)"]))
+        );
+        assert!(cmd_for("FOR host alpha").is_err());
+        assert!(cmd_for("FORhost IN alpha").is_err());
+        assert_eq!(
+            cmd_endfor("#")("ENDFOR # hosts").unwrap(),
+            ("", Command::EndFor)
+        );
+    }
+
+    #[test]
+    fn test_cmd_comment_block() {
+        assert_eq!(
+            cmd_comment_start("#")("comment\n").unwrap(),
+            ("\n", Command::CommentStart)
+        );
+        assert_eq!(
+            cmd_comment_end("#")("ENDCOMMENT").unwrap(),
+            ("", Command::CommentEnd)
+        );
+        assert!(cmd_comment_start("#")("# comment").is_err());
+        assert!(cmd_comment_start("#")("COMMENTS").is_err());
     }
 
     #[test]
     fn test_comment() {
         assert_eq!(
-            cmd_comment("# some comment").unwrap(),
+            cmd_comment("#")("# some comment").unwrap(),
+            (" some comment", Command::Comment)
+        );
+        assert!(cmd_comment("#")("// not a comment").is_err());
+        assert_eq!(
+            cmd_comment("//")("// some comment").unwrap(),
             (" some comment", Command::Comment)
         );
-        assert!(cmd_comment("// not a comment").is_err());
     }
 
     #[test]
     fn command_test_from_line() {
-        let res = Command::parse_from_line("~~~", "~~ another line");
+        let res = Command::parse_from_line(&["~~~"], "#", "~~ another line");
         assert!(res.is_none());
 
-        let res = Command::parse_from_line("~~~", "~~~eLsE");
+        let res = Command::parse_from_line(&["~~~"], "#", "~~~eLsE");
+        assert_eq!(res.unwrap().unwrap(), Command::Else);
+
+        let res = Command::parse_from_line(&[" "], "#", " iF abc ==\txyz\t");
+        assert_eq!(res.unwrap().unwrap(), Command::If("abc", "xyz", false));
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let parse = |line| parse_command(line, "#").unwrap().1;
+        assert_eq!(parse("IFDEF FOO # note"), Command::IfDef("FOO"));
+        assert_eq!(parse("IFNDEF FOO\t#"), Command::IfNDef("FOO"));
+        assert_eq!(parse("IF $A == b # note"), Command::If("$A", "b", false));
+        let ask = Command::Ask("Laptop?", Some("y"), None);
+        assert_eq!(parse("ASK [y] Laptop? # note"), ask);
+        assert_eq!(parse("OPTION* red # default"), Command::Option("red", true));
+        // Comment characters in values are kept
+        let color = Command::If("$COLOR", "#fff", false);
+        assert_eq!(parse("IF $COLOR == #fff"), color);
+        assert_eq!(parse("IFDEF a#b"), Command::IfDef("a#b"));
+        assert_eq!(parse("ECHO x=1 # kept"), Command::Echo("x=1 # kept"));
+        let res = parse_command("IFDEF FOO // note", "//").unwrap().1;
+        assert_eq!(res, Command::IfDef("FOO"));
+    }
+
+    #[test]
+    fn test_multiple_prefixes() {
+        let prefixes = &["#", "//", "#~"];
+        let res = Command::parse_from_line(prefixes, "#", "// ENDIF");
+        assert_eq!(res.unwrap().unwrap(), Command::EndIf);
+        // The longest prefix wins, `#` alone would leave `~ ELSE`
+        let res = Command::parse_from_line(prefixes, "#", "#~ ELSE");
         assert_eq!(res.unwrap().unwrap(), Command::Else);
+        assert!(Command::parse_from_line(prefixes, "#", "-- ELSE").is_none());
+        assert!(Command::parse_from_line(&[], "#", "ELSE").is_none());
+    }
 
-        let res = Command::parse_from_line(" ", " iF abc ==\txyz\t");
-        assert_eq!(res.unwrap().unwrap(), Command::If("abc", "xyz"));
+    #[test]
+    fn test_display() {
+        let lines = &[
+            "IF $TERM == alacritty",
+            "IF $EDITOR ~= vim",
+            "IFNDEF $HOME",
+            "IFSET HOME",
+            "ASK [2] Which color?",
+            "ASK[ui] Enable animations?",
+            "ASK:EMAIL What is your git email?",
+            "OPTION red",
+            "ENDASK",
+            "COMMENT",
+            "SET NAME = Max",
+            "FOR host IN a,b",
+            "ENDFOR",
+        ];
+        for line in lines {
+            let cmd = Command::parse_from_line(&[""], "#", line).unwrap().unwrap();
+            assert_eq!(&cmd.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn test_keyword_boundaries() {
+        let lines = &[
+            "IFDEFINED x",
+            "IFDEF",
+            "IFx == x",
+            "ELSEWHERE",
+            "ENDIFS",
+            "ASKING what?",
+            "ENDASKED",
+            "COMMENTS",
+            "ENDCOMMENTS",
+            "ENDFORS",
+            "ECHOES",
+            "WARNED x",
+        ];
+        for line in lines {
+            assert!(parse_command(line, "#").is_err(), "{:?} was parsed", line);
+        }
+        // Comment strings made of letters must not stick to the keyword
+        assert!(parse_command("ENDIFREM", "REM").is_err());
+        assert_eq!(
+            parse_command("ENDIF REM done", "REM").unwrap(),
+            ("", Command::EndIf)
+        );
     }
 }
@@ -1,16 +1,30 @@
 use colored::Colorize;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::Write;
+use std::env;
+use std::fmt;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::path::Path;
 
 use crate::command::Command;
-use crate::env::expand;
+use crate::config::Substitutions;
+use crate::env::{expand, expand_env_path, CommandSettings};
 use crate::error::{Error, Result};
+use crate::Opt;
+
+lazy_static! {
+    /// Matches references to earlier answers, i.e. `answer_to("Question?")`.
+    static ref RE_ANSWER_TO: Regex = Regex::new(r#"answer_to\("([^"]*)"\)"#).unwrap();
+}
 
 /// A slice containing commands.
 ///
-/// The slice contains tuples with a line number and a [`Command`].
+/// The slice contains tuples with a line number and a [`Command`]. Line numbers
+/// are reported as they are, they should match the line in the file, starting at 1.
 type CmdLineSlice<'bor, 'str> = &'bor [(usize, Command<'str>)];
 
 /// An answer to a question.
@@ -22,314 +36,943 @@ enum Answer {
     Option(usize),
 }
 
+/// Where the answer to a question came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnswerSource {
+    /// The user answered the question.
+    Interactive,
+    /// The default was used in non-interactive mode.
+    Default,
+    /// The answer was given in the `--answers` file.
+    AnswersFile,
+    /// The answer was given for the tag of the question with `--answer-tag`.
+    Tag,
+    /// The question has been answered before.
+    Cached,
+}
+
+/// An answer given to a question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedAnswer {
+    /// The line of the question.
+    pub line_nr: usize,
+    /// The question asked.
+    pub question: String,
+    /// The answer, i.e. `yes`, the name of the selected option or the entered text.
+    pub answer: String,
+    /// Where the answer came from.
+    pub source: AnswerSource,
+}
+
+/// Answers to questions, by question, see `--answers`.
+pub type Answers = HashMap<String, String>;
+
+/// A block repeated by [`For`](Command::For).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// The line of the `FOR`.
+    pub first_line: usize,
+    /// The line of the matching `ENDFOR`.
+    pub end_line: usize,
+    /// The substitution bound to the current item.
+    pub name: String,
+    /// The expanded items, the block is repeated once for each.
+    pub items: Vec<String>,
+}
+
+/// The result of reading commands.
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    /// The lines to remove from the output.
+    pub skips: HashSet<usize>,
+    /// Substitutions defined by the commands, i.e. by [`AskText`](Command::AskText)
+    /// and [`Set`](Command::Set).
+    pub substitutions: Substitutions,
+    /// Text to insert after the given lines, i.e. by [`Echo`](Command::Echo).
+    pub insertions: HashMap<usize, String>,
+    /// All answers given to questions, in the order they were asked.
+    pub answers: Vec<ResolvedAnswer>,
+    /// Blocks to repeat, i.e. by [`For`](Command::For).
+    pub loops: Vec<Loop>,
+}
+
+/// The input and output questions are asked with, see [`CommandReader::read_with`].
+pub trait Prompter {
+    /// Get exclusive access to the input and output for asking a single question.
+    fn lock(&mut self) -> (Box<dyn BufRead + '_>, Box<dyn Write + '_>);
+}
+
+/// Asks questions on stdin and stdout.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Terminal;
+
+impl Prompter for Terminal {
+    /// Holding both locks keeps prompts of parallel jobs from interleaving.
+    fn lock(&mut self) -> (Box<dyn BufRead + '_>, Box<dyn Write + '_>) {
+        (Box::new(io::stdin().lock()), Box::new(io::stdout().lock()))
+    }
+}
+
 /// Reader of commands!
 pub struct CommandReader<'bor, 'str> {
     idx: usize,
     skips: HashSet<usize>,
+    substitutions: Substitutions,
+    insertions: HashMap<usize, String>,
+    answers: Vec<ResolvedAnswer>,
+    loops: Vec<Loop>,
     cmds: CmdLineSlice<'bor, 'str>,
-    answered_questions: HashMap<(&'str str, Vec<Command<'str>>), Answer>,
+    /// The directory relative paths of `IFEXISTS` are resolved against.
+    root: &'bor Path,
+    /// How `$(...)` commands in arguments are run.
+    settings: &'bor CommandSettings,
+    /// Answers by normalized question and option names, see [`normalize`].
+    answered_questions: HashMap<(String, Vec<String>), Answer>,
+    /// Answers to `ASK:` by normalized question.
+    answered_texts: HashMap<String, String>,
+    opt: &'bor Opt,
+    prompter: &'bor mut dyn Prompter,
+    /// Whether the current command is part of the output. Commands in
+    /// skipped branches must not ask questions or run `$(...)` commands.
+    active: bool,
 }
 
 impl<'bor, 'str> CommandReader<'bor, 'str> {
     /// Read and evaluate the given commands.
     ///
     /// # Returns
-    /// A set of lines to skip.
+    /// The [`Evaluation`] containing a set of lines to skip.
     ///
     /// # Errors
     /// This will return an error, if an unexpected command is found,
     /// i.e. an EndIf without a starting if, or a closing command is missing. I.e.
     /// an Ask without an EndAsk
-    pub fn read(cmds: CmdLineSlice<'bor, 'str>) -> Result<HashSet<usize>> {
-        let mut cr = Self::new(cmds);
+    pub fn read(
+        cmds: CmdLineSlice<'bor, 'str>,
+        root: &'bor Path,
+        settings: &'bor CommandSettings,
+        opt: &'bor Opt,
+    ) -> Result<Evaluation> {
+        CommandReader::read_with(cmds, root, settings, opt, &mut Terminal)
+    }
+    /// Read and evaluate the given commands like [`CommandReader::read`], asking
+    /// questions with the given `prompter`.
+    ///
+    /// # Errors
+    /// See [`CommandReader::read`].
+    pub fn read_with(
+        cmds: CmdLineSlice<'bor, 'str>,
+        root: &'bor Path,
+        settings: &'bor CommandSettings,
+        opt: &'bor Opt,
+        prompter: &'bor mut dyn Prompter,
+    ) -> Result<Evaluation> {
+        let mut cr = Self::new(cmds, root, settings, opt, prompter);
         while cr.idx < cmds.len() {
             cr.read_cmd()?;
         }
-        Ok(cr.skips)
+        // Insertions from skipped lines are not part of the output
+        let skips = cr.skips;
+        cr.insertions.retain(|line_nr, _| !skips.contains(line_nr));
+        cr.loops
+            .retain(|repeat| !skips.contains(&repeat.first_line));
+        Ok(Evaluation {
+            skips,
+            substitutions: cr.substitutions,
+            insertions: cr.insertions,
+            answers: cr.answers,
+            loops: cr.loops,
+        })
     }
     /// Create a new CommandReader, that will read the given commands.
-    fn new(cmds: CmdLineSlice<'bor, 'str>) -> Self {
+    fn new(
+        cmds: CmdLineSlice<'bor, 'str>,
+        root: &'bor Path,
+        settings: &'bor CommandSettings,
+        opt: &'bor Opt,
+        prompter: &'bor mut dyn Prompter,
+    ) -> Self {
         CommandReader {
             idx: 0,
             skips: HashSet::new(),
+            substitutions: Substitutions::new(),
+            insertions: HashMap::new(),
+            answers: vec![],
+            loops: vec![],
             cmds,
+            root,
+            settings,
             answered_questions: HashMap::new(),
+            answered_texts: HashMap::new(),
+            opt,
+            prompter,
+            active: true,
         }
     }
     /// Ask the user the given question.
     ///
     /// If the question has already been asked. The cached
     /// answer will be returned without bothering the user.
-    fn ask_question(&mut self, question: &'str str, options: Vec<Command<'str>>) -> Result<Answer> {
-        match self.answered_questions.get(&(question, options.clone())) {
-            Some(cached_answer) => Ok(*cached_answer),
-            None => {
-                if options.is_empty() {
-                    print!("ASK  ─ {} (y/n) ", question);
-                    ::std::io::stdout().flush().unwrap();
-                    let mut input;
-                    let keep_block = loop {
-                        input = String::new();
-                        ::std::io::stdin()
-                            .read_line(&mut input)
-                            .map_err(Error::FailedToReadUserInput)?;
-                        input = input.trim_end_matches("\n").into();
-                        match input.as_ref() {
-                            "y" | "Y" => break true,
-                            "n" | "N" => break false,
-                            _ => {}
-                        }
-                    };
-                    self.answered_questions
-                        .insert((question, options), Answer::YesNo(keep_block));
-                    Ok(Answer::YesNo(keep_block))
-                } else {
-                    println!("ASK  ┬ {}", question);
-                    let mut idx = 1;
-                    for option in &options {
-                        if let Command::Option(name) = option {
-                            println!("     │ {:>2}> {}", idx.to_string().bold(), name);
-                        } else {
-                            panic!("BUG: ask_question received a non `Option` cmd");
-                        }
-                        idx += 1;
-                    }
-                    let mut input = String::new();
-                    // Get the user input until he succeds
-                    while usize::from_str_radix(&input, 10).is_err() {
-                        print!("     └ Please enter a number: ");
-                        ::std::io::stdout().flush().unwrap();
-                        input = String::new();
-                        ::std::io::stdin()
-                            .read_line(&mut input)
-                            .map_err(Error::FailedToReadUserInput)?;
-                        input = input.trim_end_matches("\n").into();
-                    }
-                    let selection = usize::from_str_radix(&input, 10).unwrap() - 1;
-                    self.answered_questions
-                        .insert((question, options), Answer::Option(selection));
-                    Ok(Answer::Option(selection))
-                }
+    /// In non-interactive mode, the `default` is used without asking. Questions with a
+    /// `tag` given by `--answer-tag` are answered without asking as well.
+    ///
+    /// The answer is recorded with the given `line_nr`.
+    ///
+    /// # Errors
+    /// Fails, if the `default` is invalid, no `default` is given in non-interactive
+    /// mode or reading the user input fails.
+    fn ask_question(
+        &mut self,
+        line_nr: usize,
+        question: &'str str,
+        default: Option<&'str str>,
+        tag: Option<&'str str>,
+        options: Vec<Command<'str>>,
+    ) -> Result<Answer> {
+        let names: Vec<_> = options
+            .iter()
+            .map(|option| match option {
+                Command::Option(name, _) => *name,
+                _ => panic!("BUG: ask_question received a non `Option` cmd"),
+            })
+            .collect();
+        let describe = |answer| match answer {
+            Answer::YesNo(true) => String::from("yes"),
+            Answer::YesNo(false) => String::from("no"),
+            Answer::Option(idx) => names.get(idx).map_or("none", |name| name).into(),
+        };
+        // An `OPTION*` is the default, unless the question names one
+        let marked = marked_default(question, &options)?;
+        let key = (
+            normalize(question),
+            names.iter().map(|name| normalize(name)).collect(),
+        );
+        if let Some(cached_answer) = self.answered_questions.get(&key).copied() {
+            self.record_answer(
+                line_nr,
+                question,
+                describe(cached_answer),
+                AnswerSource::Cached,
+            );
+            return Ok(cached_answer);
+        }
+        let default = parse_default(question, default.or(marked.as_deref()), names.len())?;
+        let (answer, source) = if let Some(scripted) = self.scripted_answer(question) {
+            let answer = parse_default(question, Some(scripted), names.len())
+                .map_err(|_| Error::InvalidAnswer(question.into(), scripted.into()))?
+                .expect("BUG: parsing an answer yields an answer");
+            (answer, AnswerSource::AnswersFile)
+        } else if let Some((tag, tagged)) =
+            tag.and_then(|tag| self.tagged_answer(tag).map(|answer| (tag, answer)))
+        {
+            let answer = parse_default(question, Some(tagged), names.len())
+                .map_err(|_| Error::InvalidTagAnswer(question.into(), tag.into(), tagged.into()))?
+                .expect("BUG: parsing an answer yields an answer");
+            (answer, AnswerSource::Tag)
+        } else if self.opt.non_interactive {
+            let answer = default.ok_or_else(|| Error::MissingDefaultAnswer(question.into()))?;
+            (answer, AnswerSource::Default)
+        } else {
+            let (mut input, mut output) = self.prompter.lock();
+            let answer = if names.is_empty() {
+                let default = default.map(|answer| answer == Answer::YesNo(true));
+                Answer::YesNo(prompt_yes_no(question, default, &mut input, &mut output)?)
+            } else {
+                let default = match default {
+                    Some(Answer::Option(idx)) => Some(idx),
+                    _ => None,
+                };
+                let selection = prompt_option(question, &names, default, &mut input, &mut output)?;
+                Answer::Option(selection)
+            };
+            (answer, AnswerSource::Interactive)
+        };
+        self.record_answer(line_nr, question, describe(answer), source);
+        self.answered_questions.insert(key, answer);
+        Ok(answer)
+    }
+
+    /// Ask the user the given question and return the entered text.
+    ///
+    /// Like [`CommandReader::ask_question`], answers are cached, taken from the
+    /// answers file and the `default` is used in non-interactive mode.
+    fn ask_text(
+        &mut self,
+        line_nr: usize,
+        question: &'str str,
+        default: Option<&'str str>,
+    ) -> Result<String> {
+        if let Some(cached_answer) = self.answered_texts.get(&normalize(question)).cloned() {
+            self.record_answer(
+                line_nr,
+                question,
+                cached_answer.clone(),
+                AnswerSource::Cached,
+            );
+            return Ok(cached_answer);
+        }
+        let (answer, source) = if let Some(scripted) = self.scripted_answer(question) {
+            (scripted.into(), AnswerSource::AnswersFile)
+        } else if self.opt.non_interactive {
+            let answer = default.ok_or_else(|| Error::MissingDefaultAnswer(question.into()))?;
+            (answer.into(), AnswerSource::Default)
+        } else {
+            let (mut input, mut output) = self.prompter.lock();
+            let answer = prompt_text(question, default, &mut input, &mut output)?;
+            (answer, AnswerSource::Interactive)
+        };
+        self.record_answer(line_nr, question, answer.clone(), source);
+        self.answered_texts
+            .insert(normalize(question), answer.clone());
+        Ok(answer)
+    }
+
+    /// Get the answer to `question` from the `--answers` file, if any.
+    fn scripted_answer(&self, question: &str) -> Option<&'bor str> {
+        let answers = self.opt.answers.as_ref()?;
+        let question = normalize(question);
+        answers
+            .iter()
+            .find(|(other, _)| normalize(other) == question)
+            .map(|(_, answer)| answer.as_str())
+    }
+
+    /// Get the answer to questions with `tag` from `--answer-tag`, if any.
+    ///
+    /// The last `--answer-tag` for a tag wins.
+    fn tagged_answer(&self, tag: &str) -> Option<&'bor str> {
+        self.opt
+            .answer_tag
+            .iter()
+            .rev()
+            .find(|(other, _)| other == tag)
+            .map(|(_, answer)| answer.as_str())
+    }
+
+    /// Replace every `answer_to("question")` in `var` with the latest answer to `question`.
+    ///
+    /// Unanswered questions are replaced with the empty string.
+    fn expand_answers<'a>(&self, var: &'a str) -> Cow<'a, str> {
+        RE_ANSWER_TO.replace_all(var, |caps: &Captures| {
+            self.answers
+                .iter()
+                .rev()
+                .find(|answer| answer.question == caps[1])
+                .map(|answer| answer.answer.clone())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Record the answer to a question for later inspection.
+    fn record_answer(
+        &mut self,
+        line_nr: usize,
+        question: &str,
+        answer: String,
+        source: AnswerSource,
+    ) {
+        self.answers.push(ResolvedAnswer {
+            line_nr,
+            question: question.into(),
+            answer,
+            source,
+        });
+    }
+
+    /// Read an ASK command asking for text.
+    ///
+    /// The answer is stored as a substitution with the given name.
+    fn read_cmd_ask_text(&mut self) -> Result<()> {
+        if let (line_nr, Command::AskText(var, question, default)) = &self.cmds[self.idx] {
+            if self.active {
+                let answer = self.ask_text(*line_nr, question, *default)?;
+                self.substitutions.insert((*var).into(), answer);
             }
+            self.idx += 1;
+            Ok(())
+        } else {
+            panic!("BUG: read_cmd_ask_text called but no AskText found")
         }
     }
 
     /// Read ASK command lines.
     ///
     /// This method expects a slice of command lines. The first line is expected
-    /// to be a [`Command::Ask`]. On success a tuple containing the number of read lines
-    /// and a [`Vec`] of lines to skip is returned.
+    /// to be a [`Command::Ask`]. The question is asked before the block is read,
+    /// commands in the lines, that are not selected, are read, but not evaluated.
+    /// Thus nested questions are only asked, if their lines are kept.
     ///
     /// # Errors
     /// This function will return an error, if an unexpected line is encountered. I.e. an
-    /// [`Else`](Command::Else) without an `if`.
+    /// [`Else`](Command::Else) without an `if`. An `Option` or `EndAsk` must come after
+    /// the line of the preceding `Ask` or `Option`, otherwise the lines to skip are unknown.
     ///
     /// # Panics
     /// This function panics, if the first command in the given slice is not a [`Command::Ask`].
-    fn read_cmd_ask<'b>(&mut self) -> Result<()> {
+    fn read_cmd_ask(&mut self) -> Result<()> {
         use Command::*;
         let first_idx = self.idx;
         // Assert that the first line is an `Ask`
-        if let (first_line, Ask(question)) = &self.cmds[self.idx] {
+        if let (first_line, Ask(question, default, tag)) = &self.cmds[self.idx] {
+            let outer_active = self.active;
+            // A block without an ending is read without asking, to report the error
+            let answer = match self.find_options() {
+                Some(options) if outer_active => {
+                    Some(self.ask_question(*first_line, question, *default, *tag, options)?)
+                }
+                _ => None,
+            };
+            // Whether the lines following the `Ask` (0) or the n-th `Option` (n + 1) are kept
+            let is_selected = |part: usize| match answer {
+                Some(Answer::YesNo(keep)) => part == 0 && keep,
+                Some(Answer::Option(options_idx)) => part == options_idx + 1,
+                None => false,
+            };
             self.idx += 1;
-            // Found option lines and their line nrs
-            let mut options: Vec<Command> = vec![];
-            let mut options_line_nrs: Vec<usize> = vec![];
+            self.active = is_selected(0);
+            let mut part = 0;
+            let mut last_cmd_line = *first_line;
             // Iterate over the remaining cmds
             while self.idx < self.cmds.len() {
-                match self.cmds[self.idx].1 {
-                    // Handle all unexpected commands
-                    Ask(_) | EndIf | Else | Comment | IfDef(_) | IfNDef(_) | If(_, _) => {
-                        // Read the current command and forward the error
-                        self.read_cmd()?;
-                    }
-                    Option(name) => {
-                        // We found an Option command. Add it to the collection
-                        options.push(Option(name));
-                        options_line_nrs.push(self.cmds[self.idx].0);
-                        self.idx += 1;
-                    }
-                    EndAsk => {
-                        // Everything has been handled, EndAsk was found
-                        // Handle the user questioning
-                        let answer = self.ask_question(question, options)?;
-                        // Iterate over pairs of adjacent command lines. If the lines in between
-                        // should be skipped, add them to the collected skips. Try not to add cmd lines
-                        let mut last_cmd_line = first_line;
-                        match answer {
-                            // The question was considering a collection of options. `options_idx`
-                            // is the index for the collected options lines .
-                            Answer::Option(options_idx) => {
-                                // Was the previous command line the selected option?
-                                let mut skip_not = false;
-                                for idx in 0..options_line_nrs.len() {
-                                    let cmd_line_nr = &options_line_nrs[idx];
-                                    if !skip_not {
-                                        self.skips.extend(last_cmd_line + 1..*cmd_line_nr);
-                                    }
-                                    if idx == options_idx {
-                                        skip_not = true;
-                                    } else {
-                                        skip_not = false;
-                                    }
-                                    last_cmd_line = cmd_line_nr;
-                                }
-                                if !skip_not {
-                                    self.skips.extend(last_cmd_line + 1..self.cmds[self.idx].0);
-                                }
-                            }
-                            // The question was a simple yes-no-question. If `no` was answered,
-                            // skip all lines between `Ask` and `EndAsk`
-                            Answer::YesNo(keep) if !keep => {
-                                self.skips.extend(first_line + 1..self.cmds[self.idx].0);
-                            }
-                            _ => {}
+                let (cmd_line, cmd) = &self.cmds[self.idx];
+                match cmd {
+                    Option(_, _) | EndAsk => {
+                        if *cmd_line <= last_cmd_line {
+                            let (line, cmd) = (*cmd_line, cmd.to_string());
+                            return Err(Error::InstructionOutOfOrder(line, cmd, last_cmd_line));
+                        }
+                        // Skip the lines since the last `Ask` or `Option`, if not selected.
+                        // Try not to add cmd lines
+                        if !is_selected(part) {
+                            self.skips.extend(last_cmd_line + 1..*cmd_line);
                         }
                         self.idx += 1;
-                        return Ok(());
+                        if *cmd == EndAsk {
+                            self.active = outer_active;
+                            return Ok(());
+                        }
+                        part += 1;
+                        last_cmd_line = *cmd_line;
+                        self.active = is_selected(part);
                     }
+                    // Read nested commands and forward the error
+                    _ => self.read_cmd()?,
                 }
             }
-            Err(Error::MissingEndingInstruction(
-                self.cmds[first_idx].0,
-                format!("{:?}", self.cmds[first_idx].1),
-            ))
+            Err(self.missing_ending(first_idx))
         } else {
             panic!("BUG: read_cmd_ask called but no Ask found")
         }
     }
-
-    fn read_cmd_ifdef<'b>(&mut self) -> Result<()> {
+    /// Find the options of the ASK at the current command, ignoring those of nested blocks.
+    ///
+    /// Returns `None`, if the matching ENDASK is missing.
+    fn find_options(&self) -> Option<Vec<Command<'str>>> {
         use Command::*;
-        let first_idx = self.idx;
-        if let (first_line, IfDef(var)) = &self.cmds[self.idx] {
-            self.idx += 1;
-            let mut else_line = None;
-            while self.idx < self.cmds.len() {
-                match self.cmds[self.idx].1 {
-                    EndAsk | Option(_) | Ask(_) | Comment | If(_, _) | IfNDef(_) | IfDef(_) => {
-                        self.read_cmd()?;
-                    }
-                    Else => {
-                        else_line = Some(self.cmds[self.idx].0);
-                        self.idx += 1;
-                    }
-                    EndIf => {
-                        match (evaluate_var(var), else_line) {
-                            (true, Some(el)) => self.skips.extend(el + 1..self.cmds[self.idx].0),
-                            (true, None) => {}
-                            (false, Some(el)) => self.skips.extend(first_line + 1..el),
-                            (false, None) => {
-                                self.skips.extend(first_line + 1..self.cmds[self.idx].0)
-                            }
-                        }
-                        self.idx += 1;
-                        return Ok(());
-                    }
+        let mut options = vec![];
+        let mut depth = 0;
+        let mut comment_depth = 0;
+        for (_, cmd) in &self.cmds[self.idx + 1..] {
+            match cmd {
+                // Instructions in comment blocks need not be balanced
+                CommentStart => comment_depth += 1,
+                CommentEnd if comment_depth > 0 => comment_depth -= 1,
+                _ if comment_depth > 0 => {}
+                EndAsk if depth == 0 => return Some(options),
+                Option(_, _) if depth == 0 => options.push(cmd.clone()),
+                IfDef(_) | IfNDef(_) | IfSet(_) | IfExists(_) | IfNExists(_) | If(_, _, _) => {
+                    depth += 1
                 }
+                Ask(_, _, _) | For(_, _) => depth += 1,
+                EndIf | EndAsk | EndFor if depth > 0 => depth -= 1,
+                _ => {}
             }
-            Err(Error::MissingEndingInstruction(
-                self.cmds[first_idx].0,
-                format!("{:?}", self.cmds[first_idx].1),
-            ))
+        }
+        None
+    }
+
+    fn read_cmd_ifdef(&mut self) -> Result<()> {
+        if let (_, Command::IfDef(var)) = self.cmds[self.idx] {
+            let (var, settings) = (self.expand_answers(var).into_owned(), self.settings);
+            self.read_conditional(move || evaluate_var(&var, settings))
         } else {
             panic!("BUG: read_cmd_ifdef called but no IfDef found")
         }
     }
 
-    fn read_cmd_ifndef<'b>(&mut self) -> Result<()> {
+    fn read_cmd_ifndef(&mut self) -> Result<()> {
+        if let (_, Command::IfNDef(var)) = self.cmds[self.idx] {
+            let (var, settings) = (self.expand_answers(var).into_owned(), self.settings);
+            self.read_conditional(move || evaluate_var(&var, settings).map(|defined| !defined))
+        } else {
+            panic!("BUG: read_cmd_ifndef called but no IfNDef found")
+        }
+    }
+
+    fn read_cmd_ifset(&mut self) -> Result<()> {
+        if let (_, Command::IfSet(name)) = self.cmds[self.idx] {
+            self.read_conditional(move || Ok(env::var_os(name).is_some()))
+        } else {
+            panic!("BUG: read_cmd_ifset called but no IfSet found")
+        }
+    }
+
+    fn read_cmd_ifexists(&mut self) -> Result<()> {
+        if let (_, Command::IfExists(path)) = self.cmds[self.idx] {
+            let (root, settings) = (self.root, self.settings);
+            self.read_conditional(move || path_exists(root, path, settings))
+        } else {
+            panic!("BUG: read_cmd_ifexists called but no IfExists found")
+        }
+    }
+
+    fn read_cmd_ifnexists(&mut self) -> Result<()> {
+        if let (_, Command::IfNExists(path)) = self.cmds[self.idx] {
+            let (root, settings) = (self.root, self.settings);
+            self.read_conditional(move || path_exists(root, path, settings).map(|exists| !exists))
+        } else {
+            panic!("BUG: read_cmd_ifnexists called but no IfNExists found")
+        }
+    }
+
+    fn read_cmd_if(&mut self) -> Result<()> {
+        if let (_, Command::If(var1, var2, ignore_case)) = self.cmds[self.idx] {
+            let var1 = self.expand_answers(var1).into_owned();
+            let var2 = self.expand_answers(var2).into_owned();
+            let settings = self.settings;
+            self.read_conditional(move || evaluate_expr(&var1, &var2, ignore_case, settings))
+        } else {
+            panic!("BUG: read_cmd_if called but no If found")
+        }
+    }
+
+    /// Read a conditional block, starting with the current command and
+    /// ending with an [`EndIf`](Command::EndIf).
+    ///
+    /// The lines of the branch not taken are skipped. The `condition` is only
+    /// evaluated, if the block is not part of a skipped branch itself. Commands in
+    /// skipped branches are read, but not evaluated, see [`CommandReader::active`].
+    fn read_conditional<F>(&mut self, condition: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<bool>,
+    {
         use Command::*;
         let first_idx = self.idx;
-        if let (first_line, IfNDef(var)) = &self.cmds[self.idx] {
-            self.idx += 1;
-            let mut else_line = None;
-            while self.idx < self.cmds.len() {
-                match self.cmds[self.idx].1 {
-                    EndAsk | Option(_) | Ask(_) | Comment | If(_, _) | IfNDef(_) | IfDef(_) => {
-                        self.read_cmd()?;
-                    }
-                    Else => {
-                        else_line = Some(self.cmds[self.idx].0);
-                        self.idx += 1;
-                    }
-                    EndIf => {
-                        match (evaluate_var(var), else_line.is_some()) {
-                            (true, true) => self.skips.extend(first_line + 1..else_line.unwrap()),
-                            (true, false) => {
-                                self.skips.extend(first_line + 1..self.cmds[self.idx].0)
-                            }
-                            (false, true) => self
-                                .skips
-                                .extend(else_line.unwrap() + 1..self.cmds[self.idx].0),
-                            (false, false) => {}
-                        }
-                        self.idx += 1;
-                        return Ok(());
+        let first_line = self.cmds[first_idx].0;
+        let outer_active = self.active;
+        let condition = outer_active && condition()?;
+        self.active = condition;
+        self.idx += 1;
+        let mut else_line = None;
+        while self.idx < self.cmds.len() {
+            match self.cmds[self.idx].1 {
+                Else => {
+                    else_line = Some(self.cmds[self.idx].0);
+                    self.active = outer_active && !condition;
+                    self.idx += 1;
+                }
+                EndIf => {
+                    let end_line = self.cmds[self.idx].0;
+                    match (condition, else_line) {
+                        (true, Some(el)) => self.skips.extend(el + 1..end_line),
+                        (true, None) => {}
+                        (false, Some(el)) => self.skips.extend(first_line + 1..el),
+                        (false, None) => self.skips.extend(first_line + 1..end_line),
                     }
+                    self.active = outer_active;
+                    self.idx += 1;
+                    return Ok(());
                 }
+                _ => self.read_cmd()?,
+            }
+        }
+        Err(self.missing_ending(first_idx))
+    }
+    /// Read an ECHO command, inserting the expanded text after its line.
+    fn read_cmd_echo(&mut self) -> Result<()> {
+        if let (line_nr, Command::Echo(text)) = &self.cmds[self.idx] {
+            if self.active {
+                let text = expand(text, self.settings)?;
+                self.insertions.insert(*line_nr, text);
+            }
+            self.idx += 1;
+            Ok(())
+        } else {
+            panic!("BUG: read_cmd_echo called but no Echo found")
+        }
+    }
+    /// Read a WARN command, logging the expanded message, if the line is kept.
+    fn read_cmd_warn(&mut self) -> Result<()> {
+        if let (line_nr, Command::Warn(message)) = &self.cmds[self.idx] {
+            if self.active {
+                warn!("Line {}: {}", line_nr, expand(message, self.settings)?);
             }
-            Err(Error::MissingEndingInstruction(
-                self.cmds[first_idx].0,
-                format!("{:?}", self.cmds[first_idx].1),
-            ))
+            self.idx += 1;
+            Ok(())
         } else {
-            panic!("BUG: read_cmd_ifndef called but no IfDef found")
+            panic!("BUG: read_cmd_warn called but no Warn found")
         }
     }
-
-    fn read_cmd_if<'b>(&mut self) -> Result<()> {
+    /// Read an ABORT command, failing with the expanded message, if the line is kept.
+    fn read_cmd_abort(&mut self) -> Result<()> {
+        if let (line_nr, Command::Abort(message)) = &self.cmds[self.idx] {
+            if self.active {
+                let message = expand(message, self.settings)?;
+                return Err(Error::AbortRequested(*line_nr, message));
+            }
+            self.idx += 1;
+            Ok(())
+        } else {
+            panic!("BUG: read_cmd_abort called but no Abort found")
+        }
+    }
+    /// Read a SET command, defining a substitution with the expanded value, if the
+    /// line is kept. Later definitions win over earlier ones.
+    fn read_cmd_set(&mut self) -> Result<()> {
+        if let (_, Command::Set(name, value)) = &self.cmds[self.idx] {
+            if self.active {
+                let value = expand(&self.expand_answers(value), self.settings)?;
+                self.substitutions.insert((*name).into(), value);
+            }
+            self.idx += 1;
+            Ok(())
+        } else {
+            panic!("BUG: read_cmd_set called but no Set found")
+        }
+    }
+    /// Read a FOR block up to the matching ENDFOR, which is repeated once per item.
+    ///
+    /// Items are expanded like a var, an item expanding to several lines, i.e. by a
+    /// `$(...)` command, yields one item per non-empty line. Commands inside the
+    /// block are evaluated once, not per item.
+    fn read_cmd_for(&mut self) -> Result<()> {
         use Command::*;
         let first_idx = self.idx;
-        if let (first_line, If(var1, var2)) = &self.cmds[self.idx] {
-            let mut else_line = None;
-            self.idx += 1;
-            while self.idx < self.cmds.len() {
-                match self.cmds[self.idx].1 {
-                    EndAsk | Option(_) | Ask(_) | Comment | If(_, _) | IfNDef(_) | IfDef(_) => {
-                        self.read_cmd()?;
-                    }
-                    Else => {
-                        else_line = Some(self.cmds[self.idx].0);
-                        self.idx += 1;
-                    }
-                    EndIf => {
-                        match (evaluate_expr(var1, var2), else_line) {
-                            (true, Some(el)) => self.skips.extend(el + 1..self.cmds[self.idx].0),
-                            (true, None) => {}
-                            (false, Some(el)) => self.skips.extend(first_line + 1..el),
-                            (false, None) => {
-                                self.skips.extend(first_line + 1..self.cmds[self.idx].0)
-                            }
-                        }
-                        self.idx += 1;
-                        return Ok(());
+        let (first_line, name, items) = match &self.cmds[first_idx] {
+            (line_nr, For(name, items)) => (*line_nr, *name, items),
+            _ => panic!("BUG: read_cmd_for called but no For found"),
+        };
+        let mut expanded = vec![];
+        if self.active {
+            for item in items {
+                let item = expand(&self.expand_answers(item), self.settings)?;
+                let lines = item.lines().map(str::trim).filter(|line| !line.is_empty());
+                expanded.extend(lines.map(String::from));
+            }
+        }
+        self.idx += 1;
+        while self.idx < self.cmds.len() {
+            match self.cmds[self.idx].1 {
+                EndFor => {
+                    let end_line = self.cmds[self.idx].0;
+                    if expanded.is_empty() {
+                        self.skips.extend(first_line + 1..end_line);
+                    } else if self.active {
+                        self.loops.push(Loop {
+                            first_line,
+                            end_line,
+                            name: name.into(),
+                            items: expanded,
+                        });
                     }
+                    self.idx += 1;
+                    return Ok(());
                 }
+                _ => self.read_cmd()?,
             }
-            Err(Error::MissingEndingInstruction(
-                self.cmds[first_idx].0,
-                format!("{:?}", self.cmds[first_idx].1),
-            ))
-        } else {
-            panic!("BUG: read_cmd_if called but no If found")
         }
+        Err(self.missing_ending(first_idx))
+    }
+    /// The error for the block started at `idx`, which is never closed.
+    fn missing_ending(&self, idx: usize) -> Error {
+        let (line_nr, cmd) = &self.cmds[idx];
+        let keyword = cmd
+            .closing_keyword()
+            .expect("BUG: missing ending for a command without one");
+        Error::MissingEndingInstruction(*line_nr, cmd.to_string(), keyword)
+    }
+    /// Read a `COMMENT` block, skipping every line up to the matching `ENDCOMMENT`.
+    ///
+    /// Commands inside the block are not evaluated, but nested blocks must be balanced.
+    fn read_comment_block(&mut self) -> Result<()> {
+        use Command::*;
+        let first_idx = self.idx;
+        let first_line = self.cmds[first_idx].0;
+        let mut depth = 0;
+        while self.idx < self.cmds.len() {
+            match self.cmds[self.idx].1 {
+                CommentStart => depth += 1,
+                CommentEnd => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                self.skips.extend(first_line + 1..self.cmds[self.idx].0);
+                self.idx += 1;
+                return Ok(());
+            }
+            self.idx += 1;
+        }
+        Err(self.missing_ending(first_idx))
     }
     fn read_comment(&mut self) -> Result<()> {
         self.idx += 1;
         Ok(())
     }
-    fn read_cmd<'b>(&mut self) -> Result<()> {
+    fn read_cmd(&mut self) -> Result<()> {
         use Command::*;
+        let (line_nr, cmd) = &self.cmds[self.idx];
+        let state = if self.active { "" } else { " (skipped)" };
+        trace!("Line {}: {}{}", line_nr, cmd, state);
         match self.cmds[self.idx].1 {
             IfDef(_) => self.read_cmd_ifdef(),
             IfNDef(_) => self.read_cmd_ifndef(),
-            If(_, _) => self.read_cmd_if(),
-            Ask(_) => self.read_cmd_ask(),
+            IfSet(_) => self.read_cmd_ifset(),
+            IfExists(_) => self.read_cmd_ifexists(),
+            IfNExists(_) => self.read_cmd_ifnexists(),
+            If(_, _, _) => self.read_cmd_if(),
+            Ask(_, _, _) => self.read_cmd_ask(),
+            AskText(_, _, _) => self.read_cmd_ask_text(),
+            Echo(_) => self.read_cmd_echo(),
+            Warn(_) => self.read_cmd_warn(),
+            Abort(_) => self.read_cmd_abort(),
+            Set(_, _) => self.read_cmd_set(),
+            For(_, _) => self.read_cmd_for(),
+            CommentStart => self.read_comment_block(),
             Comment => self.read_comment(),
-            Else | EndIf | Option(_) | EndAsk => Err(Error::StrayCmdFound(
-                self.cmds[self.idx].0,
-                format!("{:?}", self.cmds[self.idx].1),
-            )),
+            Else | EndIf | Option(_, _) | EndAsk | EndFor | CommentEnd => Err(
+                Error::StrayCmdFound(self.cmds[self.idx].0, self.cmds[self.idx].1.to_string()),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for AnswerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            AnswerSource::Interactive => "interactive",
+            AnswerSource::Default => "default",
+            AnswerSource::AnswersFile => "answers file",
+            AnswerSource::Tag => "tag",
+            AnswerSource::Cached => "cached",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// Parse the `default` answer of the given `question`.
+///
+/// A question without options accepts `y`, `yes`, `n` or `no`, a question
+/// with options accepts the number of an option.
+fn parse_default(question: &str, default: Option<&str>, options: usize) -> Result<Option<Answer>> {
+    let default = match default {
+        Some(default) => default.trim(),
+        None => return Ok(None),
+    };
+    let answer = if options == 0 {
+        match default.to_lowercase().as_ref() {
+            "y" | "yes" => Some(Answer::YesNo(true)),
+            "n" | "no" => Some(Answer::YesNo(false)),
+            _ => None,
+        }
+    } else {
+        match default.parse::<usize>() {
+            Ok(nr) if nr >= 1 && nr <= options => Some(Answer::Option(nr - 1)),
+            _ => None,
+        }
+    };
+    match answer {
+        Some(answer) => Ok(Some(answer)),
+        None => Err(Error::InvalidDefaultAnswer(question.into(), default.into())),
+    }
+}
+
+/// Normalize a question or option for comparisons.
+///
+/// Surrounding whitespace is removed, other whitespace is collapsed to single spaces.
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Get the number of the option marked as default with `OPTION*`, if any.
+///
+/// # Errors
+/// Fails, if more than one option is marked.
+fn marked_default(question: &str, options: &[Command]) -> Result<Option<String>> {
+    let marked: Vec<_> = (1..)
+        .zip(options)
+        .filter(|(_, option)| matches!(option, Command::Option(_, true)))
+        .map(|(nr, _)| nr.to_string())
+        .collect();
+    match marked.len() {
+        0 => Ok(None),
+        1 => Ok(marked.into_iter().next()),
+        _ => Err(Error::InvalidDefaultAnswer(
+            question.into(),
+            marked.join(", "),
+        )),
+    }
+}
+
+/// Read a line from `input`, without the line ending.
+///
+/// # Errors
+/// Fails, if reading fails or the input has ended.
+fn read_answer<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut answer = String::new();
+    match input.read_line(&mut answer) {
+        Ok(0) => Err(Error::FailedToReadUserInput(
+            ErrorKind::UnexpectedEof.into(),
+        )),
+        Ok(_) => Ok(answer.trim_end_matches(&['\r', '\n'][..]).into()),
+        Err(e) => Err(Error::FailedToReadUserInput(e)),
+    }
+}
+
+/// Prompt the user with a yes-no-question until a valid answer is given.
+///
+/// An empty answer selects the `default`, if any.
+fn prompt_yes_no<R, W>(
+    question: &str,
+    default: Option<bool>,
+    input: &mut R,
+    output: &mut W,
+) -> Result<bool>
+where
+    R: BufRead,
+    W: Write,
+{
+    let choices = match default {
+        Some(true) => "Y/n",
+        Some(false) => "y/N",
+        None => "y/n",
+    };
+    loop {
+        write!(output, "ASK  ─ {} ({}) ", question, choices)
+            .and_then(|_| output.flush())
+            .map_err(Error::FailedToReadUserInput)?;
+        match (read_answer(input)?.as_ref(), default) {
+            ("y", _) | ("Y", _) => return Ok(true),
+            ("n", _) | ("N", _) => return Ok(false),
+            ("", Some(default)) => return Ok(default),
+            _ => {}
+        }
+    }
+}
+
+/// Prompt the user for a line of text.
+///
+/// An empty answer selects the `default`, if any.
+fn prompt_text<R, W>(
+    question: &str,
+    default: Option<&str>,
+    input: &mut R,
+    output: &mut W,
+) -> Result<String>
+where
+    R: BufRead,
+    W: Write,
+{
+    match default {
+        Some(default) => write!(output, "ASK  ─ {} [{}] ", question, default),
+        None => write!(output, "ASK  ─ {} ", question),
+    }
+    .and_then(|_| output.flush())
+    .map_err(Error::FailedToReadUserInput)?;
+    let answer = read_answer(input)?;
+    match default {
+        Some(default) if answer.is_empty() => Ok(default.into()),
+        _ => Ok(answer),
+    }
+}
+
+/// Prompt the user to select one of the given `options` until the number of an option
+/// is entered.
+///
+/// An empty answer selects the `default`, if any. Returns the index of the selected option.
+fn prompt_option<R, W>(
+    question: &str,
+    options: &[&str],
+    default: Option<usize>,
+    input: &mut R,
+    output: &mut W,
+) -> Result<usize>
+where
+    R: BufRead,
+    W: Write,
+{
+    let print_menu = |output: &mut W| -> io::Result<()> {
+        writeln!(output, "ASK  ┬ {}", question)?;
+        for (nr, name) in (1..).zip(options) {
+            writeln!(output, "     │ {:>2}> {}", nr.to_string().bold(), name)?;
+        }
+        Ok(())
+    };
+    print_menu(output).map_err(Error::FailedToReadUserInput)?;
+    // Get the user input until he succeds
+    loop {
+        match default {
+            Some(idx) => write!(output, "     └ Please enter a number [{}]: ", idx + 1),
+            None => write!(output, "     └ Please enter a number: "),
+        }
+        .and_then(|_| output.flush())
+        .map_err(Error::FailedToReadUserInput)?;
+        let answer = read_answer(input)?;
+        match (answer.parse::<usize>(), default) {
+            (Ok(nr), _) if nr >= 1 && nr <= options.len() => return Ok(nr - 1),
+            (Err(_), Some(idx)) if answer.is_empty() => return Ok(idx),
+            _ => {}
+        }
+    }
+}
+
+/// Prompt the user to select any number of the given `options`.
+///
+/// Numbers are separated by whitespace or commas, an empty answer selects all options.
+/// Returns the sorted indices of the selected options.
+pub fn prompt_selection<R, W, S>(
+    question: &str,
+    options: &[S],
+    input: &mut R,
+    output: &mut W,
+) -> Result<Vec<usize>>
+where
+    R: BufRead,
+    W: Write,
+    S: AsRef<str>,
+{
+    let print_menu = |output: &mut W| -> io::Result<()> {
+        writeln!(output, "ASK  ┬ {}", question)?;
+        for (nr, name) in (1..).zip(options) {
+            writeln!(
+                output,
+                "     │ {:>2}> {}",
+                nr.to_string().bold(),
+                name.as_ref()
+            )?;
+        }
+        Ok(())
+    };
+    print_menu(output).map_err(Error::FailedToReadUserInput)?;
+    loop {
+        write!(output, "     └ Please enter numbers [all]: ")
+            .and_then(|_| output.flush())
+            .map_err(Error::FailedToReadUserInput)?;
+        let answer = read_answer(input)?;
+        if answer.trim().is_empty() {
+            return Ok((0..options.len()).collect());
+        }
+        let selection: ::std::result::Result<Vec<usize>, _> = answer
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|nr| !nr.is_empty())
+            .map(|nr| match nr.parse::<usize>() {
+                Ok(nr) if nr >= 1 && nr <= options.len() => Ok(nr - 1),
+                _ => Err(()),
+            })
+            .collect();
+        if let Ok(mut selection) = selection {
+            selection.sort_unstable();
+            selection.dedup();
+            return Ok(selection);
         }
     }
 }
@@ -338,71 +981,173 @@ impl<'bor, 'str> CommandReader<'bor, 'str> {
 ///
 /// This returns true if the `var` contains more than just whitespaces
 /// after expanding `${blub}`, `$blub` and `$(blub.sh)` stuff.
-fn evaluate_var<'a>(var: &'a str) -> bool {
-    !expand(var).trim().is_empty()
+fn evaluate_var(var: &str, settings: &CommandSettings) -> Result<bool> {
+    Ok(!expand(var, settings)?.trim().is_empty())
 }
 
 /// Evaluate the given expressions.
 ///
-/// This returns true, if both `var`s are equal after expansion.
-fn evaluate_expr<'a>(var1: &'a str, var2: &'a str) -> bool {
-    expand(var1).trim() == expand(var2).trim()
+/// This returns true, if both `var`s are equal after expansion. With `ignore_case`,
+/// they are compared after lowercasing both.
+fn evaluate_expr(
+    var1: &str,
+    var2: &str,
+    ignore_case: bool,
+    settings: &CommandSettings,
+) -> Result<bool> {
+    let (var1, var2) = (expand(var1, settings)?, expand(var2, settings)?);
+    if ignore_case {
+        Ok(var1.trim().to_lowercase() == var2.trim().to_lowercase())
+    } else {
+        Ok(var1.trim() == var2.trim())
+    }
+}
+
+/// Whether a file exists at the expanded `path`, relative paths are resolved against `root`.
+fn path_exists(root: &Path, path: &str, settings: &CommandSettings) -> Result<bool> {
+    let path = expand_env_path(Path::new(path), settings.strict_env)?;
+    Ok(root.join(path).exists())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use structopt::StructOpt;
+
+    const SETTINGS: CommandSettings = CommandSettings {
+        shell: None,
+        timeout: None,
+        strict_env: false,
+    };
 
     #[test]
     fn test_command_read_ifdef() {
         use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let mut terminal = Terminal;
         // With else branch
         let cmds = &[(1, IfDef("NOT_EMPTY_STRING")), (5, Else), (10, EndIf)];
-        let mut reader = CommandReader::new(cmds);
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_ifdef().expect("Should work");
         assert_eq!(reader.idx, 3);
         assert_eq!(reader.skips, vec![6, 7, 8, 9].drain(..).collect());
 
         // Without else branch
         let cmds = &[(1, IfDef("ULTRA_LONG_VARIABLE")), (7, EndIf)];
-        let mut reader = CommandReader::new(cmds);
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_ifdef().expect("Should work");
         assert_eq!(reader.idx, 2);
         assert_eq!(reader.skips, HashSet::new());
 
         // With no line in between
         let cmds = &[(1, IfDef("NOT_EMPTY_STRING")), (2, EndIf)];
-        let mut reader = CommandReader::new(cmds);
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_ifdef().expect("Should work");
         assert_eq!(reader.idx, 2);
         assert_eq!(reader.skips, HashSet::new());
     }
 
+    #[test]
+    fn test_command_read_if_expanded_operator() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        // The expansion is compared as a whole, it is not split again
+        ::std::env::set_var("DOTFILE_TESTING_IF_OPERATOR", "a == b");
+        let cmds = &[
+            (1, If("$DOTFILE_TESTING_IF_OPERATOR", "a == b", false)),
+            (3, EndIf),
+            (4, If("$DOTFILE_TESTING_IF_OPERATOR", "a", false)),
+            (6, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![5].into_iter().collect());
+    }
+
+    #[test]
+    fn test_command_read_if_ignore_case() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[
+            (1, If("Vim", "vim", false)),
+            (3, EndIf),
+            (4, If("Vim", "vim", true)),
+            (6, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![2].into_iter().collect());
+    }
+
+    #[test]
+    fn test_command_read_ifset() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        // Set, but empty variables are set
+        ::std::env::set_var("DOTFILE_TESTING_IFSET_EMPTY", "");
+        let cmds = &[
+            (1, IfSet("DOTFILE_TESTING_IFSET_EMPTY")),
+            (3, Else),
+            (5, EndIf),
+            (6, IfSet("DOTFILE_TESTING_IFSET_UNSET")),
+            (8, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        let mut skips: Vec<_> = evaluation.skips.into_iter().collect();
+        skips.sort_unstable();
+        assert_eq!(skips, vec![4, 7]);
+    }
+
+    #[test]
+    fn test_command_read_ifexists() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("present"), "").unwrap();
+        ::std::env::set_var("DOTFILE_TESTING_IFEXISTS_NAME", "present");
+        // Relative paths are resolved against the root
+        let cmds = &[
+            (1, IfExists("$DOTFILE_TESTING_IFEXISTS_NAME")),
+            (3, Else),
+            (5, EndIf),
+            (6, IfExists("missing")),
+            (8, EndIf),
+            (9, IfNExists("missing")),
+            (11, Else),
+            (13, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, dir.path(), &SETTINGS, &opt).unwrap();
+        let mut skips: Vec<_> = evaluation.skips.into_iter().collect();
+        skips.sort_unstable();
+        assert_eq!(skips, vec![4, 7, 12]);
+    }
+
     /// Test basic `read_cmd_if` stuff.
     #[test]
     fn test_command_read_if() {
         use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let mut terminal = Terminal;
         // With Else branch
         let cmds = &[
-            (3, If("SHORT_VALUE", "SHORT_VALUE")),
+            (3, If("SHORT_VALUE", "SHORT_VALUE", false)),
             (6, Else),
             (11, EndIf),
         ];
-        let mut reader = CommandReader::new(cmds);
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_if().expect("Should work");
         assert_eq!(reader.idx, 3);
         assert_eq!(reader.skips, vec![7, 8, 9, 10].drain(..).collect());
 
         // Without Else branch
-        let cmds = &[(4, If("öüä@", "öüä@")), (8, EndIf)];
-        let mut reader = CommandReader::new(cmds);
+        let cmds = &[(4, If("öüä@", "öüä@", false)), (8, EndIf)];
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_if().expect("Should work");
         assert_eq!(reader.idx, 2);
         assert_eq!(reader.skips, HashSet::new());
 
         // With no lines in between
-        let cmds = &[(5, If("öüä@", "öüä@")), (6, EndIf)];
-        let mut reader = CommandReader::new(cmds);
+        let cmds = &[(5, If("öüä@", "öüä@", false)), (6, EndIf)];
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
         reader.read_cmd_if().expect("Should work");
         assert_eq!(reader.idx, 2);
         assert_eq!(reader.skips, HashSet::new());
@@ -411,15 +1156,610 @@ mod tests {
     #[test]
     fn test_command_read() {
         use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
 
         let cmds = &[
             (1, IfDef("SHORT_VALUE")),
             (4, Else),
             (6, EndIf),
-            (8, If("SOME", "SOME1")),
+            (8, If("SOME", "SOME1", false)),
             (10, EndIf),
         ];
-        let skips = CommandReader::read(cmds).expect("Should work");
+        let skips = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt)
+            .expect("Should work")
+            .skips;
         assert_eq!(skips, vec![5, 9].drain(..).collect())
     }
+
+    #[test]
+    fn test_command_read_echo() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        ::std::env::set_var("DOTFILE_TESTING_ECHO", "echoed");
+        let cmds = &[
+            (0, Echo("$DOTFILE_TESTING_ECHO")),
+            (1, IfDef("")),
+            (2, Echo("skipped")),
+            (3, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.insertions.len(), 1);
+        assert_eq!(evaluation.insertions[&0], "echoed");
+    }
+
+    #[test]
+    fn test_command_read_set() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        ::std::env::set_var("DOTFILE_TESTING_SET", "set");
+        let cmds = &[
+            (0, Set("NAME", "Max")),
+            (1, Set("NAME", "$DOTFILE_TESTING_SET")),
+            (2, IfDef("")),
+            (3, Set("NAME", "skipped")),
+            (4, Set("OTHER", "skipped")),
+            (5, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.substitutions.len(), 1);
+        assert_eq!(evaluation.substitutions["NAME"], "set");
+    }
+
+    #[test]
+    fn test_command_read_ask_out_of_order() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        // An option preceding its question
+        let cmds = &[
+            (5, Ask("Color?", Some("1"), None)),
+            (0, Option("red", false)),
+            (7, EndAsk),
+        ];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::InstructionOutOfOrder(0, cmd, 5)) => assert_eq!(cmd, "OPTION red"),
+            other => panic!("Expected an out of order error, got {:?}", other),
+        }
+        let cmds = &[(3, Ask("Yes?", Some("y"), None)), (3, EndAsk)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::InstructionOutOfOrder(3, cmd, 3)) => assert_eq!(cmd, "ENDASK"),
+            other => panic!("Expected an out of order error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_read_nested_ask() {
+        use Command::*;
+        // Asking questions without defaults would fail
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let cmds = &[
+            (0, Ask("Outer?", Some("1"), None)),
+            (1, Option("A", false)),
+            (2, IfDef("")),
+            (3, Ask("Unasked?", None, None)),
+            (4, EndAsk),
+            (5, EndIf),
+            (6, Ask("Inner?", Some("n"), None)),
+            (8, EndAsk),
+            (10, Option("B", false)),
+            (11, Ask("Never?", None, None)),
+            (13, EndAsk),
+            (14, EndAsk),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        let mut skips: Vec<_> = evaluation.skips.into_iter().collect();
+        skips.sort_unstable();
+        assert_eq!(skips, vec![3, 4, 7, 11, 12, 13]);
+        // The outer question is asked first
+        let questions: Vec<_> = evaluation.answers.iter().map(|a| &a.question).collect();
+        assert_eq!(questions, vec!["Outer?", "Inner?"]);
+        // Options of nested questions are not part of the outer question
+        let cmds = &[
+            (0, Ask("Yes?", Some("y"), None)),
+            (1, Ask("Color?", Some("2"), None)),
+            (2, Option("red", false)),
+            (4, Option("green", false)),
+            (6, EndAsk),
+            (7, EndAsk),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![3].into_iter().collect());
+    }
+
+    #[test]
+    fn test_command_read_inactive_ask() {
+        use Command::*;
+        // Asking would fail in non-interactive mode without defaults
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let cmds = &[
+            (0, IfDef("")),
+            (1, Ask("Q?", None, None)),
+            (3, EndAsk),
+            (4, AskText("VAR", "Text?", None)),
+            (5, Else),
+            (6, EndIf),
+            (7, IfNDef("NOT_EMPTY")),
+            (8, Ask("Q?", None, None)),
+            (9, EndAsk),
+            (10, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![1, 2, 3, 4, 8, 9].drain(..).collect());
+        assert!(evaluation.substitutions.is_empty());
+        // But the taken branch still asks
+        let cmds = &[
+            (0, IfDef("x")),
+            (1, Ask("Q?", None, None)),
+            (2, EndAsk),
+            (3, EndIf),
+        ];
+        assert!(CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).is_err());
+    }
+
+    #[test]
+    fn test_prompt_yes_no_default() {
+        let mut output = vec![];
+        let answer = prompt_yes_no("Q?", Some(false), &mut "\n".as_bytes(), &mut output);
+        assert!(!answer.unwrap());
+        let answer = prompt_yes_no("Q?", None, &mut "\nx\ny\n".as_bytes(), &mut output);
+        assert!(answer.unwrap());
+        assert!(prompt_yes_no("Q?", None, &mut "\n".as_bytes(), &mut output).is_err());
+    }
+
+    #[test]
+    fn test_prompt_option_default() {
+        let mut output = vec![];
+        let options = &["a", "b", "c"];
+        let answer = prompt_option("Q?", options, Some(1), &mut "\n".as_bytes(), &mut output);
+        assert_eq!(answer.unwrap(), 1);
+        let answer = prompt_option("Q?", options, None, &mut "\n3\n".as_bytes(), &mut output);
+        assert_eq!(answer.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_prompt_option_out_of_range() {
+        let mut output = vec![];
+        let options = &["a", "b", "c"];
+        // Numbers without an option are asked again
+        let mut input = "0\n99\n4\n1\n".as_bytes();
+        let answer = prompt_option("Q?", options, None, &mut input, &mut output);
+        assert_eq!(answer.unwrap(), 0);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("Please enter a number").count(), 4);
+        // The input ends before a valid number is entered
+        let mut input = "3\n".as_bytes();
+        let answer = prompt_option("Q?", &options[..2], Some(0), &mut input, &mut vec![]);
+        assert!(matches!(answer, Err(Error::FailedToReadUserInput(_))));
+    }
+
+    #[test]
+    fn test_prompt_selection() {
+        let mut output = vec![];
+        let options = &["a", "b", "c"];
+        let selection = prompt_selection("Q?", options, &mut "\n".as_bytes(), &mut output);
+        assert_eq!(selection.unwrap(), vec![0, 1, 2]);
+        let selection = prompt_selection("Q?", options, &mut "3, 1 3\n".as_bytes(), &mut output);
+        assert_eq!(selection.unwrap(), vec![0, 2]);
+        // Invalid numbers are asked again
+        let input = &mut "0\n4\nb\n2\n".as_bytes();
+        let selection = prompt_selection("Q?", options, input, &mut output);
+        assert_eq!(selection.unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_command_read_abort() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[(0, IfDef("")), (1, Abort("Invalid")), (2, EndIf)];
+        assert!(CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).is_ok());
+        let cmds = &[(0, IfDef("x")), (1, Abort("Invalid")), (2, EndIf)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::AbortRequested(1, message)) => assert_eq!(message, "Invalid"),
+            other => panic!("Expected an abort, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_read_ask_options() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let cmds = |default| {
+            vec![
+                (0, Ask("Color?", Some(default), None)),
+                (1, Option("red", false)),
+                (3, Option("green", false)),
+                (5, Option("blue", false)),
+                (8, EndAsk),
+            ]
+        };
+        let skips = |default| {
+            let mut skips: Vec<_> =
+                CommandReader::read(&cmds(default), Path::new("."), &SETTINGS, &opt)
+                    .unwrap()
+                    .skips
+                    .into_iter()
+                    .collect();
+            skips.sort();
+            skips
+        };
+        assert_eq!(skips("1"), vec![4, 6, 7]);
+        assert_eq!(skips("2"), vec![2, 6, 7]);
+        // Everything between the last option and ENDASK is kept
+        assert_eq!(skips("3"), vec![2, 4]);
+    }
+
+    /// Answers questions from a fixed input.
+    struct Canned {
+        input: &'static [u8],
+        output: Vec<u8>,
+    }
+
+    impl Prompter for Canned {
+        fn lock(&mut self) -> (Box<dyn BufRead + '_>, Box<dyn Write + '_>) {
+            (Box::new(&mut self.input), Box::new(&mut self.output))
+        }
+    }
+
+    #[test]
+    fn test_read_with_prompter() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[
+            (1, Ask("Laptop?", None, None)),
+            (3, EndAsk),
+            (4, Ask("Color?", None, None)),
+            (5, Option("red", false)),
+            (7, Option("blue", false)),
+            (9, EndAsk),
+            // Asked before, the answer is cached
+            (10, Ask("Laptop?", None, None)),
+            (12, EndAsk),
+            (13, AskText("NAME", "Name?", None)),
+        ];
+        let mut canned = Canned {
+            input: b"n\n2\nMax\n",
+            output: vec![],
+        };
+        let evaluation =
+            CommandReader::read_with(cmds, Path::new("."), &SETTINGS, &opt, &mut canned).unwrap();
+        assert_eq!(evaluation.skips, vec![2, 6, 11].drain(..).collect());
+        assert_eq!(evaluation.substitutions["NAME"], "Max");
+        assert_eq!(evaluation.answers[2].source, AnswerSource::Cached);
+        assert!(canned.input.is_empty());
+        let output = String::from_utf8(canned.output).unwrap();
+        assert_eq!(output.matches("Laptop?").count(), 1);
+    }
+
+    #[test]
+    fn test_answers_file() {
+        use Command::*;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("answers.toml");
+        let toml = "\"Laptop?\" = false\n\"Color?\" = 2\n\"Name?\" = \"Max\"\n";
+        fs::write(&path, toml).unwrap();
+        let opt = Opt::from_iter(&["dope", "--yes", "--answers", path.to_str().unwrap()]);
+        let cmds = &[
+            (1, Ask("Laptop?", Some("y"), None)),
+            (3, EndAsk),
+            (4, Ask("Color?", None, None)),
+            (5, Option("red", false)),
+            (7, Option("blue", false)),
+            (9, EndAsk),
+            (10, AskText("NAME", "Name?", None)),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![2, 6].drain(..).collect());
+        assert_eq!(evaluation.substitutions["NAME"], "Max");
+        assert!(evaluation
+            .answers
+            .iter()
+            .all(|answer| answer.source == AnswerSource::AnswersFile));
+        // Questions missing in the file still need a default
+        let cmds = &[(1, Ask("Other?", None, None)), (3, EndAsk)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingDefaultAnswer(_)) => {}
+            other => panic!("Expected a missing default error, got {:?}", other),
+        }
+        // Answers must fit the question
+        let cmds = &[(1, Ask("Name?", None, None)), (3, EndAsk)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::InvalidAnswer(_, answer)) => assert_eq!(answer, "Max"),
+            other => panic!("Expected an invalid answer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_answer_tag() {
+        use Command::*;
+        let args = &["dope", "--answer-tag", "ui=no", "--answer-tag", "theme=2"];
+        let opt = Opt::from_iter(args);
+        let cmds = &[
+            (1, Ask("Animations?", Some("y"), Some("ui"))),
+            (3, EndAsk),
+            (4, Ask("Color?", None, Some("theme"))),
+            (5, Option("red", false)),
+            (7, Option("blue", false)),
+            (9, EndAsk),
+            // Untagged questions are still asked
+            (10, Ask("Laptop?", None, None)),
+            (12, EndAsk),
+        ];
+        let mut canned = Canned {
+            input: b"y\n",
+            output: vec![],
+        };
+        let evaluation =
+            CommandReader::read_with(cmds, Path::new("."), &SETTINGS, &opt, &mut canned).unwrap();
+        assert_eq!(evaluation.skips, vec![2, 6].drain(..).collect());
+        let sources: Vec<_> = evaluation.answers.iter().map(|a| a.source).collect();
+        let expected = [
+            AnswerSource::Tag,
+            AnswerSource::Tag,
+            AnswerSource::Interactive,
+        ];
+        assert_eq!(sources, expected);
+        let output = String::from_utf8(canned.output).unwrap();
+        assert!(!output.contains("Animations?"));
+        assert!(output.contains("Laptop?"));
+        // Answers must fit the question
+        let cmds = &[(1, Ask("Animations?", None, Some("theme"))), (3, EndAsk)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::InvalidTagAnswer(_, tag, answer)) => {
+                assert_eq!((&*tag, &*answer), ("theme", "2"))
+            }
+            other => panic!("Expected an invalid answer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_read_comment_block() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[
+            (0, CommentStart),
+            (2, Abort("Not evaluated")),
+            (3, CommentStart),
+            (5, CommentEnd),
+            (7, CommentEnd),
+            (9, Comment),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, (1..7).collect());
+        // Unbalanced blocks
+        let cmds = &[(0, CommentStart), (1, CommentStart), (2, CommentEnd)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingEndingInstruction(0, _, "ENDCOMMENT")) => {}
+            other => panic!("Expected a missing ENDCOMMENT, got {:?}", other),
+        }
+        let cmds = &[(0, CommentEnd)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::StrayCmdFound(0, _)) => {}
+            other => panic!("Expected a stray ENDCOMMENT, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_read_answer_to() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let cmds = &[
+            (0, Ask("Laptop?", Some("n"), None)),
+            (1, EndAsk),
+            (2, If(r#"answer_to("Laptop?")"#, "yes", false)),
+            (4, Else),
+            (6, EndIf),
+            (7, IfDef(r#"answer_to("Never asked?")"#)),
+            (9, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(evaluation.skips, vec![3, 8].drain(..).collect());
+    }
+
+    #[test]
+    fn test_answer_sources() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let mut terminal = Terminal;
+        let cmds = &[
+            (0, Ask("Laptop?", Some("y"), None)),
+            (2, EndAsk),
+            (3, Ask("Color?", None, None)),
+            (4, Option("red", false)),
+            (6, Option("blue", false)),
+            (8, EndAsk),
+        ];
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
+        let key = ("Color?".into(), vec!["red".into(), "blue".into()]);
+        reader.answered_questions.insert(key, Answer::Option(1));
+        while reader.idx < cmds.len() {
+            reader.read_cmd().unwrap();
+        }
+        let labels: Vec<_> = reader
+            .answers
+            .iter()
+            .map(|a| (a.line_nr, a.answer.as_ref(), a.source.to_string()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                (0, "yes", String::from("default")),
+                (3, "blue", String::from("cached"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalized_questions() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[
+            (1, Ask("Laptop?", None, None)),
+            (3, EndAsk),
+            (4, Ask("  Laptop? \r", None, None)),
+            (6, EndAsk),
+            (7, Ask("Color?", None, None)),
+            (8, Option("dark  red", false)),
+            (10, EndAsk),
+            (11, Ask("Color? ", None, None)),
+            (12, Option(" dark red", true)),
+            (14, EndAsk),
+            (15, AskText("NAME", "Name?", None)),
+            (16, AskText("NAME", "Name?\t", None)),
+        ];
+        // Every question is asked only once
+        let mut canned = Canned {
+            input: b"n\n1\nMax\n",
+            output: vec![],
+        };
+        let evaluation =
+            CommandReader::read_with(cmds, Path::new("."), &SETTINGS, &opt, &mut canned).unwrap();
+        assert_eq!(evaluation.skips, vec![2, 5].drain(..).collect());
+        assert_eq!(evaluation.substitutions["NAME"], "Max");
+        assert_eq!(normalize(" a \t b\r"), "a b");
+    }
+
+    #[test]
+    fn test_prompt_text() {
+        let mut output = vec![];
+        let answer = prompt_text("Q?", Some("default"), &mut "\n".as_bytes(), &mut output);
+        assert_eq!(answer.unwrap(), "default");
+        let answer = prompt_text("Q?", None, &mut "me@mail.org\n".as_bytes(), &mut output);
+        assert_eq!(answer.unwrap(), "me@mail.org");
+    }
+
+    #[test]
+    fn test_ask_text() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let mut terminal = Terminal;
+        let cmds = &[
+            (0, AskText("EMAIL", "Email?", Some("me@mail.org"))),
+            (1, AskText("USER", "User?", None)),
+        ];
+        let mut reader = CommandReader::new(cmds, Path::new("."), &SETTINGS, &opt, &mut terminal);
+        reader.answered_texts.insert("User?".into(), "me".into());
+        reader.read_cmd().unwrap();
+        reader.read_cmd().unwrap();
+        assert_eq!(reader.substitutions["EMAIL"], "me@mail.org");
+        assert_eq!(reader.substitutions["USER"], "me");
+    }
+
+    #[test]
+    fn test_parse_default() {
+        assert_eq!(parse_default("Q", None, 0).unwrap(), None);
+        assert_eq!(
+            parse_default("Q", Some("Y"), 0).unwrap(),
+            Some(Answer::YesNo(true))
+        );
+        assert_eq!(
+            parse_default("Q", Some("no"), 0).unwrap(),
+            Some(Answer::YesNo(false))
+        );
+        assert_eq!(
+            parse_default("Q", Some("2"), 2).unwrap(),
+            Some(Answer::Option(1))
+        );
+        assert!(parse_default("Q", Some("3"), 2).is_err());
+        assert!(parse_default("Q", Some("0"), 2).is_err());
+        assert!(parse_default("Q", Some("2"), 0).is_err());
+    }
+
+    #[test]
+    fn test_non_interactive() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope", "--yes"]);
+        let cmds = &[
+            (0, Ask("Q?", Some("2"), None)),
+            (1, Option("a", false)),
+            (3, Option("b", false)),
+            (5, EndAsk),
+            (6, Ask("Other?", Some("n"), None)),
+            (8, EndAsk),
+        ];
+        let skips = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt)
+            .unwrap()
+            .skips;
+        assert_eq!(skips, vec![2, 7].drain(..).collect());
+        // A marked option is the default, the number in brackets wins
+        let marked = |default| {
+            let cmds = &[
+                (0, Ask("Q?", default, None)),
+                (1, Option("a", true)),
+                (3, Option("b", false)),
+                (5, EndAsk),
+            ];
+            CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).map(|reader| reader.skips)
+        };
+        assert_eq!(marked(None).unwrap(), vec![4].drain(..).collect());
+        assert_eq!(marked(Some("2")).unwrap(), vec![2].drain(..).collect());
+        let cmds = &[
+            (0, Ask("Q?", None, None)),
+            (1, Option("a", true)),
+            (3, Option("b", true)),
+            (5, EndAsk),
+        ];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::InvalidDefaultAnswer(_, marked)) => assert_eq!(marked, "1, 2"),
+            other => panic!("Expected an invalid default, got {:?}", other),
+        }
+        // Without a default
+        let cmds = &[(0, Ask("Q?", None, None)), (2, EndAsk)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingDefaultAnswer(_)) => {}
+            other => panic!("Expected a missing default error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_read_missing_ending() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[(0, IfDef("a")), (1, Else)];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingEndingInstruction(0, cmd, "ENDIF")) => assert_eq!(cmd, "IFDEF a"),
+            other => panic!("Expected a missing ENDIF, got {:?}", other),
+        }
+        let cmds = &[
+            (0, Ask("Q?", None, None)),
+            (1, Option("a", false)),
+            (2, If("a", "a", false)),
+            (3, EndIf),
+        ];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingEndingInstruction(0, cmd, "ENDASK")) => assert_eq!(cmd, "ASK Q?"),
+            other => panic!("Expected a missing ENDASK, got {:?}", other),
+        }
+        let cmds = &[(0, For("host", vec!["a", "b"])), (1, Echo("x"))];
+        match CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt) {
+            Err(Error::MissingEndingInstruction(0, cmd, "ENDFOR")) => {
+                assert_eq!(cmd, "FOR host IN a,b")
+            }
+            other => panic!("Expected a missing ENDFOR, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_read_for() {
+        use Command::*;
+        let opt = Opt::from_iter(&["dope"]);
+        let cmds = &[
+            (1, For("host", vec!["alpha", "$(printf 'beta\\n\\ngamma')"])),
+            (3, EndFor),
+            (4, IfDef("")),
+            (5, For("skipped", vec!["a"])),
+            (7, EndFor),
+            (8, EndIf),
+        ];
+        let evaluation = CommandReader::read(cmds, Path::new("."), &SETTINGS, &opt).unwrap();
+        assert_eq!(
+            evaluation.loops,
+            vec![Loop {
+                first_line: 1,
+                end_line: 3,
+                name: "host".into(),
+                items: vec!["alpha".into(), "beta".into(), "gamma".into()],
+            }]
+        );
+        assert!(CommandReader::read(&[(0, EndFor)], Path::new("."), &SETTINGS, &opt).is_err());
+    }
 }
@@ -1,26 +1,52 @@
+use lazy_static::lazy_static;
 use regex::Captures;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+use std::convert::TryFrom;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::str;
 
 use super::Substitutions;
-use crate::env::{expand, expand_env_path};
-use crate::error::{Error, Result};
-use crate::helper::get_link_function;
 use crate::command::Command;
+use crate::command_reader::{CommandReader, Evaluation};
+use crate::env::{
+    self, expand, expand_env, expand_env_path, referenced_commands, referenced_env_vars,
+    CommandSettings,
+};
+use crate::error::{Error, Result};
+use crate::helper::{
+    canonicalize_lenient, canonicalize_parent, copy_mode, create_parent_dir, display_path,
+    get_link_function, hard_link_or_copy, is_same_file, link_atomically, mirror_path,
+    path_from_dir, relative_path,
+};
 use crate::Opt;
-use crate::command_reader::CommandReader;
 
-const COMPILED_SUFFIX: &'static str = ".preprocessed";
+const COMPILED_SUFFIX: &str = ".preprocessed";
+/// Suffix of backups of targets, see [`FileConfig::remove_link`].
+const BACKUP_SUFFIX: &str = ".bak";
+/// Starts a comment after the prefix, if no `comment_char` is configured.
+const DEFAULT_COMMENT_CHAR: &str = "#";
+
+lazy_static! {
+    /// Matches a header line like `# dope: prefix="#~" escape="{{ }}"`.
+    static ref RE_HEADER: Regex =
+        Regex::new(r#"^\S*\s*dope:((?:\s+[a-z_]+="[^"]*")+)\s*$"#).unwrap();
+    /// Matches a single `key="value"` pair of a header.
+    static ref RE_HEADER_PAIR: Regex = Regex::new(r#"([a-z_]+)="([^"]*)""#).unwrap();
+}
 
 /// An opening and a closing character sequence.
 /// These delimit string that need special treatment.
+///
+/// Given as table, array or string in the configuration, see [`Escape::parse`].
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "RawEscape")]
 pub struct Escape {
     /// Starting sequence. I.e. `{{-`
     pub start: String,
@@ -28,68 +54,433 @@ pub struct Escape {
     pub end: String,
 }
 
+/// The forms an [`Escape`] may be written in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawEscape {
+    /// I.e. `"{{ }}"` or `"%%"`.
+    Text(String),
+    /// I.e. `["{{", "}}"]`.
+    Pair(String, String),
+    /// I.e. `{ start = "{{", end = "}}" }`.
+    Table { start: String, end: String },
+}
+
+/// The line prefix starting instructions.
+///
+/// Whitespace after the prefix is skipped, the prefix itself should not match it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Prefix {
+    /// A single prefix, i.e. `"#~"`.
+    Single(String),
+    /// Any of the prefixes, the longest matching one is used. I.e. `["#~", "//~"]`.
+    Any(Vec<String>),
+    /// A regular expression matching the prefix at the start of a line.
+    /// I.e. `{ regex = "(#|//)~" }`.
+    Regex { regex: String },
+}
+
+/// The source of a configuration.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Source {
+    /// A single file, i.e. `"git/config"`.
+    Single(PathBuf),
+    /// Fragments concatenated in order, i.e. `["git/base", "git/work"]`. The first
+    /// fragment is used like a single source for the preprocessed file and in logs.
+    Fragments(Vec<PathBuf>),
+}
+
+/// A [`Prefix`] ready to be matched against lines.
+enum PrefixMatcher<'a> {
+    Literals(Vec<&'a str>),
+    Regex(Regex),
+}
+
+/// The kind of link created at the target.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkType {
+    /// A symbolic link to the preprocessed file.
+    #[default]
+    Symlink,
+    /// A hard link to the preprocessed file.
+    Hardlink,
+}
+
+/// A source of substitutions.
+///
+/// Sources are merged in the order of declaration, later sources win.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubstitutionSource {
+    /// The `[substitutions]` of the configuration file.
+    Global,
+    /// Substitutions given by `--set` on the command line.
+    Cli,
+}
+
+/// The state of a target before linking, see [`FileConfig::backup_link`].
+#[derive(Debug)]
+pub enum LinkBackup {
+    /// The target did not exist.
+    Missing,
+    /// The target was a symbolic link to the given path.
+    Symlink(PathBuf),
+    /// The target was a file, that cannot be restored.
+    Unrestorable,
+}
+
+/// Everything a source file depends on, see [`FileConfig::dependencies`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Dependencies {
+    /// Names of environment variables, i.e. `HOME` for `$HOME`.
+    pub env_vars: BTreeSet<String>,
+    /// Commands of `$(...)` expansions.
+    pub commands: BTreeSet<String>,
+    /// Keys of substitutions between the escapes.
+    pub substitutions: BTreeSet<String>,
+}
+
+impl Dependencies {
+    /// Add the environment variables and commands referenced in `s`.
+    fn add_expansion(&mut self, s: &str) {
+        self.env_vars.extend(referenced_env_vars(s));
+        self.commands.extend(referenced_commands(s));
+    }
+    /// One line per dependency, like `env HOME`, `command date` or `substitution NAME`.
+    pub fn lines(&self) -> Vec<String> {
+        let env_vars = self.env_vars.iter().map(|var| format!("env {}", var));
+        let commands = self.commands.iter().map(|cmd| format!("command {}", cmd));
+        let substitutions = self
+            .substitutions
+            .iter()
+            .map(|key| format!("substitution {}", key));
+        env_vars.chain(commands).chain(substitutions).collect()
+    }
+}
+
 /// Configuration for a single dotfile.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FileConfig {
-    /// Source file that will be read and preprocessed.
-    source: PathBuf,
+    /// A name to select the file with `--only` and `--skip`.
+    name: Option<String>,
+    /// Whether the file is processed at all. Defaults to true.
+    #[serde(default = "super::default_true")]
+    enabled: bool,
+    /// Source file that will be read and preprocessed, see [`Source`].
+    source: Source,
     /// Target path that will link to the preprocessed file.
     target: PathBuf,
     /// Escape sequence to use for this configuration.
     escape: Option<Escape>,
-    /// Line prefix for commands.
-    prefix: Option<String>,
+    /// Line prefix for commands, see [`Prefix`].
+    prefix: Option<Prefix>,
+    /// Starts a comment after the prefix. Defaults to `#`.
+    comment_char: Option<String>,
     /// Remove instructions after processing?
     remove_instructions: Option<bool>,
+    /// Replace removed lines with empty lines, keeping the line numbers of the others.
+    #[serde(default)]
+    blank_instead_of_remove: bool,
+    /// Remove lines, that only become empty by substitutions. Lines, that are blank in
+    /// the source, are kept.
+    #[serde(default)]
+    collapse_empty: bool,
+    /// How deep substitutions in values of substitutions are resolved. Defaults to 1,
+    /// values are inserted as they are.
+    max_substitution_depth: Option<usize>,
+    /// The kind of link to create. Defaults to a symlink.
+    #[serde(default)]
+    link_type: LinkType,
+    /// Copy the preprocessed file, if a hard link cannot be created
+    /// across file systems or, on windows, a symbolic link without privileges.
+    #[serde(default)]
+    copy_fallback: bool,
+    /// Point symbolic links to the preprocessed file with a path relative to the
+    /// directory of the target, instead of an absolute path.
+    #[serde(default)]
+    relative_links: bool,
+    /// Keep the preprocessed file, if the target does not need it. Symbolic and
+    /// hard links always need it, copies do not.
+    #[serde(default = "super::default_true")]
+    keep_temp: bool,
+    /// Create missing parent directories of the target and the preprocessed file.
+    #[serde(default = "super::default_true")]
+    create_parents: bool,
+    /// The substitution sources to use for this file. Defaults to all sources.
+    substitution_sources: Option<Vec<SubstitutionSource>>,
+    /// Substitutions for this file only, winning over the global ones.
+    #[serde(default)]
+    substitutions: Substitutions,
+    /// Pass bytes that are not valid UTF-8 through untouched, instead of failing.
+    #[serde(default)]
+    allow_non_utf8: bool,
+    /// A shell command run before the source is read.
+    pre_process: Option<String>,
+    /// A shell command run after the target was linked.
+    post_link: Option<String>,
+    /// The directory to write the preprocessed file to. Defaults to the directory
+    /// of the source. Set from the global configuration.
+    #[serde(skip)]
+    output_dir: Option<PathBuf>,
+    /// How `$(...)` commands and hooks are run. Set from the global configuration.
+    #[serde(skip)]
+    command_settings: CommandSettings,
 }
 
 impl Escape {
+    /// Parse the start and end separated by whitespace, i.e. `"{{ }}"`.
+    ///
+    /// A single sequence, like `"%%"`, is used for both. Returns `None` for
+    /// more than two sequences or none at all.
+    pub fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<_> = s.split_whitespace().collect();
+        let (start, end) = match parts[..] {
+            [both] => (both, both),
+            [start, end] => (start, end),
+            _ => return None,
+        };
+        Some(Escape {
+            start: start.into(),
+            end: end.into(),
+        })
+    }
     /// Create a regular expression ([`Regex`]).
     ///
     /// The regular expression matches everything, inside `self.start` and `self.end`
-    /// and includes `self.start` and `self.end`. This does not match, if `self.start`
-    /// is preceded by a backslash (\).
-    fn to_regex(&self) -> Result<Regex> {
+    /// and includes `self.start` and `self.end`. The first group captures a backslash (\)
+    /// preceding `self.start`, such matches must be left untouched. The second group
+    /// captures the key. No character before `self.start` is consumed, adjacent
+    /// escapes thus match one after another.
+    fn to_regex(&self) -> ::std::result::Result<Regex, regex::Error> {
         let start = regex::escape(&self.start);
         let end = regex::escape(&self.end);
-        let s = format!(r"([^\\]){}(.*?[^\\]){}", start, end);
-        Regex::new(&s).map_err(Error::FailedToParseRegex)
+        let s = format!(r"(\\?){}(.*?[^\\]){}", start, end);
+        Regex::new(&s)
+    }
+}
+
+impl TryFrom<RawEscape> for Escape {
+    type Error = String;
+
+    fn try_from(raw: RawEscape) -> ::std::result::Result<Self, Self::Error> {
+        match raw {
+            RawEscape::Text(text) => Escape::parse(&text)
+                .ok_or_else(|| format!("escape {:?} needs a start and an end", text)),
+            RawEscape::Pair(start, end) | RawEscape::Table { start, end } => {
+                Ok(Escape { start, end })
+            }
+        }
+    }
+}
+
+impl Prefix {
+    /// Whether the prefix, or any of the prefixes, is empty and thus matches every line.
+    fn is_empty(&self) -> bool {
+        match self {
+            Prefix::Single(prefix) => prefix.is_empty(),
+            Prefix::Any(prefixes) => prefixes.is_empty() || prefixes.iter().any(String::is_empty),
+            Prefix::Regex { regex } => regex.is_empty(),
+        }
+    }
+    /// Compile the prefix, regular expressions are anchored to the start of the line.
+    fn matcher(&self) -> ::std::result::Result<PrefixMatcher<'_>, regex::Error> {
+        Ok(match self {
+            Prefix::Single(prefix) => PrefixMatcher::Literals(vec![prefix]),
+            Prefix::Any(prefixes) => {
+                PrefixMatcher::Literals(prefixes.iter().map(String::as_str).collect())
+            }
+            Prefix::Regex { regex } => {
+                PrefixMatcher::Regex(Regex::new(&format!("^(?:{})", regex))?)
+            }
+        })
+    }
+}
+
+impl From<&str> for Prefix {
+    fn from(prefix: &str) -> Self {
+        Prefix::Single(prefix.into())
+    }
+}
+
+impl Source {
+    /// All files of the source, in order.
+    pub fn paths(&self) -> &[PathBuf] {
+        match self {
+            Source::Single(path) => std::slice::from_ref(path),
+            Source::Fragments(paths) => paths,
+        }
+    }
+    /// The first file of the source, see [`Source::Fragments`].
+    ///
+    /// # Panics
+    /// Panics on empty fragments, which are rejected by [`FileConfig::validate`].
+    pub fn first(&self) -> &Path {
+        self.paths().first().expect("Sources are validated on load")
+    }
+}
+
+impl<P: Into<PathBuf>> From<P> for Source {
+    fn from(path: P) -> Self {
+        Source::Single(path.into())
+    }
+}
+
+impl<'a> PrefixMatcher<'a> {
+    /// The prefixes `line` may start with.
+    ///
+    /// A regular expression yields its non-empty match at the start of `line`, if any.
+    fn candidates<'l>(&'l self, line: &'l str) -> Vec<&'l str> {
+        match self {
+            PrefixMatcher::Literals(prefixes) => prefixes.clone(),
+            PrefixMatcher::Regex(regex) => regex
+                .find(line)
+                .map(|m| m.as_str())
+                .filter(|prefix| !prefix.is_empty())
+                .into_iter()
+                .collect(),
+        }
+    }
+    /// The line without one backslash escaping a prefix, see [`Command::unescape_line`].
+    fn unescape<'l>(&self, line: &'l str) -> Option<&'l str> {
+        let candidates = self.candidates(line.trim_start_matches('\\'));
+        Command::unescape_line(&candidates, line)
     }
 }
 
 impl FileConfig {
+    /// Create a configuration linking `target` to the preprocessed `source`.
+    ///
+    /// All options are unset, see [`FileConfig::supplement`].
+    pub fn new<P, Q>(source: P, target: Q) -> Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        FileConfig {
+            name: None,
+            enabled: true,
+            source: Source::Single(source.into()),
+            target: target.into(),
+            escape: None,
+            prefix: None,
+            comment_char: None,
+            remove_instructions: None,
+            blank_instead_of_remove: false,
+            collapse_empty: false,
+            max_substitution_depth: None,
+            link_type: LinkType::default(),
+            copy_fallback: false,
+            relative_links: false,
+            keep_temp: true,
+            create_parents: true,
+            substitution_sources: None,
+            substitutions: Substitutions::new(),
+            allow_non_utf8: false,
+            pre_process: None,
+            post_link: None,
+            output_dir: None,
+            command_settings: CommandSettings::default(),
+        }
+    }
+    /// Whether this file is processed, it must be enabled and not be filtered by `--only`
+    /// or `--skip`.
+    ///
+    /// Files without a name are never filtered by `--skip`, but always by `--only`.
+    pub fn is_selected(&self, opt: &Opt) -> bool {
+        let named = |names: &[String]| self.name.as_ref().is_some_and(|name| names.contains(name));
+        self.enabled && (opt.only.is_empty() || named(&opt.only)) && !named(&opt.skip)
+    }
+    /// The name of the configuration, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    /// The name used in log messages, the configured name if any, otherwise the
+    /// quoted source path, see [`display_path`].
+    pub fn display_name<P: AsRef<Path>>(&self, root: P, opt: &Opt) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("{:?}", display_path(self.source_path(root), opt)),
+        }
+    }
     /// Get the source path of the configuration.
     /// If source is relative, it will be interpreted as relative to the given root.
     /// If source is absolute, that path will be used.
-    /// Environment variables in the path will be interpreted before
-    /// determining whether the path is relative or not.
+    /// Environment variables in the path are expanded on load, see
+    /// [`FileConfig::expand_paths`].
+    ///
+    /// For fragments, this is the path of the first one, see [`Source`].
     pub fn source_path<P: AsRef<Path>>(&self, root: P) -> PathBuf {
-        root.as_ref().join(expand_env_path(&self.source))
+        root.as_ref().join(self.source.first())
     }
     /// Get the content of the source file.
     /// See [`FileConfig::source_path`] for the path that will be read.
-    pub fn source<P: AsRef<Path>>(&self, root: P) -> Result<String> {
-        let source_path = self.source_path(root);
-        fs::read_to_string(&source_path).map_err(|e| {
-            let path_string = source_path.to_string_lossy().into();
-            Error::FailedToReadSourceFile(path_string, e)
-        })
+    ///
+    /// Fragments are concatenated as they are, without adding newlines in between.
+    pub fn source<P: AsRef<Path>>(&self, root: P) -> Result<Vec<u8>> {
+        let mut content = vec![];
+        for path in self.source.paths() {
+            let path = root.as_ref().join(path);
+            let fragment = fs::read(&path).map_err(|e| {
+                let path_string = path.to_string_lossy().into();
+                Error::FailedToReadSourceFile(path_string, e)
+            })?;
+            content.extend(fragment);
+        }
+        Ok(content)
+    }
+    /// Split the `content` of the source file into regions of valid UTF-8,
+    /// each followed by the invalid bytes after it.
+    ///
+    /// # Errors
+    /// Fails on invalid UTF-8, unless `allow_non_utf8` is set.
+    fn decode<'a, P: AsRef<Path>>(
+        &self,
+        root: P,
+        content: &'a [u8],
+    ) -> Result<Vec<(&'a str, &'a [u8])>> {
+        match str::from_utf8(content) {
+            Ok(text) => Ok(vec![(text, &[])]),
+            Err(_) if self.allow_non_utf8 => Ok(content
+                .utf8_chunks()
+                .map(|chunk| (chunk.valid(), chunk.invalid()))
+                .collect()),
+            Err(e) => {
+                let path_string = self.source_path(root).to_string_lossy().into();
+                let e = io::Error::new(ErrorKind::InvalidData, e);
+                Err(Error::FailedToReadSourceFile(path_string, e))
+            }
+        }
     }
     /// Get the target path of the configuration.
     /// Behaves like [`FileConfig::source_path`] but returns the target path.
+    ///
+    /// Like `ln -s`, a target that is an existing directory is linked inside of it,
+    /// using the file name of the source.
     pub fn target_path<P: AsRef<Path>>(&self, root: P) -> PathBuf {
-        root.as_ref().join(expand_env_path(&self.target))
+        let root = root.as_ref();
+        let target_path = root.join(&self.target);
+        match self.source_path(root).file_name() {
+            Some(name) if target_path.is_dir() => target_path.join(name),
+            _ => target_path,
+        }
     }
     /// Get the temporary path for storing the preprocessed file.
     /// This will use the expanded source path (see [`FileConfig::source_path`])
-    /// and append [`COMPILED_SUFFIX`].
+    /// and append [`COMPILED_SUFFIX`]. If an output directory is set, the source path
+    /// relative to `root` is mirrored below it.
     pub fn temp_path<P: AsRef<Path>>(&self, root: P) -> PathBuf {
-        format!(
-            "{}{}",
-            self.source_path(root).to_string_lossy(),
-            COMPILED_SUFFIX
-        )
-        .into()
+        let root = root.as_ref();
+        let source_path = self.source_path(root);
+        let path = match self.output_dir {
+            Some(ref output_dir) => root
+                .join(output_dir)
+                .join(mirror_path(relative_path(&source_path, root))),
+            None => source_path,
+        };
+        format!("{}{}", path.to_string_lossy(), COMPILED_SUFFIX).into()
     }
     /// Write the given `content` to the temporary file.
     /// See [`FileConfig::temp_path`] for the path that will be used.
@@ -97,15 +488,21 @@ impl FileConfig {
     pub fn write_temp<P, S>(&self, root: P, content: S) -> Result<()>
     where
         P: AsRef<Path>,
-        S: AsRef<str>,
+        S: AsRef<[u8]>,
     {
+        let root = root.as_ref();
         let temp_path = self.temp_path(root);
+        if self.create_parents {
+            create_parent_dir(&temp_path)?;
+        }
         let mut temp = File::create(&temp_path).map_err(|e| {
             let path_string = temp_path.to_string_lossy().into();
             Error::FailedToOpenTempFile(path_string, e)
         })?;
-        write!(temp, "{}", content.as_ref())
+        // Keep the mode of the source, i.e. the executable bit of scripts
+        temp.write_all(content.as_ref())
             .and_then(|_| temp.sync_all())
+            .and_then(|_| copy_mode(self.source_path(root), &temp_path))
             .map_err(|e| {
                 let path_string = temp_path.to_string_lossy().into();
                 Error::FailedToWriteTempFile(path_string, e)
@@ -116,8 +513,101 @@ impl FileConfig {
     pub fn escape_regex(&self) -> Option<Regex> {
         self.escape
             .as_ref()
-            .map(Escape::to_regex)
-            .map(Result::unwrap)
+            .map(|escape| escape.to_regex().expect("Escapes are validated on load"))
+    }
+    /// Expand environment variables in the escape sequences.
+    ///
+    /// # Errors
+    /// Fails on unset variables, if `strict` is set.
+    pub fn expand_escape(&mut self, strict: bool) -> Result<()> {
+        if let Some(escape) = &mut self.escape {
+            escape.start = expand_env(&escape.start, strict)?;
+            escape.end = expand_env(&escape.end, strict)?;
+        }
+        Ok(())
+    }
+    /// Expand a leading `~` and environment variables in the source, target and
+    /// output paths, see [`expand_env_path`].
+    ///
+    /// This is done once on load, later changes of the environment do not affect the
+    /// paths. Configurations created with [`FileConfig::new`] are used as given.
+    ///
+    /// # Errors
+    /// Fails on unset variables, if `strict` is set.
+    pub fn expand_paths(&mut self, strict: bool) -> Result<()> {
+        match &mut self.source {
+            Source::Single(path) => *path = expand_env_path(path, strict)?,
+            Source::Fragments(paths) => {
+                for path in paths {
+                    *path = expand_env_path(path, strict)?;
+                }
+            }
+        }
+        self.target = expand_env_path(&self.target, strict)?;
+        if let Some(output_dir) = &mut self.output_dir {
+            *output_dir = expand_env_path(output_dir, strict)?;
+        }
+        Ok(())
+    }
+    /// Replace values like `$ENV:NAME` in the substitutions of this file.
+    ///
+    /// # Errors
+    /// Fails, if a variable is not set, see [`Config::load`](super::Config::load).
+    pub(super) fn resolve_env_values(&mut self) -> Result<()> {
+        super::resolve_env_values(&mut self.substitutions)
+    }
+    /// Check the options that cannot be checked while deserializing.
+    ///
+    /// # Errors
+    /// Fails, if the escape has an empty start or end, or the escape or the prefix
+    /// does not compile to a regex. Fails as well without any source fragments.
+    pub fn validate(&self) -> Result<()> {
+        if self.source.paths().is_empty() {
+            return Err(Error::EmptySource(self.target.clone()));
+        }
+        if let Some(prefix) = &self.prefix {
+            let source = self.source.first().to_string_lossy().into_owned();
+            prefix
+                .matcher()
+                .map_err(|e| Error::FailedToParsePrefixRegex(source, e))?;
+        }
+        if let Some(escape) = &self.escape {
+            let source = self.source.first().to_string_lossy().into_owned();
+            if escape.start.is_empty() || escape.end.is_empty() {
+                return Err(Error::EmptyEscape(source));
+            }
+            escape
+                .to_regex()
+                .map_err(|e| Error::FailedToParseRegex(source, e))?;
+        }
+        if self.comment_char.as_deref() == Some("") {
+            let source = self.source.first().to_string_lossy().into_owned();
+            return Err(Error::EmptyCommentChar(source));
+        }
+        Ok(())
+    }
+    /// Check everything, that [`FileConfig::validate`] does not, before any work is done.
+    ///
+    /// Returns all problems found: Source files, that cannot be opened, and an empty
+    /// prefix, unless `--no-instructions` is given.
+    pub fn check(&self, root: &Path, opt: &Opt) -> Vec<Error> {
+        let mut errors = vec![];
+        if let Err(e) = self.validate() {
+            errors.push(e);
+        }
+        for path in self.source.paths() {
+            let path = root.join(path);
+            if let Err(e) = File::open(&path) {
+                let path_string = path.to_string_lossy().into();
+                errors.push(Error::FailedToReadSourceFile(path_string, e));
+            }
+        }
+        let empty_prefix = self.prefix.as_ref().is_some_and(Prefix::is_empty);
+        if empty_prefix && !opt.no_instructions {
+            let source = self.source.first().to_string_lossy().into_owned();
+            errors.push(Error::EmptyPrefix(source));
+        }
+        errors
     }
     /// Replace `None`s with the given defaults.
     /// Defined values (`Some`s) will not be changed.
@@ -125,7 +615,10 @@ impl FileConfig {
         &mut self,
         escape: &Option<Escape>,
         remove_instructions: bool,
-        prefix: &Option<String>,
+        prefix: &Option<Prefix>,
+        comment_char: &Option<String>,
+        max_substitution_depth: Option<usize>,
+        output_dir: &Option<PathBuf>,
     ) {
         if self.escape.is_none() {
             self.escape = escape.clone();
@@ -136,56 +629,423 @@ impl FileConfig {
         if self.prefix.is_none() {
             self.prefix = prefix.clone();
         }
+        if self.comment_char.is_none() {
+            self.comment_char = comment_char.clone();
+        }
+        if self.max_substitution_depth.is_none() {
+            self.max_substitution_depth = max_substitution_depth;
+        }
+        if self.output_dir.is_none() {
+            self.output_dir = output_dir.clone();
+        }
+    }
+    /// Set how `$(...)` commands and hooks of this file are run.
+    pub fn set_command_settings(&mut self, settings: CommandSettings) {
+        self.command_settings = settings;
+    }
+    /// Merge the substitutions of all sources used by this configuration.
+    ///
+    /// See [`SubstitutionSource`] for the order of precedence. The substitutions of
+    /// this file win over the global ones, but not over the command line.
+    pub fn substitutions(&self, global: &Substitutions, opt: &Opt) -> Substitutions {
+        let uses = |source| match self.substitution_sources {
+            Some(ref sources) => sources.contains(&source),
+            None => true,
+        };
+        let mut substitutions = Substitutions::new();
+        if uses(SubstitutionSource::Global) {
+            substitutions.extend(global.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        substitutions.extend(self.substitutions.clone());
+        if uses(SubstitutionSource::Cli) {
+            substitutions.extend(opt.set.iter().cloned());
+        }
+        substitutions
+    }
+    /// Apply the header of the given `content`, if any.
+    ///
+    /// The first line of a source file may contain a header like
+    /// `# dope: prefix="#~" escape="{{ }}"`, which defines the prefix and
+    /// the escape sequences (separated by whitespace) for the file.
+    /// Values from the configuration always win over the header, the header
+    /// is only used for options neither set for the file nor by a default.
+    ///
+    /// Returns the resulting configuration and the content without the header line.
+    ///
+    /// # Errors
+    /// Fails, if the header sets an empty prefix or an invalid escape, see
+    /// [`FileConfig::validate`].
+    fn apply_header<'a>(&self, content: &'a str) -> Result<(Cow<'_, Self>, &'a str)> {
+        let (first_line, rest) = match content.find('\n') {
+            Some(idx) => (&content[..idx], &content[idx + 1..]),
+            None => (content, ""),
+        };
+        let pairs = match RE_HEADER.captures(first_line.trim_end_matches('\r')) {
+            Some(captures) => captures.get(1).unwrap().as_str(),
+            None => return Ok((Cow::Borrowed(self), content)),
+        };
+        let mut config = self.clone();
+        for captures in RE_HEADER_PAIR.captures_iter(pairs) {
+            match (&captures[1], &captures[2]) {
+                ("prefix", "") if config.prefix.is_none() => {
+                    let source = self.source.first().to_string_lossy().into_owned();
+                    return Err(Error::EmptyPrefix(source));
+                }
+                ("prefix", prefix) if config.prefix.is_none() => {
+                    config.prefix = Some(Prefix::Single(prefix.into()));
+                }
+                ("escape", escape) if config.escape.is_none() => match Escape::parse(escape) {
+                    Some(escape) => config.escape = Some(escape),
+                    None => warn!("Header escape {:?} needs a start and an end", escape),
+                },
+                ("prefix", _) | ("escape", _) => {}
+                (key, _) => warn!("Unknown header option {:?}", key),
+            }
+        }
+        config.validate()?;
+        Ok((Cow::Owned(config), rest))
     }
     /// Preprocess this configuration file.
     ///
     /// 1) Replace substitutions.
     /// 2) Evaluate preprocessor instructions.
     /// 3) Write the file.
-    pub fn preprocess(&self, substitutions: &Substitutions, opt: &Opt) -> Result<()> {
-        // Root directory, config directory.
-        let root = opt.config_file.parent().expect("No root directory found");
-        info!("Preprocessing {:?}", self.source_path(root));
+    ///
+    /// Returns the sorted numbers of the source lines, that are not part of the output,
+    /// and the number of replaced substitution keys.
+    pub fn preprocess(
+        &self,
+        root: &Path,
+        substitutions: &Substitutions,
+        opt: &Opt,
+    ) -> Result<(Vec<usize>, usize)> {
+        info!("Preprocessing {}", self.display_name(root, opt));
+        self.check_collision(root, opt)?;
+        self.run_hook("pre_process", &self.pre_process, opt)?;
         // Read the file's contents
         let content = self.source(root)?;
-        // Evaluate preprocessor instructions.
-        let new_content = self.preprocess_instructions(&content)?;
-        // Replace substitutions.
-        let newest_content = self.preprocess_substitutions(&new_content, substitutions);
+        let mut regions = self.decode(root, &content)?;
+        // Strip the header and use the options defined there
+        let (config, first_region) = self.apply_header(regions[0].0)?;
+        let first_region = if opt.no_instructions {
+            regions[0].0
+        } else {
+            first_region
+        };
+        // Line numbers start after the header
+        let mut line_nr = first_line_nr(regions[0].0, first_region);
+        regions[0].0 = first_region;
+        let mut substitutions = self.substitutions(substitutions, opt);
+        let mut answers = vec![];
+        let mut skipped_lines = vec![];
+        let mut substituted = 0;
+        let mut newest_content = vec![];
+        if line_nr > 1 && self.blank_instead_of_remove {
+            newest_content.push(b'\n');
+        }
+        // Only valid UTF-8 is preprocessed, invalid bytes are copied
+        for (text, invalid) in regions {
+            let (region, evaluation, count) =
+                config.process_text(text, root, line_nr, &mut substitutions, opt)?;
+            substituted += count;
+            // Invalid bytes never contain a newline
+            line_nr += text.matches('\n').count();
+            newest_content.extend_from_slice(region.as_bytes());
+            // Removing all lines drops the final newline, keep the invalid bytes on their own line
+            if !invalid.is_empty() && text.ends_with('\n') && !region.ends_with('\n') {
+                newest_content.push(b'\n');
+            }
+            newest_content.extend_from_slice(invalid);
+            answers.extend(evaluation.answers);
+            skipped_lines.extend(evaluation.skips);
+        }
+        skipped_lines.sort_unstable();
+        info!(
+            "{}: removed {} lines, applied {} substitutions",
+            self.display_name(root, opt),
+            skipped_lines.len(),
+            substituted
+        );
+        if opt.dry_run {
+            for answer in &answers {
+                info!(
+                    "Line {}: {:?} answered with {:?} ({})",
+                    answer.line_nr, answer.question, answer.answer, answer.source
+                );
+            }
+            info!(
+                "Dry run, not writing {:?}",
+                display_path(self.temp_path(root), opt)
+            );
+            return Ok((skipped_lines, substituted));
+        }
         // Write the preprocessed file.
-        self.write_temp(root, newest_content)
+        self.write_temp(root, newest_content)?;
+        Ok((skipped_lines, substituted))
     }
-    /// Create a symbolic link from target to source.
-    pub fn create_link(&self, opt: &Opt) -> Result<()> {
-        let mut linker = get_link_function();
-        let root = opt.config_file.parent().expect("No root found");
+    /// Preprocess the `content` of a source file and return the result.
+    ///
+    /// Like [`FileConfig::preprocess`], but nothing is read from or written to
+    /// the file system. The `substitutions` are used as they are, see
+    /// [`FileConfig::substitutions`] for selecting them. Relative paths of `IFEXISTS`
+    /// are resolved against the current directory.
+    pub fn preprocess_content(
+        &self,
+        content: &str,
+        substitutions: &Substitutions,
+        opt: &Opt,
+    ) -> Result<String> {
+        let (config, rest) = self.apply_header(content)?;
+        let rest = if opt.no_instructions { content } else { rest };
+        let mut substitutions = substitutions.clone();
+        let line_nr = first_line_nr(content, rest);
+        let root = Path::new(".");
+        let (content, _, _) = config.process_text(rest, root, line_nr, &mut substitutions, opt)?;
+        // The removed header is blanked like any other line
+        if line_nr > 1 && self.blank_instead_of_remove {
+            Ok(format!("\n{}", content))
+        } else {
+            Ok(content)
+        }
+    }
+    /// Evaluate the instructions and insert the substitutions in `text`, whose first
+    /// line is line `first_line_nr` of the file. Relative paths are resolved against `root`.
+    ///
+    /// Substitutions defined by instructions are moved from the returned [`Evaluation`]
+    /// to `substitutions`. The number of replaced keys is returned as well.
+    fn process_text(
+        &self,
+        text: &str,
+        root: &Path,
+        first_line_nr: usize,
+        substitutions: &mut Substitutions,
+        opt: &Opt,
+    ) -> Result<(String, Evaluation, usize)> {
+        // Evaluate preprocessor instructions.
+        let (new_content, mut evaluation) = if opt.no_instructions {
+            (Cow::from(text), Evaluation::default())
+        } else {
+            self.preprocess_instructions(text, root, first_line_nr, opt)?
+        };
+        if opt.no_substitute {
+            return Ok((new_content.into_owned(), evaluation, 0));
+        }
+        // Replace substitutions, answers to questions win over configured ones.
+        substitutions.extend(evaluation.substitutions.drain());
+        let (content, count) = self.preprocess_substitutions(&new_content, substitutions, opt)?;
+        Ok((content.into_owned(), evaluation, count))
+    }
+    /// Create a link from target to source and run the `post_link` hook.
+    ///
+    /// Depending on the [`LinkType`], this is a symbolic or a hard link.
+    pub fn create_link(&self, root: &Path, opt: &Opt) -> Result<()> {
+        self.link_target(root, opt)?;
+        self.run_hook("post_link", &self.post_link, opt)
+    }
+    /// Run the `hook` named `name`, if it is set. Nothing is run in a dry run.
+    ///
+    /// # Errors
+    /// A failing hook is only logged, unless `--strict` is given.
+    fn run_hook(&self, name: &str, hook: &Option<String>, opt: &Opt) -> Result<()> {
+        let command = match hook {
+            Some(command) if !opt.dry_run => command,
+            _ => return Ok(()),
+        };
+        info!("Running {} hook {:?}", name, command);
+        match env::run_hook(command, &self.command_settings) {
+            Err(e) if !opt.strict => {
+                warn!("{}", e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    /// Link the target to the preprocessed file, see [`FileConfig::create_link`].
+    fn link_target(&self, root: &Path, opt: &Opt) -> Result<()> {
         // Expand environment variables in the paths
         let target_path = self.target_path(root);
         let source_path = self.source_path(root);
+        self.check_collision(root, opt)?;
+        // Nothing to link to, before the target is touched
+        let temp_path = self.temp_path(root);
+        if !temp_path.is_file() {
+            return Err(Error::MissingPreprocessedFile(display_path(temp_path, opt)));
+        }
         // If the target already exists...
         if target_path.exists() {
             // Verify, that it's just a link...
             let target_md = fs::symlink_metadata(&target_path)
                 .map_err(|e| Error::as_failed_link(&source_path, &target_path, e))?;
-            if target_md.file_type().is_symlink() {
-                // ... and remove it
-                fs::remove_file(&target_path)
-                    .map_err(|e| Error::as_failed_link(&source_path, &target_path, e))?;
-            } else {
-                // If it's not a symlink, we should not delete it
-                return Err(Error::TargetAlreadyExists(target_path));
+            let is_our_hardlink =
+                self.link_type == LinkType::Hardlink && is_same_file(&target_path, &temp_path);
+            // ... which will be replaced below
+            if !target_md.file_type().is_symlink() && !is_our_hardlink {
+                // If it's not a symlink, we should not replace it
+                return Err(Error::TargetAlreadyExists(display_path(target_path, opt)));
             }
         }
+        if self.create_parents {
+            create_parent_dir(&target_path)?;
+        }
         // Get the temp path and remove garbage. This makes the path
         // absolute and removes redundent parts. This is necessary to
         // prevent bad and ugly links.
-        let source_path: PathBuf = self
-            .temp_path(root)
+        let source_path: PathBuf = temp_path
             .canonicalize()
             .map_err(|e| Error::as_failed_link(&source_path, &target_path, e))?;
-        // Create a link from target to source
-        info!("Linking {:?} to {:?}", &source_path, &target_path);
-        linker(source_path, target_path)?;
+        // The path stored in a symbolic link
+        let link_path = match canonicalize_parent(&target_path).parent() {
+            Some(dir) if self.relative_links => path_from_dir(&source_path, dir),
+            _ => source_path.clone(),
+        };
+        // Keep a correct link untouched, to not change its modification time
+        let already_linked = match self.link_type {
+            LinkType::Symlink => fs::read_link(&target_path).ok().as_ref() == Some(&link_path),
+            LinkType::Hardlink => is_same_file(&target_path, &source_path),
+        };
+        if already_linked {
+            info!(
+                "{:?} is already linked to {:?}",
+                display_path(&target_path, opt),
+                display_path(&source_path, opt)
+            );
+            return Ok(());
+        }
+        // Create a link from target to source, replacing the old one atomically
+        info!(
+            "Linking {} to {:?}",
+            self.display_name(root, opt),
+            display_path(&target_path, opt)
+        );
+        link_atomically(&source_path, &target_path, |src, dst| {
+            match self.link_type {
+                LinkType::Symlink => get_link_function(self.copy_fallback)(&link_path, dst),
+                LinkType::Hardlink => hard_link_or_copy(src, dst, self.copy_fallback),
+            }
+        })?;
+        // A copy does not need the preprocessed file anymore
+        let copied =
+            self.link_type == LinkType::Hardlink && !is_same_file(&target_path, &source_path);
+        if copied && !self.keep_temp {
+            info!("Removing {:?}", display_path(&source_path, opt));
+            fs::remove_file(&source_path)
+                .map_err(|e| Error::as_failed_link(&source_path, &target_path, e))?;
+        }
+        Ok(())
+    }
+    /// Make sure, that neither writing the preprocessed file nor linking the target
+    /// overwrites the source or the preprocessed file.
+    ///
+    /// The target itself is not resolved, it usually links to the preprocessed file.
+    ///
+    /// # Errors
+    /// Fails with [`Error::SourceTargetCollision`], if any two of the source, the
+    /// preprocessed file and the target are the same file.
+    fn check_collision(&self, root: &Path, opt: &Opt) -> Result<()> {
+        let source_path = self.source_path(root);
+        let target_path = self.target_path(root);
+        let source = canonicalize_lenient(&source_path);
+        let temp = canonicalize_lenient(&self.temp_path(root));
+        let target = canonicalize_parent(&target_path);
+        if source == temp || source == target || temp == target {
+            return Err(Error::SourceTargetCollision(
+                display_path(source_path, opt),
+                display_path(target_path, opt),
+            ));
+        }
+        Ok(())
+    }
+    /// Remember the current state of the target, before [`FileConfig::create_link`] is called.
+    pub fn backup_link(&self, root: &Path) -> LinkBackup {
+        let target_path = self.target_path(root);
+        match fs::symlink_metadata(&target_path) {
+            Err(_) => LinkBackup::Missing,
+            Ok(md) if md.file_type().is_symlink() => match fs::read_link(&target_path) {
+                Ok(path) => LinkBackup::Symlink(path),
+                Err(_) => LinkBackup::Unrestorable,
+            },
+            Ok(_) => LinkBackup::Unrestorable,
+        }
+    }
+    /// Restore the target to the state remembered by [`FileConfig::backup_link`].
+    pub fn restore_link(&self, root: &Path, backup: LinkBackup, opt: &Opt) -> Result<()> {
+        let target_path = self.target_path(root);
+        match backup {
+            LinkBackup::Missing => fs::remove_file(&target_path)
+                .map_err(|e| Error::as_failed_link(self.temp_path(root), &target_path, e)),
+            LinkBackup::Symlink(path) => {
+                info!(
+                    "Restoring link {:?} to {:?}",
+                    display_path(&path, opt),
+                    display_path(&target_path, opt)
+                );
+                link_atomically(path, &target_path, |src, dst| {
+                    get_link_function(false)(src, dst)
+                })
+            }
+            LinkBackup::Unrestorable => {
+                warn!(
+                    "Cannot restore {:?}, it was replaced",
+                    display_path(&target_path, opt)
+                );
+                Ok(())
+            }
+        }
+    }
+    /// Remove the target and the preprocessed file.
+    ///
+    /// The target is only removed, if it links to the preprocessed file. With
+    /// `restore_backup`, `<target>.bak` is moved back to the target afterwards.
+    ///
+    /// # Errors
+    /// Fails, if the target is not our link or removing a file fails.
+    pub fn remove_link(&self, root: &Path, restore_backup: bool, opt: &Opt) -> Result<()> {
+        let target_path = self.target_path(root);
+        let temp_path = self.temp_path(root);
+        let remove = |path: &Path| {
+            if opt.dry_run {
+                info!("Dry run, not removing {:?}", display_path(path, opt));
+                return Ok(());
+            }
+            info!("Removing {:?}", display_path(path, opt));
+            fs::remove_file(path)
+                .map_err(|e| Error::FailedToRemoveFile(path.to_string_lossy().into(), e))
+        };
+        if let Ok(target_md) = fs::symlink_metadata(&target_path) {
+            // Never delete files we did not link
+            // Relative links are relative to the directory of the target
+            let target_dir = target_path.parent().unwrap_or_else(|| Path::new("."));
+            let linked_path = fs::read_link(&target_path).map(|link| target_dir.join(link));
+            let is_our_symlink = target_md.file_type().is_symlink()
+                && linked_path.ok().map(|path| canonicalize_lenient(&path))
+                    == Some(canonicalize_lenient(&temp_path));
+            let is_our_hardlink =
+                self.link_type == LinkType::Hardlink && is_same_file(&target_path, &temp_path);
+            if !is_our_symlink && !is_our_hardlink {
+                return Err(Error::ForeignTarget(display_path(target_path, opt)));
+            }
+            remove(&target_path)?;
+        }
+        let backup_path = PathBuf::from(format!(
+            "{}{}",
+            target_path.to_string_lossy(),
+            BACKUP_SUFFIX
+        ));
+        if restore_backup && fs::symlink_metadata(&backup_path).is_ok() {
+            info!(
+                "Restoring {:?} from {:?}",
+                display_path(&target_path, opt),
+                display_path(&backup_path, opt)
+            );
+            if !opt.dry_run {
+                fs::rename(&backup_path, &target_path).map_err(|e| {
+                    Error::FailedToRemoveFile(backup_path.to_string_lossy().into(), e)
+                })?;
+            }
+        }
+        if temp_path.exists() {
+            remove(&temp_path)?;
+        }
         Ok(())
     }
     /// Preprocess substitutions.
@@ -193,80 +1053,1335 @@ impl FileConfig {
     /// all occurences of `{++KEY++}` with the `VALUE` defined in the given
     /// [`Substitutions`]. The returned content is unaltered, if no escape sequences
     /// are defined, or no usage is found in the given `content`.
+    ///
+    /// Unknown keys are expanded like a *var*. Unknown keys without `$` or `%`, that
+    /// thus cannot be variables, are most likely typos and are warned about.
+    ///
+    /// Returns the content together with the number of replaced keys.
+    ///
+    /// # Errors
+    /// Fails, if the expansion of an unknown key fails, see [`expand`].
+    /// With `--strict`, unknown keys, that are no variables, fail as well.
     fn preprocess_substitutions<'a>(
         &self,
         content: &'a str,
         substitutions: &Substitutions,
-    ) -> Cow<'a, str> {
-        // Create a replacer for regex replacements
-        let replacer = construct_replacer(substitutions);
+        opt: &Opt,
+    ) -> Result<(Cow<'a, str>, usize)> {
+        // The first error encountered while expanding
+        let mut error = None;
+        // Unknown keys, that are not variables
+        let mut unknown = vec![];
+        // The number of replaced keys
+        let mut count = 0;
         // Get the regex specified explicitly for this file configuration
         let regex = self.escape_regex();
         // Only if we have a regex to work with
-        if let Some(regex) = regex {
+        let content = if let Some(regex) = regex {
+            // Resolve substitutions used in values of other substitutions
+            let max_depth = self.max_substitution_depth.unwrap_or(1);
+            let substitutions = resolve_substitutions(substitutions, &regex, max_depth)?;
+            // Create a replacer for regex replacements
+            let mut replacer = construct_replacer(
+                &substitutions,
+                &self.command_settings,
+                &mut error,
+                &mut unknown,
+                &mut count,
+            );
             // Create the final file content by replacing stuff
-            regex.replace_all(content.as_ref(), replacer)
+            if self.collapse_empty {
+                content
+                    .split_inclusive('\n')
+                    .map(|line| (line, regex.replace_all(line, &mut replacer)))
+                    .filter(|(line, new)| line.trim().is_empty() || !new.trim().is_empty())
+                    .map(|(_, new)| new)
+                    .collect::<String>()
+                    .into()
+            } else {
+                regex.replace_all(content, replacer)
+            }
         } else {
             // If no regex is given, inform the user
-            info!("No escape characters defined, no substitution will be made");
+            debug!("No escape characters defined, no substitution will be made");
             Cow::from(content)
+        };
+        if let Some(e) = error {
+            return Err(e);
         }
+        if opt.strict && !unknown.is_empty() {
+            let errors = unknown
+                .into_iter()
+                .map(Error::UnknownSubstitution)
+                .collect();
+            return Err(Error::from_errors(errors));
+        }
+        for key in unknown {
+            warn!("Unknown substitution {:?}", key);
+        }
+        Ok((content, count))
     }
-    /// Preprocess instructions
-    fn preprocess_instructions<'a>(&self, content: &'a str) -> Result<Cow<'a, str>> {
+    /// Parse all instructions in `content`, together with their line numbers.
+    /// The first line of `content` is line `first_line_nr`.
+    ///
+    /// Returns the errors of all lines that start with the prefix, but do not parse,
+    /// as well.
+    fn parse_instructions<'a>(
+        &'a self,
+        content: &'a str,
+        first_line_nr: usize,
+    ) -> (Vec<(usize, Command<'a>)>, Vec<Error>) {
+        let mut cmds = vec![];
+        let mut errors = vec![];
         let prefix = match self.prefix.as_ref() {
-            Some(prefix) => prefix,
-            None => {
-                // Do nothing
-                return Ok(Cow::from(content));
+            Some(prefix) => prefix.matcher().expect("Prefixes are validated on load"),
+            None => return (cmds, errors),
+        };
+        let comment = self.comment_char.as_deref().unwrap_or(DEFAULT_COMMENT_CHAR);
+        for (line_nr, line) in (first_line_nr..).zip(content.lines()) {
+            match Command::parse_from_line(&prefix.candidates(line), comment, line) {
+                Some(Ok(cmd)) => cmds.push((line_nr, cmd)),
+                Some(Err(e)) => errors.push(e),
+                None => {}
+            }
+        }
+        (cmds, errors)
+    }
+    /// Collect the environment variables, commands and substitution keys the source
+    /// file depends on, without expanding or running anything.
+    ///
+    /// Arguments of instructions, i.e. `$HOST` of `IFDEF $HOST`, are included. The header
+    /// of the source file is respected, lines that are not valid UTF-8 are replaced lossily.
+    pub fn dependencies<P: AsRef<Path>>(&self, root: P) -> Result<Dependencies> {
+        let content = self.source(root)?;
+        let content = String::from_utf8_lossy(&content);
+        let (config, rest) = self.apply_header(&content)?;
+        let first_line_nr = first_line_nr(&content, rest);
+        let (cmds, errors) = config.parse_instructions(rest, first_line_nr);
+        if !errors.is_empty() {
+            return Err(Error::from_errors(errors));
+        }
+        let mut dependencies = Dependencies::default();
+        for (_, cmd) in &cmds {
+            cmd.expanded_args()
+                .iter()
+                .for_each(|arg| dependencies.add_expansion(arg));
+            match cmd {
+                Command::IfSet(name) => {
+                    dependencies.env_vars.insert((*name).into());
+                }
+                // Paths are expanded without running commands
+                Command::IfExists(path) | Command::IfNExists(path) => {
+                    dependencies.env_vars.extend(referenced_env_vars(path));
+                }
+                _ => {}
             }
+        }
+        let regex = match config.escape_regex() {
+            Some(regex) => regex,
+            None => return Ok(dependencies),
         };
-        let (mut cmd_lines, mut errors): (Vec<_>, Vec<_>) = content
-            .lines()
-            .enumerate()
-            .filter_map(|(line_nr, line)| {
-                Command::parse_from_line(prefix, line).map(|res| (line_nr, res))
-            })
-            .partition(|(_, res)| res.is_ok());
-        if errors.len() > 0 {
-            return errors.remove(0).1.map(|_| Cow::from(content));
-        }
-        let cmd_lines: Vec<_> = cmd_lines
-            .drain(..)
-            .map(|(line_nr, res)| (line_nr, res.unwrap()))
-            .collect();
-        let mut skips = CommandReader::read(&cmd_lines)?;
+        let cmd_line_nrs: HashSet<_> = cmds.iter().map(|(line_nr, _)| *line_nr).collect();
+        for (line_nr, line) in (first_line_nr..).zip(rest.lines()) {
+            if cmd_line_nrs.contains(&line_nr) {
+                continue;
+            }
+            // Skip escaped escapes, keys with `$` or `%` are expanded like a var
+            for captures in regex.captures_iter(line).filter(|c| c[1].is_empty()) {
+                match split_filters(&captures[2]).0 {
+                    key if key.contains(&['$', '%'][..]) => dependencies.add_expansion(key),
+                    key => {
+                        dependencies.substitutions.insert(key.into());
+                    }
+                }
+            }
+        }
+        Ok(dependencies)
+    }
+    /// List all instructions of the source file, one per line with its line number.
+    ///
+    /// The header of the source file is respected, lines that are not valid UTF-8 are
+    /// replaced lossily.
+    pub fn list_instructions<P: AsRef<Path>>(&self, root: P) -> Result<Vec<String>> {
+        let content = self.source(root)?;
+        let content = String::from_utf8_lossy(&content);
+        let (config, rest) = self.apply_header(&content)?;
+        let (cmds, errors) = config.parse_instructions(rest, first_line_nr(&content, rest));
+        if !errors.is_empty() {
+            return Err(Error::from_errors(errors));
+        }
+        Ok(cmds
+            .iter()
+            .map(|(line_nr, cmd)| format!("{}: {}", line_nr, cmd))
+            .collect())
+    }
+    /// Preprocess instructions
+    ///
+    /// Returns the remaining content and the [`Evaluation`] of the instructions.
+    /// The skips of the evaluation include removed instruction lines.
+    fn preprocess_instructions<'a>(
+        &self,
+        content: &'a str,
+        root: &Path,
+        first_line_nr: usize,
+        opt: &Opt,
+    ) -> Result<(Cow<'a, str>, Evaluation)> {
+        let prefix = match self.prefix {
+            Some(ref prefix) => prefix.matcher().expect("Prefixes are validated on load"),
+            // Do nothing
+            None => return Ok((Cow::from(content), Evaluation::default())),
+        };
+        let (cmd_lines, mut errors) = self.parse_instructions(content, first_line_nr);
+        if !errors.is_empty() {
+            // Report all malformed instructions at once, unless we should stop at the first
+            return Err(if opt.panic {
+                errors.remove(0)
+            } else {
+                Error::from_errors(errors)
+            });
+        }
+        let mut evaluation = CommandReader::read(&cmd_lines, root, &self.command_settings, opt)?;
+        let skips = &mut evaluation.skips;
         // Add command lines to skip if necessary
         if self.remove_instructions.expect("Default") {
             let mut cmd_line_nrs = cmd_lines.iter().map(|(line_nr, _)| *line_nr);
             skips.extend(&mut cmd_line_nrs);
         }
-        if skips.is_empty() {
-            Ok(Cow::from(content))
+        // Lines escaping the prefix lose one backslash
+        let escaped = content.lines().any(|line| prefix.unescape(line).is_some());
+        let unchanged = evaluation.insertions.is_empty() && evaluation.loops.is_empty() && !escaped;
+        if skips.is_empty() && unchanged {
+            Ok((Cow::from(content), evaluation))
         } else {
-            let remaining_lines: Vec<_> = content
-                .lines()
-                .enumerate()
-                .filter(|(line_nr, _)| !skips.contains(line_nr))
-                .map(|(_, line)| line)
-                .collect();
+            let lines: Vec<_> = (first_line_nr..).zip(content.lines()).collect();
+            let escape = self.escape_regex();
+            let blank = self.blank_instead_of_remove;
+            let remaining_lines =
+                render_lines(&lines, &evaluation, &prefix, escape.as_ref(), blank);
             // TODO: Plattform independet line endings
-            Ok(remaining_lines.join("\n").into())
+            let mut remaining_content = remaining_lines.join("\n");
+            // Joining drops the final newline
+            if content.ends_with('\n') && !remaining_lines.is_empty() {
+                remaining_content.push('\n');
+            }
+            Ok((remaining_content.into(), evaluation))
+        }
+    }
+}
+
+/// Join the remaining `lines` of a file with the insertions of the `evaluation` and
+/// repeat its loops.
+///
+/// Inserted text follows the line of its command, taking its place, if the command
+/// line is removed. A loop repeats the rendered lines between `FOR` and `ENDFOR`
+/// once per item, replacing the substitution of its name by the item. With `blank`,
+/// removed lines are replaced by empty lines.
+fn render_lines<'a>(
+    lines: &[(usize, &'a str)],
+    evaluation: &'a Evaluation,
+    prefix: &PrefixMatcher<'_>,
+    escape: Option<&Regex>,
+    blank: bool,
+) -> Vec<Cow<'a, str>> {
+    let mut rendered = vec![];
+    let mut idx = 0;
+    while let Some(&(line_nr, line)) = lines.get(idx) {
+        idx += 1;
+        let insertion = evaluation.insertions.get(&line_nr);
+        if !evaluation.skips.contains(&line_nr) {
+            rendered.push(Cow::from(prefix.unescape(line).unwrap_or(line)));
+        } else if blank && insertion.is_none() {
+            rendered.push(Cow::from(""));
+        }
+        if let Some(text) = insertion {
+            rendered.push(Cow::from(text.as_str()));
+        }
+        let repeat = evaluation
+            .loops
+            .iter()
+            .find(|repeat| repeat.first_line == line_nr);
+        if let Some(repeat) = repeat {
+            // The `ENDFOR` line is rendered as usual
+            let end = lines[idx..]
+                .iter()
+                .position(|(line_nr, _)| *line_nr == repeat.end_line)
+                .map_or(lines.len(), |pos| idx + pos);
+            let block = render_lines(&lines[idx..end], evaluation, prefix, escape, blank);
+            for item in &repeat.items {
+                let bound = block
+                    .iter()
+                    .map(|line| bind_item(line, &repeat.name, item, escape));
+                rendered.extend(bound.map(Cow::from));
+            }
+            idx = end;
         }
     }
+    rendered
+}
+
+/// Replace the substitution `name` in `line` by `item`.
+///
+/// Escaped substitutions are kept, see [`Escape::to_regex`]. The item passes through
+/// the filters of the substitution, see [`split_filters`]. Without escapes, the
+/// `line` is returned unchanged.
+fn bind_item(line: &str, name: &str, item: &str, escape: Option<&Regex>) -> String {
+    match escape {
+        Some(regex) => regex
+            .replace_all(line, |captures: &Captures| {
+                match split_filters(&captures[2]) {
+                    (key, filters) if captures[1].is_empty() && key == name => filters
+                        .iter()
+                        .try_fold(item.to_string(), |value, filter| {
+                            apply_filter(Some(value), filter, key)
+                        })
+                        .unwrap_or_default(),
+                    _ => captures[0].to_string(),
+                }
+            })
+            .into_owned(),
+        None => line.into(),
+    }
+}
+
+/// The number of the first line of `rest`, the remainder of `content` after
+/// [`FileConfig::apply_header`]. Lines are counted from 1, like in editors.
+fn first_line_nr(content: &str, rest: &str) -> usize {
+    if rest.len() < content.len() {
+        2
+    } else {
+        1
+    }
 }
 
 /// Create a [`regex::Replacer`] for the given substitutions. This replacer
 /// can then be used to replace instances found by the regular expression
-/// created by any [`Escape::to_regex`].
+/// created by any [`Escape::to_regex`]. Commands in unknown keys are run with
+/// `settings`.
+///
+/// The first expansion error is stored in `error`, expansion of
+/// unknown keys is skipped after that. Unknown keys, that are no
+/// variables, are added to `unknown`. Every replacement, that is not
+/// escaped, is counted in `count`. Escaped keys lose their backslash.
+///
+/// The value may pass through filters, i.e. `{{ NAME | default:World | upper }}`,
+/// see [`split_filters`] and [`apply_filter`].
 fn construct_replacer<'a>(
     substitutions: &'a Substitutions,
+    settings: &'a CommandSettings,
+    error: &'a mut Option<Error>,
+    unknown: &'a mut Vec<String>,
+    count: &'a mut usize,
 ) -> impl FnMut(&Captures) -> String + 'a {
     move |captures| {
-        let inner = &captures[2];
-        match substitutions.get(inner) {
-            Some(repl) => format!("{}{}", expand(&captures[1]), repl),
-            None => format!("{}{}", &captures[1], expand(inner)),
+        // Escaped by a backslash, keep the key literally
+        if !captures[1].is_empty() {
+            return captures[0][captures[1].len()..].into();
+        }
+        *count += 1;
+        let (inner, filters) = split_filters(&captures[2]);
+        let is_var = inner.contains(&['$', '%'][..]);
+        let value = match substitutions.get(inner) {
+            Some(repl) => Some(repl.clone()),
+            None if error.is_some() => Some(String::new()),
+            None if !is_var => None,
+            None => match expand(inner, settings) {
+                Ok(expanded) => Some(expanded),
+                Err(e) => {
+                    *error = Some(e);
+                    Some(String::new())
+                }
+            },
+        };
+        let value = filters
+            .iter()
+            .fold(value, |value, filter| apply_filter(value, filter, inner));
+        match value {
+            Some(value) => value,
+            None => {
+                if !unknown.iter().any(|key| key == inner) {
+                    unknown.push(inner.into());
+                }
+                inner.into()
+            }
+        }
+    }
+}
+
+/// Split the filters, separated by `|`, from the key of a substitution.
+///
+/// Filters are taken from the end, as long as their name only consists of
+/// alphanumeric characters and `_`, so commands like `$(ls | sort)` stay intact.
+/// The key is always trimmed, i.e. `{{ NAME }}` and `{{ NAME | upper }}` use `NAME`.
+fn split_filters(inner: &str) -> (&str, Vec<&str>) {
+    let mut key = inner;
+    let mut filters = vec![];
+    while let Some((rest, filter)) = key.rsplit_once('|') {
+        let filter = filter.trim();
+        let name = filter.split(':').next().unwrap_or_default();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            break;
+        }
+        filters.push(filter);
+        key = rest;
+    }
+    filters.reverse();
+    (key.trim(), filters)
+}
+
+/// Apply a single filter, like `upper` or `default:World`, to the `value` of `key`.
+///
+/// `None` stands for an unknown key, only `default` replaces it (or an empty value).
+/// Unknown filters are warned about and leave the value unchanged.
+fn apply_filter(value: Option<String>, filter: &str, key: &str) -> Option<String> {
+    let (name, arg) = match filter.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (filter, None),
+    };
+    match (name, value) {
+        ("default", Some(value)) if !value.is_empty() => Some(value),
+        ("default", _) => Some(arg.unwrap_or_default().into()),
+        ("upper", value) => value.map(|value| value.to_uppercase()),
+        ("lower", value) => value.map(|value| value.to_lowercase()),
+        ("trim", value) => value.map(|value| value.trim().into()),
+        (_, value) => {
+            warn!("Unknown filter {:?} for substitution {:?}", name, key);
+            value
+        }
+    }
+}
+
+/// Resolve substitutions used in the values of other substitutions.
+///
+/// Values are resolved up to `max_depth - 1` levels deep, a `max_depth` of `1` leaves them
+/// untouched. Unknown keys in values are kept as they are.
+///
+/// # Errors
+/// Fails with [`Error::SubstitutionCycle`], if a value references itself before the
+/// maximum depth is reached.
+fn resolve_substitutions<'a>(
+    substitutions: &'a Substitutions,
+    regex: &Regex,
+    max_depth: usize,
+) -> Result<Cow<'a, Substitutions>> {
+    if max_depth <= 1 {
+        return Ok(Cow::Borrowed(substitutions));
+    }
+    let mut resolved = Substitutions::new();
+    for key in substitutions.keys() {
+        let value = resolve_key(key, substitutions, regex, max_depth, &mut vec![])?;
+        resolved.insert(key.clone(), value);
+    }
+    Ok(Cow::Owned(resolved))
+}
+
+/// Resolve the value of `key`, see [`resolve_substitutions`].
+///
+/// `stack` contains the keys currently being resolved.
+fn resolve_key<'a>(
+    key: &'a str,
+    substitutions: &'a Substitutions,
+    regex: &Regex,
+    depth: usize,
+    stack: &mut Vec<&'a str>,
+) -> Result<String> {
+    if let Some(start) = stack.iter().position(|other| *other == key) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(key);
+        return Err(Error::SubstitutionCycle(cycle.join(" -> ")));
+    }
+    let value = &substitutions[key];
+    if depth <= 1 {
+        return Ok(value.clone());
+    }
+    stack.push(key);
+    let mut error = None;
+    let resolved = regex
+        .replace_all(value, |captures: &Captures| {
+            if !captures[1].is_empty() || error.is_some() {
+                return captures[0].to_string();
+            }
+            match substitutions.get_key_value(&captures[2]) {
+                Some((inner, _)) => resolve_key(inner, substitutions, regex, depth - 1, stack)
+                    .unwrap_or_else(|e| {
+                        error = Some(e);
+                        String::new()
+                    }),
+                None => captures[0].to_string(),
+            }
+        })
+        .into_owned();
+    stack.pop();
+    match error {
+        Some(e) => Err(e),
+        None => Ok(resolved),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn file_config(prefix: Option<&str>) -> FileConfig {
+        FileConfig {
+            name: None,
+            enabled: true,
+            source: "source".into(),
+            target: "target".into(),
+            escape: None,
+            prefix: prefix.map(Prefix::from),
+            comment_char: None,
+            remove_instructions: Some(true),
+            blank_instead_of_remove: false,
+            collapse_empty: false,
+            max_substitution_depth: None,
+            link_type: LinkType::Symlink,
+            copy_fallback: false,
+            relative_links: false,
+            keep_temp: true,
+            create_parents: true,
+            substitution_sources: None,
+            substitutions: Substitutions::new(),
+            allow_non_utf8: false,
+            pre_process: None,
+            post_link: None,
+            output_dir: None,
+            command_settings: CommandSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let content = "# dope: prefix=\"#~\" escape=\"{{ }}\"\n#~ IFNDEF $HOME\nhidden\n#~ ENDIF\nNAME={{NAME}}";
+        let fc = file_config(None);
+        let (config, content) = fc.apply_header(content).unwrap();
+        assert_eq!(config.prefix, Some("#~".into()));
+        let opt = Opt::from_iter(&["dope"]);
+        let (content, _) = config
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("NAME".into(), "dope".into());
+        let (content, _) = config
+            .preprocess_substitutions(&content, &substitutions, &opt)
+            .unwrap();
+        assert_eq!(content, "NAME=dope");
+    }
+
+    #[test]
+    fn test_substitution_sources() {
+        let opt = Opt::from_iter(&["dope", "--set", "NAME=cli", "--set", "CLI=yes"]);
+        let mut global = Substitutions::new();
+        global.insert("NAME".into(), "global".into());
+        global.insert("GLOBAL".into(), "yes".into());
+        // Honors all sources
+        let fc = file_config(None);
+        let substitutions = fc.substitutions(&global, &opt);
+        assert_eq!(substitutions["NAME"], "cli");
+        assert_eq!(substitutions["CLI"], "yes");
+        assert_eq!(substitutions["GLOBAL"], "yes");
+        // Opts out of CLI overrides
+        let mut fc = file_config(None);
+        fc.substitution_sources = Some(vec![SubstitutionSource::Global]);
+        let substitutions = fc.substitutions(&global, &opt);
+        assert_eq!(substitutions["NAME"], "global");
+        assert!(!substitutions.contains_key("CLI"));
+        // Substitutions of the file win over global ones
+        fc.substitutions.insert("NAME".into(), "file".into());
+        let substitutions = fc.substitutions(&global, &opt);
+        assert_eq!(substitutions["NAME"], "file");
+        assert_eq!(substitutions["GLOBAL"], "yes");
+    }
+
+    #[test]
+    fn test_header_precedence() {
+        let content = "// dope: prefix=\"//~\"\n//~ # comment\n#~ # comment";
+        let fc = file_config(Some("#~"));
+        let (config, content) = fc.apply_header(content).unwrap();
+        assert_eq!(config.prefix, Some("#~".into()));
+        assert_eq!(content, "//~ # comment\n#~ # comment");
+        // Not a header
+        let content = "# dope is great\nsecond line";
+        let (_, rest) = fc.apply_header(content).unwrap();
+        assert_eq!(rest, content);
+        // Invalid header values are rejected like invalid options
+        let fc = file_config(None);
+        match fc.apply_header("# dope: prefix=\"\"\nline") {
+            Err(Error::EmptyPrefix(_)) => {}
+            other => panic!("Expected an empty prefix error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list_instructions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fc = file_config(None);
+        fc.source = dir.path().join("source").into();
+        let content = "# dope: prefix=\"#~\"\n#~ IFDEF $X\nx\n#~ ELSE\n#~ ASK Q?\n#~ OPTION a\n#~ ENDASK\n#~ ENDIF\n";
+        fs::write(dir.path().join("source"), content).unwrap();
+        assert_eq!(
+            fc.list_instructions(dir.path()).unwrap(),
+            vec![
+                "2: IFDEF $X",
+                "4: ELSE",
+                "5: ASK Q?",
+                "6: OPTION a",
+                "7: ENDASK",
+                "8: ENDIF",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a"), "name = {{NA").unwrap();
+        fs::write(root.join("b"), "ME}}\n").unwrap();
+        fs::write(root.join("c"), "end\n").unwrap();
+        let toml = "source = [\"a\", \"b\", \"c\"]\ntarget = \"target\"";
+        let parsed: FileConfig = toml::from_str(toml).unwrap();
+        let mut fc = file_config(None);
+        let fragments = vec!["a".into(), "b".into(), "c".into()];
+        assert_eq!(parsed.source, Source::Fragments(fragments));
+        fc.source = parsed.source;
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("NAME".into(), "dope".into());
+        fc.preprocess(root, &substitutions, &opt).unwrap();
+        // The preprocessed file is named after the first fragment
+        let content = fs::read_to_string(root.join("a.preprocessed")).unwrap();
+        assert_eq!(content, "name = dope\nend\n");
+        // Missing fragments are reported with their path
+        fs::remove_file(root.join("b")).unwrap();
+        match fc.source(root) {
+            Err(Error::FailedToReadSourceFile(path, _)) => assert!(path.ends_with("b")),
+            other => panic!("Expected a missing fragment, got {:?}", other),
+        }
+        fc.source = Source::Fragments(vec![]);
+        assert!(fc.validate().is_err());
+    }
+
+    #[test]
+    fn test_non_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("preprocessor.toml");
+        let opt = Opt::from_iter(&["dope", "-c", config_path.to_str().unwrap()]);
+        let mut fc = file_config(Some("#~"));
+        fc.source = dir.path().join("source").into();
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        fs::write(
+            dir.path().join("source"),
+            b"a {{NAME}}\n\xff\n#~ IF a == a\nb {{NAME}}\n#~ ENDIF\n",
+        )
+        .unwrap();
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("NAME".into(), "dope".into());
+        match fc.preprocess(dir.path(), &substitutions, &opt) {
+            Err(Error::FailedToReadSourceFile(_, _)) => {}
+            other => panic!("Expected invalid UTF-8 to fail, got {:?}", other),
+        }
+        fc.allow_non_utf8 = true;
+        fc.preprocess(dir.path(), &substitutions, &opt).unwrap();
+        let output = fs::read(fc.temp_path(dir.path())).unwrap();
+        assert_eq!(output, b"a dope\n\xff\nb dope\n");
+    }
+
+    #[test]
+    fn test_substitution_at_start() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("KEY".into(), "value".into());
+        let content = "{++KEY++} and {++KEY++}\n{++KEY++} but not \\{++KEY++}";
+        let (output, count) = fc
+            .preprocess_substitutions(content, &substitutions, &opt)
+            .unwrap();
+        assert_eq!(output, "value and value\nvalue but not {++KEY++}");
+        // Escaped keys are not counted
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_escaped_substitution() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("KEY".into(), "{++value++}".into());
+        let content = "\\{++NOT_A_KEY++} {++KEY++} \\{++KEY++}";
+        let (output, count) = fc
+            .preprocess_substitutions(content, &substitutions, &opt)
+            .unwrap();
+        // The delimiter is written literally, values are not de-escaped
+        assert_eq!(output, "{++NOT_A_KEY++} {++value++} {++KEY++}");
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_substitution_filters() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("PATH".into(), " /usr/bin ".into());
+        substitutions.insert("EMPTY".into(), "".into());
+        let content = "{++ PATH | upper ++}\n{++PATH|trim|upper++}\n{++ NAME | default:World ++}\n\
+                       {++EMPTY|default:none|upper++}\n{++PATH | reverse | trim++}\n{++$(echo a | tr a b)++}";
+        let (output, count) = fc
+            .preprocess_substitutions(content, &substitutions, &opt)
+            .unwrap();
+        // Unknown filters pass the value through, commands keep their pipes
+        assert_eq!(output, " /USR/BIN \n/USR/BIN\nWorld\nNONE\n/usr/bin\nb");
+        assert_eq!(count, 6);
+        // The key is trimmed with and without filters
+        substitutions.insert("NAME".into(), "dope".into());
+        let content = "{++ NAME ++} {++ NAME | upper ++} {++NAME++}";
+        let (output, _) = fc
+            .preprocess_substitutions(content, &substitutions, &opt)
+            .unwrap();
+        assert_eq!(output, "dope DOPE dope");
+        substitutions.remove("NAME");
+        // Without a default, unknown keys stay unknown
+        let opt = Opt::from_iter(&["dope", "--strict"]);
+        let content = "{++ NAME | lower ++}";
+        match fc.preprocess_substitutions(content, &substitutions, &opt) {
+            Err(Error::UnknownSubstitution(key)) => assert_eq!(key, "NAME"),
+            other => panic!("Expected an unknown substitution, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_recursive_substitution() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("FIRST".into(), "Max".into());
+        substitutions.insert("LAST".into(), "Mustermann".into());
+        substitutions.insert("FULL".into(), "{++FIRST++} {++LAST++}".into());
+        substitutions.insert("GREETING".into(), "Hello {++FULL++}".into());
+        let content = "{++GREETING++}";
+        let substitute = |fc: &FileConfig| {
+            fc.preprocess_substitutions(content, &substitutions, &opt)
+                .map(|(output, _)| output)
+        };
+        // Single pass by default
+        assert_eq!(substitute(&fc).unwrap(), "Hello {++FULL++}");
+        fc.max_substitution_depth = Some(2);
+        assert_eq!(substitute(&fc).unwrap(), "Hello {++FIRST++} {++LAST++}");
+        fc.max_substitution_depth = Some(3);
+        assert_eq!(substitute(&fc).unwrap(), "Hello Max Mustermann");
+        // Cycles are detected
+        substitutions.insert("FIRST".into(), "{++GREETING++}".into());
+        let substitute =
+            |fc: &FileConfig| fc.preprocess_substitutions(content, &substitutions, &opt);
+        fc.max_substitution_depth = Some(10);
+        match substitute(&fc) {
+            Err(Error::SubstitutionCycle(_)) => {}
+            other => panic!("Expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_substitution() {
+        let lenient = Opt::from_iter(&["dope"]);
+        let strict = Opt::from_iter(&["dope", "--strict"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("KNOWN".into(), "known".into());
+        let content = "{++KNOWN++} {++TYPO++} {++TYPO++}";
+        let output = fc.preprocess_substitutions(content, &substitutions, &lenient);
+        assert_eq!(output.unwrap().0, "known TYPO TYPO");
+        match fc.preprocess_substitutions(content, &substitutions, &strict) {
+            Err(Error::UnknownSubstitution(key)) => assert_eq!(key, "TYPO"),
+            other => panic!("Expected an unknown substitution, got {:?}", other),
+        }
+        // Variables are fine
+        std::env::set_var("DOPE_TEST_UNKNOWN", "var");
+        let content = "{++$DOPE_TEST_UNKNOWN++}";
+        let output = fc.preprocess_substitutions(content, &substitutions, &strict);
+        assert_eq!(output.unwrap().0, "var");
+    }
+
+    #[test]
+    fn test_substitution_containing_escapes() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{++".into(),
+            end: "++}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("A".into(), "{++B++}".into());
+        substitutions.insert("B".into(), "b } ++}".into());
+        let content = "{++A++}{++B++} {++B++}}";
+        let (output, _) = fc
+            .preprocess_substitutions(content, &substitutions, &opt)
+            .unwrap();
+        // Replacements are inserted literally
+        assert_eq!(output, "{++B++}b } ++} b } ++}}");
+    }
+
+    #[test]
+    fn test_preprocess_content() {
+        let opt = Opt::from_iter(&["dope", "--non-interactive"]);
+        let mut fc = file_config(Some("#~"));
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("COLOR".into(), "red".into());
+        substitutions.insert("SIZE".into(), "12".into());
+        let content = "\
+#~ # The color depends on the answer
+#~ ASK:COLOR [blue] Which color?
+#~ IF a == b
+color = green
+#~ ELSE
+color = {{COLOR}}
+#~ ENDIF
+#~ ECHO size = {{SIZE}}";
+        let output = fc.preprocess_content(content, &substitutions, &opt);
+        // Answers win over configured substitutions
+        assert_eq!(output.unwrap(), "color = blue\nsize = 12");
+        // The header is honored
+        let content = "# dope: prefix=\"//\" escape=\"< >\"\n// IF a == a\n<SIZE>\n// ENDIF";
+        let output = file_config(None).preprocess_content(content, &substitutions, &opt);
+        assert_eq!(output.unwrap(), "12");
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("NAME".into(), "dope".into());
+        let mut fc = file_config(Some("#~"));
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        let preprocess = |fc: &FileConfig, content: &str| {
+            fc.preprocess_content(content, &substitutions, &opt)
+                .unwrap()
+        };
+        let content = "#~ IF a == a\nname = {{NAME}}\n#~ ENDIF";
+        assert_eq!(preprocess(&fc, content), "name = dope");
+        assert_eq!(preprocess(&fc, &format!("{}\n", content)), "name = dope\n");
+        // Without instructions, only substitutions are inserted
+        fc.prefix = None;
+        assert_eq!(preprocess(&fc, "name = {{NAME}}"), "name = dope");
+        assert_eq!(preprocess(&fc, "name = {{NAME}}\n"), "name = dope\n");
+    }
+
+    #[test]
+    fn test_blank_instead_of_remove() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(None);
+        fc.blank_instead_of_remove = true;
+        let content = "# dope: prefix=\"#~\"\n#~ IF a == b\nskipped\n#~ ELSE\nkept\n#~ ENDIF\n#~ ECHO echoed\nend";
+        let output = fc
+            .preprocess_content(content, &Substitutions::new(), &opt)
+            .unwrap();
+        assert_eq!(output, "\n\n\n\nkept\n\nechoed\nend");
+        assert_eq!(output.lines().count(), content.lines().count());
+    }
+
+    #[test]
+    fn test_collapse_empty() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("EMPTY".into(), "".into());
+        substitutions.insert("NAME".into(), "dope".into());
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        let content = "a\n{{EMPTY}}\n\n  {{EMPTY}} \nname = {{NAME}}{{EMPTY}}\n{{EMPTY}}";
+        let kept = fc
+            .preprocess_content(content, &substitutions, &opt)
+            .unwrap();
+        assert_eq!(kept, "a\n\n\n   \nname = dope\n");
+        fc.collapse_empty = true;
+        // The blank line of the source is kept
+        let collapsed = fc
+            .preprocess_content(content, &substitutions, &opt)
+            .unwrap();
+        assert_eq!(collapsed, "a\n\nname = dope\n");
+    }
+
+    #[test]
+    fn test_pass_control() {
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("SIZE".into(), "12".into());
+        let content = "# dope: prefix=\"//\"\n// IF a == b\n<SIZE>\n// ENDIF\n<SIZE>";
+        let mut fc = file_config(None);
+        fc.escape = Some(Escape {
+            start: "<".into(),
+            end: ">".into(),
+        });
+        let preprocess = |args: &[&str]| {
+            let opt = Opt::from_iter(args);
+            fc.preprocess_content(content, &substitutions, &opt)
+                .unwrap()
+        };
+        assert_eq!(preprocess(&["dope"]), "12");
+        assert_eq!(preprocess(&["dope", "--no-substitute"]), "<SIZE>");
+        assert_eq!(
+            preprocess(&["dope", "--no-instructions"]),
+            "# dope: prefix=\"//\"\n// IF a == b\n12\n// ENDIF\n12"
+        );
+        let args = &["dope", "--no-instructions", "--no-substitute"];
+        assert_eq!(preprocess(args), content);
+    }
+
+    #[test]
+    fn test_keep_temp() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("source"), "content").unwrap();
+        let mut fc = file_config(None);
+        fc.keep_temp = false;
+        // Links need the preprocessed file, even if it should not be kept
+        for link_type in [LinkType::Symlink, LinkType::Hardlink] {
+            fc.link_type = link_type;
+            fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+                .unwrap();
+            fc.create_link(dir.path(), &opt).unwrap();
+            assert!(fc.temp_path(dir.path()).exists());
+            let output = fs::read_to_string(fc.target_path(dir.path())).unwrap();
+            assert_eq!(output, "content");
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_source_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "#!/bin/sh").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o555)).unwrap();
+        let fc = file_config(None);
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        // The executable bit survives the symlink, the owner can still write
+        let mode = fs::metadata(fc.target_path(dir.path()))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        // Preprocessing again replaces the file
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_parents() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("source"), "content").unwrap();
+        let mut fc = file_config(None);
+        fc.target = "a/b/target".into();
+        fc.output_dir = Some("build".into());
+        fc.create_parents = false;
+        let result = fc.preprocess(dir.path(), &Substitutions::new(), &opt);
+        assert!(matches!(result, Err(Error::FailedToOpenTempFile(..))));
+        fc.create_parents = true;
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        let output = fs::read_to_string(dir.path().join("a/b/target")).unwrap();
+        assert_eq!(output, "content");
+        // A file in the way is reported as such
+        fc.target = "source/target".into();
+        let result = fc.create_link(dir.path(), &opt);
+        assert!(matches!(result, Err(Error::FailedToCreateDirectory(..))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_remove_link() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let target_path = dir.path().join("target");
+        fs::write(dir.path().join("source"), "content").unwrap();
+        fs::write(dir.path().join("target.bak"), "backup").unwrap();
+        let fc = file_config(None);
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        fc.remove_link(dir.path(), true, &opt).unwrap();
+        assert_eq!(fs::read_to_string(&target_path).unwrap(), "backup");
+        assert!(!fc.temp_path(dir.path()).exists());
+        // The restored backup is not ours
+        let result = fc.remove_link(dir.path(), true, &opt);
+        assert!(matches!(result, Err(Error::ForeignTarget(_))));
+        assert!(target_path.exists());
+        // Neither are links to other files
+        fs::remove_file(&target_path).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("source"), &target_path).unwrap();
+        let result = fc.remove_link(dir.path(), false, &opt);
+        assert!(matches!(result, Err(Error::ForeignTarget(_))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_link_idempotent() {
+        use std::os::unix::fs::MetadataExt;
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("source"), "content").unwrap();
+        let mut fc = file_config(None);
+        for link_type in [LinkType::Symlink, LinkType::Hardlink] {
+            fc.link_type = link_type;
+            let target_path = fc.target_path(dir.path());
+            let _ = fs::remove_file(&target_path);
+            fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+                .unwrap();
+            fc.create_link(dir.path(), &opt).unwrap();
+            let before = fs::symlink_metadata(&target_path).unwrap();
+            fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+                .unwrap();
+            fc.create_link(dir.path(), &opt).unwrap();
+            let after = fs::symlink_metadata(&target_path).unwrap();
+            assert_eq!(before.ino(), after.ino());
+            // The content of a hard link is rewritten by preprocessing
+            if link_type == LinkType::Symlink {
+                assert_eq!(before.modified().unwrap(), after.modified().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_source_target_collision() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let source_path = dir.path().join("source");
+        fs::write(&source_path, "content").unwrap();
+        let mut fc = FileConfig::new("source", "./source");
+        fc.supplement(&None, true, &None, &None, None, &None);
+        let result = fc.preprocess(dir.path(), &Substitutions::new(), &opt);
+        assert!(matches!(result, Err(Error::SourceTargetCollision(_, _))));
+        assert!(!fc.temp_path(dir.path()).exists());
+        let result = fc.create_link(dir.path(), &opt);
+        assert!(matches!(result, Err(Error::SourceTargetCollision(_, _))));
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "content");
+        // Linking the preprocessed file onto itself fails as well
+        let fc = FileConfig::new("source", "source.preprocessed");
+        let result = fc.create_link(dir.path(), &opt);
+        assert!(matches!(result, Err(Error::SourceTargetCollision(_, _))));
+    }
+
+    #[test]
+    fn test_relative_links() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/source"), "content").unwrap();
+        let mut fc = FileConfig::new("src/source", "dst/target");
+        fc.supplement(&None, true, &None, &None, None, &None);
+        fc.relative_links = true;
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        let target_path = fc.target_path(dir.path());
+        let link = fs::read_link(&target_path).unwrap();
+        assert_eq!(link, PathBuf::from("../src/source.preprocessed"));
+        assert_eq!(fs::read_to_string(&target_path).unwrap(), "content");
+        // The link is recognized as ours
+        fc.remove_link(dir.path(), false, &opt).unwrap();
+        assert!(fs::symlink_metadata(&target_path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hooks() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        fs::write(dir.path().join("source"), "content").unwrap();
+        let mut fc = FileConfig::new("source", "target");
+        fc.supplement(&None, true, &None, &None, None, &None);
+        fc.pre_process = Some(format!("echo pre >> '{}'", log.display()));
+        fc.post_link = Some(format!("echo post >> '{}'", log.display()));
+        // Nothing is run in a dry run
+        let dry_run = Opt::from_iter(&["dope", "--dry-run"]);
+        fc.preprocess(dir.path(), &Substitutions::new(), &dry_run)
+            .unwrap();
+        assert!(!log.exists());
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "pre\npost\n");
+        // Failing hooks are only an error with --strict
+        fc.post_link = Some("exit 3".into());
+        fc.create_link(dir.path(), &opt).unwrap();
+        let strict = Opt::from_iter(&["dope", "--strict"]);
+        match fc.create_link(dir.path(), &strict) {
+            Err(Error::HookFailed(command, _)) => assert_eq!(command, "exit 3"),
+            other => panic!("Expected a failed hook, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_target_dir() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("dst")).unwrap();
+        fs::write(dir.path().join("source"), "content").unwrap();
+        let mut fc = FileConfig::new("source", "dst");
+        fc.supplement(&None, true, &None, &None, None, &None);
+        assert_eq!(fc.target_path(dir.path()), dir.path().join("dst/source"));
+        fc.preprocess(dir.path(), &Substitutions::new(), &opt)
+            .unwrap();
+        fc.create_link(dir.path(), &opt).unwrap();
+        let content = fs::read_to_string(dir.path().join("dst/source")).unwrap();
+        assert_eq!(content, "content");
+        fc.remove_link(dir.path(), false, &opt).unwrap();
+        assert!(dir.path().join("dst").is_dir());
+        assert!(fs::symlink_metadata(dir.path().join("dst/source")).is_err());
+    }
+
+    #[test]
+    fn test_display_name() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let opt_relative = Opt::from_iter(&["dope", "--relative-to", root.to_str().unwrap()]);
+        let mut fc = file_config(None);
+        assert_eq!(fc.display_name(&root, &opt_relative), "\"source\"");
+        let absolute = format!("{:?}", root.join("source"));
+        assert_eq!(fc.display_name(&root, &opt), absolute);
+        fc.name = Some("awesome".into());
+        assert_eq!(fc.display_name(&root, &opt), "awesome");
+        assert_eq!(fc.name(), Some("awesome"));
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/source"), "content").unwrap();
+        let mut fc = file_config(None);
+        fc.source = "sub/source".into();
+        fc.output_dir = Some("build".into());
+        // The directory tree is mirrored below the output directory
+        let temp_path = root.join("build/sub/source.preprocessed");
+        assert_eq!(fc.temp_path(&root), temp_path);
+        fc.preprocess(&root, &Substitutions::new(), &opt).unwrap();
+        fc.create_link(&root, &opt).unwrap();
+        assert_eq!(fs::read_link(fc.target_path(&root)).unwrap(), temp_path);
+        assert!(!root.join("sub/source.preprocessed").exists());
+        // Sources outside of the root keep their absolute path
+        let other = tempfile::tempdir().unwrap();
+        let other = other.path().canonicalize().unwrap();
+        fc.source = other.join("source").into();
+        let mirrored = root.join("build").join(mirror_path(&other));
+        assert_eq!(fc.temp_path(&root), mirrored.join("source.preprocessed"));
+    }
+
+    #[test]
+    fn test_link_without_preprocess() {
+        let opt = Opt::from_iter(&["dope"]);
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("source"), "content").unwrap();
+        let mut fc = file_config(None);
+        fc.output_dir = Some("build".into());
+        // A preprocessed file next to the source is not the one to link
+        fs::write(root.join("source.preprocessed"), "stale").unwrap();
+        match fc.create_link(&root, &opt) {
+            Err(Error::MissingPreprocessedFile(path)) => {
+                assert!(path.ends_with("build/source.preprocessed"))
+            }
+            other => panic!("Expected a missing preprocessed file, got {:?}", other),
+        }
+        assert!(fs::symlink_metadata(fc.target_path(&root)).is_err());
+        fc.preprocess(&root, &Substitutions::new(), &opt).unwrap();
+        fc.create_link(&root, &opt).unwrap();
+        let content = fs::read_to_string(fc.target_path(&root)).unwrap();
+        assert_eq!(content, "content");
+    }
+
+    #[test]
+    fn test_report_all_instruction_errors() {
+        let content = "#~ IF a == a\n#~ BOGUS\nline\n#~ ENDIF\n#~ ALSO BOGUS";
+        let fc = file_config(Some("#~"));
+        let opt = Opt::from_iter(&["dope"]);
+        match fc.preprocess_instructions(content, Path::new("."), 1, &opt) {
+            Err(Error::Multiple(errors)) => {
+                let errors: Vec<_> = errors.0.iter().map(Error::to_string).collect();
+                assert_eq!(errors.len(), 2);
+                assert!(errors[0].contains("BOGUS"));
+                assert!(errors[1].contains("ALSO BOGUS"));
+            }
+            other => panic!("Expected multiple errors, got {:?}", other),
+        }
+        // Stop at the first error with `--panic`
+        let opt = Opt::from_iter(&["dope", "--panic"]);
+        match fc.preprocess_instructions(content, Path::new("."), 1, &opt) {
+            Err(Error::UnrecognizedPreprocessorInstruction(line)) => assert_eq!(line, "BOGUS"),
+            other => panic!("Expected the first error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_escaped_instructions() {
+        let opt = Opt::from_iter(&["dope"]);
+        let fc = file_config(Some("#~"));
+        let content = "\\#~ IFDEF X\n#~ IFDEF $DOPE_TEST_UNDEFINED\nhidden\n#~ ENDIF\n\\\\#~ ENDIF";
+        let (output, _) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, "#~ IFDEF X\n\\#~ ENDIF");
+        // Without a prefix, nothing is an escape
+        let fc = file_config(None);
+        let (output, _) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_prefix_candidates() {
+        let opt = Opt::from_iter(&["dope"]);
+        let content =
+            "#~ IFDEF $DOPE_TEST_UNDEFINED\nhidden\n//~ ENDIF\n--~ # comment\n\\//~ ENDIF";
+        let mut fc: FileConfig =
+            toml::from_str("source = \"s\"\ntarget = \"t\"\nprefix = [\"#~\", \"//~\"]").unwrap();
+        fc.remove_instructions = Some(true);
+        let (output, _) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, "--~ # comment\n//~ ENDIF");
+        let mut fc: FileConfig =
+            toml::from_str("source = \"s\"\ntarget = \"t\"\nprefix = { regex = \"(#|//|--)~\" }")
+                .unwrap();
+        fc.remove_instructions = Some(true);
+        fc.validate().unwrap();
+        let (output, _) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, "//~ ENDIF");
+        // Invalid regular expressions fail on load
+        fc.prefix = Some(Prefix::Regex { regex: "(".into() });
+        assert!(fc.validate().is_err());
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "# dope: prefix=\"#~\" escape=\"{{ }}\"\n#~ IF $(hostname) == ${HOST}\nname = {{NAME}}\n#~ ENDIF\n#~ IFSET EDITOR\n#~ ENDIF\nhome = {{$HOME}} \\{{ESCAPED}}";
+        fs::write(dir.path().join("source"), content).unwrap();
+        let dependencies = file_config(None).dependencies(dir.path()).unwrap();
+        assert_eq!(
+            dependencies.lines(),
+            vec![
+                "env EDITOR",
+                "env HOME",
+                "env HOST",
+                "command hostname",
+                "substitution NAME"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(Some("#~"));
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        let content = "#~ FOR host IN a, b\nHost {{host}} \\{{host}}\n#~ FOR port IN 1,2\n  Port {{host}}{{port}}\n#~ ENDFOR\n  User {{USER}}\n#~ ENDFOR\nend";
+        let (output, evaluation) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(
+            output,
+            "Host a \\{{host}}\n  Port a1\n  Port a2\n  User {{USER}}\nHost b \\{{host}}\n  Port b1\n  Port b2\n  User {{USER}}\nend"
+        );
+        // Only the instructions are skipped
+        let mut skips: Vec<_> = evaluation.skips.into_iter().collect();
+        skips.sort_unstable();
+        assert_eq!(skips, vec![1, 3, 5, 7]);
+        // Without items, the block is removed
+        let content = "#~ FOR host IN $DOPE_TEST_UNDEFINED\nHost {{host}}\n#~ ENDFOR\nend";
+        let (output, evaluation) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, "end");
+        assert!(evaluation.skips.contains(&2));
+    }
+
+    #[test]
+    fn test_for_loop_filters() {
+        let opt = Opt::from_iter(&["dope"]);
+        let mut fc = file_config(Some("#~"));
+        fc.escape = Some(Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        });
+        // Whitespace and filters work for items like for substitutions
+        let content =
+            "#~ FOR h IN x, y\nh={{h}} {{ h }} {{h|upper}} {{ h | default:z }}\n#~ ENDFOR";
+        let (output, _) = fc
+            .preprocess_instructions(content, Path::new("."), 1, &opt)
+            .unwrap();
+        assert_eq!(output, "h=x x X x\nh=y y Y y");
+    }
+
+    #[test]
+    fn test_error_line_numbers() {
+        let opt = Opt::from_iter(&["dope"]);
+        let fc = file_config(Some("#~"));
+        let substitutions = Substitutions::new();
+        // Lines are counted like in an editor, including the header
+        let content = "# dope: escape=\"{ }\"\nline\n#~ IF a == b\nskipped\n#~ ENDIF\n#~ ENDIF";
+        match fc.preprocess_content(content, &substitutions, &opt) {
+            Err(Error::StrayCmdFound(6, cmd)) => assert_eq!(cmd, "ENDIF"),
+            other => panic!("Expected a stray ENDIF in line 6, got {:?}", other),
+        }
+        let content = "line\n#~ IFDEF x\nline";
+        match fc.preprocess_content(content, &substitutions, &opt) {
+            Err(Error::MissingEndingInstruction(2, _, "ENDIF")) => {}
+            other => panic!("Expected a missing ENDIF for line 2, got {:?}", other),
         }
+        // The right lines are skipped
+        let content = "# dope: escape=\"{ }\"\na\n#~ IF a == b\nb\n#~ ELSE\nc\n#~ ENDIF\nd";
+        let output = fc.preprocess_content(content, &substitutions, &opt);
+        assert_eq!(output.unwrap(), "a\nc\nd");
     }
 }
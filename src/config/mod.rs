@@ -2,35 +2,59 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 mod file_config;
 
+use crate::command_reader::prompt_selection;
+use crate::env::{self, CommandSettings};
 use crate::error::{Error, Result};
+use crate::helper::{canonicalize_parent, display_path};
+use crate::logging::report;
+use crate::report::FileReport;
 use crate::Opt;
-use file_config::{Escape, FileConfig};
+pub use file_config::{
+    Dependencies, Escape, FileConfig, LinkBackup, LinkType, Prefix, Source, SubstitutionSource,
+};
 
 pub type Substitutions = HashMap<String, String>;
 
+/// Values of substitutions starting with this are read from the environment.
+const ENV_VALUE_PREFIX: &str = "$ENV:";
+
 /// The complete, normalized configuration file.
 ///
 /// All unset options of file_configurations have been filled with default options,
 /// if those were defined.
 #[derive(Debug)]
 pub struct Config {
-    /// The list of files to process.
-    pub file_configurations: Vec<FileConfig>,
+    /// The list of files to process, each with the directory its relative paths are
+    /// resolved against. This is the directory of its configuration file, if loaded from one.
+    pub file_configurations: Vec<(PathBuf, FileConfig)>,
     /// The list of global substitutions.
     pub substitutions: Substitutions,
+    /// Maximum duration of `$(...)` commands, if any.
+    pub command_timeout: Option<Duration>,
+    /// The shell running `$(...)` commands, the default is used if unset.
+    pub shell: Option<Shell>,
+    /// Whether unset environment variables are an error, see `--strict-env`.
+    pub strict_env: bool,
 }
 
 /// The raw, loaded TOML configuration file.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RawConfig {
     /// Default escape surrounding substitutions.
     default_escape: Option<Escape>,
     /// Default line prefix for commands.
-    default_prefix: Option<String>,
+    default_prefix: Option<Prefix>,
+    /// Default string starting a comment after the prefix.
+    default_comment_char: Option<String>,
     /// Default value for removing commands. If true, commands
     /// will be cut from the output file. Defaults to true.
     #[serde(default = "default_true")]
@@ -41,61 +65,981 @@ pub struct RawConfig {
     /// The list of global substitutions.
     #[serde(default)]
     substitutions: Option<Substitutions>,
+    /// A TOML file with more substitutions, relative to the configuration file.
+    /// Its values win over the `substitutions` table.
+    substitutions_file: Option<PathBuf>,
+    /// How deep substitutions in values of substitutions are resolved.
+    /// Defaults to 1, values are inserted as they are.
+    max_substitution_depth: Option<usize>,
+    /// Seconds after which `$(...)` commands are killed.
+    /// Defaults to no timeout.
+    command_timeout_secs: Option<u64>,
+    /// The shell running `$(...)` commands.
+    /// Defaults to `sh -c` (`cmd /C` on Windows).
+    shell: Option<Shell>,
+    /// Directory for the preprocessed files, relative to the configuration file.
+    /// Defaults to writing them next to their sources.
+    output_dir: Option<PathBuf>,
+}
+
+/// The shell running `$(...)` commands.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Shell {
+    /// A program, that is called with `-c` (`/C` on Windows) and the command.
+    Program(String),
+    /// A program followed by its arguments, the command is appended.
+    Invocation(Vec<String>),
+}
+
+impl Shell {
+    /// The program followed by its arguments, without the command.
+    pub fn invocation(&self) -> Vec<String> {
+        match self {
+            Shell::Program(program) if cfg!(windows) => vec![program.clone(), "/C".into()],
+            Shell::Program(program) => vec![program.clone(), "-c".into()],
+            Shell::Invocation(invocation) => invocation.clone(),
+        }
+    }
 }
 
 impl RawConfig {
     /// Load a raw configuration from the given path.
-    fn load<P: AsRef<Path>>(config_path: P) -> Result<Self> {
+    ///
+    /// Returns the configuration and the keys, that are not understood, see [`unknown_keys`].
+    fn load<P: AsRef<Path>>(config_path: P) -> Result<(Self, Vec<String>)> {
         let path = config_path.as_ref();
         let content = fs::read_to_string(path).map_err(Error::as_load_config)?;
-        toml::from_str(&content).map_err(Error::FailedToParseConfiguration)
+        let value: toml::Value =
+            toml::from_str(&content).map_err(Error::FailedToParseConfiguration)?;
+        let raw: Self = value
+            .clone()
+            .try_into()
+            .map_err(Error::FailedToParseConfiguration)?;
+        let known = toml::Value::try_from(&raw).map_err(Error::FailedToCheckConfiguration)?;
+        Ok((raw, unknown_keys(&value, &known)))
     }
 }
 
 impl Config {
     /// Load the configuration from the given path.
+    ///
+    /// The substitutions of the `substitutions_file` are added to the `substitutions`
+    /// table, afterwards values like `$ENV:NAME` are replaced by the variable `NAME`.
+    ///
+    /// # Errors
+    /// Fails, if the file cannot be read or parsed, or contains invalid options.
+    /// Fails as well, if a variable for a `$ENV:` value is not set.
     pub fn load<P: AsRef<Path>>(config_path: P) -> Result<Self> {
-        RawConfig::load(config_path).map(Config::from)
+        Config::load_with(config_path, &Opt::default())
+    }
+    /// Load the configuration like [`Config::load`].
+    ///
+    /// Unknown keys, i.e. misspelled options, are ignored with a warning. With
+    /// `--strict-config`, they are an error instead. With `--strict-env`, unset
+    /// environment variables are an error, while loading and processing.
+    ///
+    /// # Errors
+    /// See [`Config::load`].
+    pub fn load_with<P: AsRef<Path>>(config_path: P, opt: &Opt) -> Result<Self> {
+        let config_path = config_path.as_ref();
+        let (mut raw, unknown_keys) = RawConfig::load(config_path)?;
+        if opt.strict_config && !unknown_keys.is_empty() {
+            let errors = unknown_keys
+                .into_iter()
+                .map(|key| Error::UnknownConfigKey(key, config_path.into()))
+                .collect();
+            return Err(Error::from_errors(errors));
+        }
+        for key in unknown_keys {
+            warn!("Ignoring unknown key {:?} in {:?}", key, config_path);
+        }
+        if let Some(ref file) = raw.substitutions_file {
+            let dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+            let file = env::expand_env_path(file, opt.strict_env)?;
+            let substitutions = load_substitutions(dir.join(file))?;
+            raw.substitutions
+                .get_or_insert_with(Substitutions::new)
+                .extend(substitutions);
+        }
+        let mut config = Config::from(raw);
+        match &config.shell {
+            Some(Shell::Program(program)) if program.is_empty() => return Err(Error::EmptyShell),
+            Some(Shell::Invocation(invocation)) if invocation.is_empty() => {
+                return Err(Error::EmptyShell)
+            }
+            _ => {}
+        }
+        resolve_env_values(&mut config.substitutions)?;
+        for (root, fc) in &mut config.file_configurations {
+            if let Some(parent) = config_path.parent() {
+                *root = parent.into();
+            }
+            fc.resolve_env_values()?;
+            fc.expand_escape(opt.strict_env)?;
+            fc.expand_paths(opt.strict_env)?;
+            fc.validate()?;
+        }
+        config.strict_env = opt.strict_env;
+        config.apply_command_settings();
+        Ok(config)
+    }
+    /// Load all given configuration files and merge them.
+    ///
+    /// The files are merged in order. A substitution defined in more than one file takes
+    /// the value of the last one, with a warning. With `--strict`, this fails instead.
+    /// Every file keeps the `shell` and `command_timeout` of its own configuration.
+    ///
+    /// # Errors
+    /// Fails, if any file cannot be loaded, see [`Config::load`].
+    pub fn load_all<P: AsRef<Path>>(config_paths: &[P], opt: &Opt) -> Result<Self> {
+        let mut merged = Config::from(RawConfig::default());
+        for config_path in config_paths {
+            let config_path = config_path.as_ref();
+            debug!("Loading {:?}", display_path(config_path, opt));
+            let mut config = Config::load_with(config_path, opt)?;
+            // Bind the settings before merging, so that later files do not override them
+            config.apply_command_settings();
+            for (key, value) in config.substitutions {
+                match merged.substitutions.get(&key) {
+                    Some(old) if *old != value && opt.strict => {
+                        return Err(Error::ConflictingSubstitution(key, config_path.into()));
+                    }
+                    Some(old) if *old != value => warn!(
+                        "Substitution {:?} is redefined in {:?}, using {:?}",
+                        key,
+                        display_path(config_path, opt),
+                        value
+                    ),
+                    _ => {}
+                }
+                merged.substitutions.insert(key, value);
+            }
+            merged
+                .file_configurations
+                .extend(config.file_configurations);
+            merged.command_timeout = config.command_timeout.or(merged.command_timeout);
+            merged.shell = config.shell.or(merged.shell);
+        }
+        merged.strict_env = opt.strict_env;
+        Ok(merged)
+    }
+    /// How `$(...)` commands and hooks are run, given by `shell`, `command_timeout`
+    /// and `strict_env`.
+    pub fn command_settings(&self) -> CommandSettings {
+        CommandSettings {
+            shell: self.shell.as_ref().map(Shell::invocation),
+            timeout: self.command_timeout,
+            strict_env: self.strict_env,
+        }
+    }
+    /// Pass the [`Config::command_settings`] on to all files.
+    ///
+    /// This is done on load, call it again after changing `shell`, `command_timeout`
+    /// or `strict_env`.
+    pub fn apply_command_settings(&mut self) {
+        let settings = self.command_settings();
+        for (_, fc) in &mut self.file_configurations {
+            fc.set_command_settings(settings.clone());
+        }
+    }
+    /// Check the selected files up front, without processing any, see `--check`.
+    ///
+    /// # Errors
+    /// Returns every problem found by [`FileConfig::check`] and targets used by more
+    /// than one file with [`Error::DuplicateTarget`], together in [`Error::Multiple`].
+    pub fn validate(&self, opt: &Opt) -> Result<()> {
+        let mut errors = vec![];
+        let mut targets = HashMap::new();
+        let selected = self
+            .file_configurations
+            .iter()
+            .filter(|(_, fc)| fc.is_selected(opt));
+        for (root, fc) in selected {
+            errors.extend(fc.check(root, opt));
+            let target_path = fc.target_path(root);
+            let source_path = fc.source_path(root);
+            match targets.get(&canonicalize_parent(&target_path)) {
+                Some(other) => errors.push(Error::DuplicateTarget(
+                    display_path(target_path, opt),
+                    display_path(other, opt),
+                    display_path(source_path, opt),
+                )),
+                None => {
+                    targets.insert(canonicalize_parent(&target_path), source_path);
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::from_errors(errors))
+        }
     }
     /// Process all files.
     ///
     /// This will execute all preprocessing instructions and link the output file.
+    /// Files are processed by `--jobs` threads in parallel, defaulting to one per cpu.
+    /// With `--panic`, they are processed one by one, stopping at the first failure.
+    ///
+    /// # Errors
+    /// Errors are reported for every file, if any file failed, the failed sources are
+    /// summarized in [`Error::FilesFailed`]. With `--panic`, the first error is
+    /// returned instead. See [`Config::process_files_reported`] for the results of
+    /// the single files.
     pub fn process_files(&self, opt: &Opt) -> Result<()> {
-        // Iterate over all config file entries
-        for fc in &self.file_configurations {
-            // Preprocess the current file
-            match fc.preprocess(&self.substitutions, opt) {
+        self.process_files_reported(opt).1
+    }
+    /// Process all files like [`Config::process_files`] and report on each of them.
+    ///
+    /// The reports are in the order of the files, the result is the one of
+    /// [`Config::process_files`].
+    pub fn process_files_reported(&self, opt: &Opt) -> (Vec<FileReport>, Result<()>) {
+        let file_configurations = match self.select_files(opt).inspect_err(report) {
+            Ok(file_configurations) => file_configurations,
+            Err(e) => return (vec![], Err(e)),
+        };
+        if opt.transactional {
+            return self.process_files_transactional(&file_configurations, opt);
+        }
+        let reports = if opt.panic {
+            let mut reports = vec![];
+            for (root, fc) in file_configurations {
+                let (file_report, result) = self.process_file(root, fc, opt);
+                reports.push(file_report);
+                if let Err(e) = result {
+                    return (reports, Err(e));
+                }
+            }
+            reports
+        } else {
+            for_each_parallel(&file_configurations, opt, |(root, fc)| {
+                self.process_file(root, fc, opt).0
+            })
+        };
+        let failed: Vec<_> = reports
+            .iter()
+            .filter(|r| !r.errors.is_empty())
+            .map(|r| display_path(&r.source, opt).display().to_string())
+            .collect();
+        if !failed.is_empty() {
+            let e = Error::FilesFailed(failed.len(), reports.len(), failed.join(", "));
+            report(&e);
+            return (reports, Err(e));
+        }
+        (reports, Ok(()))
+    }
+    /// Preprocess all files first and link them only if all succeeded.
+    ///
+    /// If linking fails, all links created so far are rolled back.
+    fn process_files_transactional(
+        &self,
+        files: &[&(PathBuf, FileConfig)],
+        opt: &Opt,
+    ) -> (Vec<FileReport>, Result<()>) {
+        let results = for_each_parallel(files, opt, |(root, fc)| {
+            fc.preprocess(root, &self.substitutions, opt)
+        });
+        let mut reports = vec![];
+        let mut failed = 0;
+        for ((root, fc), result) in files.iter().zip(results) {
+            let mut file_report = FileReport::new(root, fc);
+            match result {
+                Ok((skipped_lines, substitutions)) => {
+                    file_report.skipped_lines = skipped_lines;
+                    file_report.substitutions = substitutions;
+                }
+                Err(e) => {
+                    report(&e);
+                    file_report.add_error(&e);
+                    failed += 1;
+                }
+            }
+            reports.push(file_report);
+        }
+        if failed > 0 {
+            let e = Error::TransactionFailed(failed);
+            report(&e);
+            return (reports, Err(e));
+        }
+        // Nothing is linked in a dry run
+        if opt.dry_run {
+            return (reports, Ok(()));
+        }
+        let mut linked: Vec<(&Path, &FileConfig, _)> = vec![];
+        for ((root, fc), file_report) in files.iter().zip(&mut reports) {
+            let backup = fc.backup_link(root);
+            if let Err(e) = fc.create_link(root, opt) {
+                report(&e);
+                file_report.add_error(&e);
+                for (root, fc, backup) in linked.into_iter().rev() {
+                    if let Err(e) = fc.restore_link(root, backup, opt) {
+                        error!("Rollback failed: {}", e);
+                    }
+                }
+                reports.iter_mut().for_each(|report| report.linked = false);
+                let e = Error::TransactionFailed(1);
+                report(&e);
+                return (reports, Err(e));
+            }
+            file_report.linked = true;
+            linked.push((root, fc, backup));
+        }
+        (reports, Ok(()))
+    }
+    /// Remove the links and preprocessed files of all files.
+    ///
+    /// See [`FileConfig::remove_link`], errors are reported for every file.
+    pub fn clean_files(&self, restore_backup: bool, opt: &Opt) -> Result<()> {
+        let mut errors = vec![];
+        for (root, fc) in self.select_files(opt).inspect_err(report)? {
+            if let Err(e) = fc.remove_link(root, restore_backup, opt) {
+                report(&e);
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::from_errors(errors))
+        }
+    }
+    /// Find the file configuration with the given source, together with its root.
+    ///
+    /// # Errors
+    /// Fails, if no file configuration uses `source`.
+    pub fn find_source(&self, source: &Path) -> Result<&(PathBuf, FileConfig)> {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.into());
+        let source = canonical(source);
+        self.file_configurations
+            .iter()
+            .find(|(root, fc)| canonical(&fc.source_path(root)) == source)
+            .ok_or(Error::UnknownSource(source))
+    }
+    /// Select the files to process.
+    ///
+    /// Disabled files and those filtered by `--only` or `--skip` are left out, see
+    /// [`FileConfig::is_selected`]. With `--interactive-select`, the user picks from
+    /// the remaining files, otherwise all of them are used.
+    fn select_files(&self, opt: &Opt) -> Result<Vec<&(PathBuf, FileConfig)>> {
+        let files: Vec<_> = self
+            .file_configurations
+            .iter()
+            .filter(|(root, fc)| {
+                let selected = fc.is_selected(opt);
+                if !selected {
+                    info!("Skipping {}", fc.display_name(root, opt));
+                }
+                selected
+            })
+            .collect();
+        if !opt.interactive_select || opt.non_interactive {
+            return Ok(files);
+        }
+        let names: Vec<_> = files
+            .iter()
+            .map(|(root, fc)| {
+                format!(
+                    "{:?} → {:?}",
+                    display_path(fc.source_path(root), opt),
+                    display_path(fc.target_path(root), opt)
+                )
+            })
+            .collect();
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let question = "Which files should be processed?";
+        let selection = prompt_selection(question, &names, &mut stdin.lock(), &mut stdout.lock())?;
+        Ok(selection.into_iter().map(|idx| files[idx]).collect())
+    }
+    /// Preprocess and link a single file, reporting all errors.
+    ///
+    /// The error is returned as well, to stop processing with `--panic`.
+    fn process_file(&self, root: &Path, fc: &FileConfig, opt: &Opt) -> (FileReport, Result<()>) {
+        let mut file_report = FileReport::new(root, fc);
+        // Preprocess the current file
+        let result = fc.preprocess(root, &self.substitutions, opt).and_then(
+            |(skipped_lines, substitutions)| {
+                file_report.skipped_lines = skipped_lines;
+                file_report.substitutions = substitutions;
+                // Nothing is linked in a dry run
+                if opt.dry_run {
+                    return Ok(());
+                }
                 // Link the current file
-                Ok(_) => match fc.create_link(opt) {
-                    Ok(_) => {}
-                    Err(e) => error!("{}", e),
-                },
-                Err(e) => error!("{}", e),
+                fc.create_link(root, opt)?;
+                file_report.linked = true;
+                Ok(())
+            },
+        );
+        if let Err(e) = &result {
+            if let Some(name) = fc.name() {
+                error!("Failed to process {}", name);
             }
+            report(e);
+            file_report.add_error(e);
         }
-        Ok(())
+        (file_report, result)
+    }
+}
+
+/// Call `f` for all `files`, using `--jobs` threads.
+///
+/// The results are in the order of `files`.
+fn for_each_parallel<'a, I, T, F>(files: &[&'a I], opt: &Opt, f: F) -> Vec<T>
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&'a I) -> T + Sync,
+{
+    let jobs = opt
+        .jobs
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+        .min(files.len());
+    if jobs <= 1 {
+        // Iterate over all config file entries
+        return files.iter().map(|fc| f(fc)).collect();
     }
+    // Every worker takes the next unprocessed entry until none are left
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::SeqCst);
+                        match files.get(idx) {
+                            Some(fc) => results.push((idx, f(fc))),
+                            None => return results,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Worker panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(idx, _)| *idx);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
 impl From<RawConfig> for Config {
     fn from(raw: RawConfig) -> Self {
         let mut file_configurations = raw.file_configurations;
         let prefix = raw.default_prefix;
+        let comment_char = raw.default_comment_char;
+        let max_substitution_depth = raw.max_substitution_depth;
         let remove_instructions = raw.default_remove_instructions;
         let escape = raw.default_escape;
-        let substitutions = raw.substitutions.unwrap_or(HashMap::new());
+        let substitutions = raw.substitutions.unwrap_or_default();
+        let command_timeout = raw.command_timeout_secs.map(Duration::from_secs);
+        let shell = raw.shell;
+        let output_dir = raw.output_dir;
         // Fill in the defaults where necessary
         for fc in &mut file_configurations {
-            fc.supplement(&escape, remove_instructions, &prefix);
+            fc.supplement(
+                &escape,
+                remove_instructions,
+                &prefix,
+                &comment_char,
+                max_substitution_depth,
+                &output_dir,
+            );
         }
+        // Relative paths are resolved against the current directory
+        let file_configurations = file_configurations
+            .into_iter()
+            .map(|fc| (PathBuf::from("."), fc))
+            .collect();
         // Return a real config
-        Config {
+        let mut config = Config {
             file_configurations,
             substitutions,
+            command_timeout,
+            shell,
+            strict_env: false,
+        };
+        config.apply_command_settings();
+        config
+    }
+}
+
+/// Find the keys of the loaded TOML `value`, that are missing in `known`, the
+/// configuration after deserializing and serializing it again. Keys of nested tables
+/// are named by their path, i.e. `config[0].preffix`.
+///
+/// Only the top level and the `[[config]]` entries are checked, the keys of
+/// substitution tables are arbitrary.
+fn unknown_keys(value: &toml::Value, known: &toml::Value) -> Vec<String> {
+    let is_unknown = |known: &toml::Value, key: &str| known.get(key).is_none();
+    let mut unknown = vec![];
+    for (key, value) in value.as_table().into_iter().flatten() {
+        if is_unknown(known, key) {
+            unknown.push(key.clone());
+        } else if let (Some(entries), Some(known_entries)) = (
+            value.as_array().filter(|_| key == "config"),
+            known[key.as_str()].as_array(),
+        ) {
+            for (idx, (entry, known_entry)) in entries.iter().zip(known_entries).enumerate() {
+                for entry_key in entry.as_table().into_iter().flat_map(|table| table.keys()) {
+                    if is_unknown(known_entry, entry_key) {
+                        unknown.push(format!("{}[{}].{}", key, idx, entry_key));
+                    }
+                }
+            }
         }
     }
+    unknown
+}
+
+/// Load substitutions from a TOML file containing only `key = "value"` pairs.
+fn load_substitutions<P: AsRef<Path>>(path: P) -> Result<Substitutions> {
+    let content = fs::read_to_string(path).map_err(Error::as_load_config)?;
+    toml::from_str(&content).map_err(Error::FailedToParseConfiguration)
+}
+
+/// Replace values like `$ENV:NAME` by the value of the environment variable `NAME`.
+///
+/// # Errors
+/// Fails, if a variable is not set or not valid unicode.
+fn resolve_env_values(substitutions: &mut Substitutions) -> Result<()> {
+    for (key, value) in substitutions.iter_mut() {
+        if let Some(name) = value.strip_prefix(ENV_VALUE_PREFIX) {
+            *value = std::env::var(name)
+                .map_err(|_| Error::MissingEnvSubstitution(key.clone(), name.into()))?;
+        }
+    }
+    Ok(())
 }
 
 fn default_true() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorList;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_load_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("preprocessor.toml");
+        // Escapes are not interpreted as regexes
+        let toml = "[[config]]\nsource = \"a\"\ntarget = \"b\"\nescape = [\"[(\", \"]\"]\n";
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let regex = config.file_configurations[0].1.escape_regex().unwrap();
+        assert_eq!(&regex.captures(" [(KEY]").unwrap()[2], "KEY");
+        // Empty escapes are rejected
+        let toml = "default_escape = [\"\", \"}}\"]\n[[config]]\nsource = \"a\"\ntarget = \"b\"\n";
+        fs::write(&config_path, toml).unwrap();
+        match Config::load(&config_path) {
+            Err(Error::EmptyEscape(source)) => assert_eq!(source, "a"),
+            other => panic!("Expected an empty escape error, got {:?}", other),
+        }
+        // Empty comment characters are rejected as well
+        let toml = "default_comment_char = \"\"\n[[config]]\nsource = \"a\"\ntarget = \"b\"\n";
+        fs::write(&config_path, toml).unwrap();
+        match Config::load(&config_path) {
+            Err(Error::EmptyCommentChar(source)) => assert_eq!(source, "a"),
+            other => panic!("Expected an empty comment char error, got {:?}", other),
+        }
+        // Escapes may be given as string or table as well
+        let forms = [
+            ("\"{{ }}\"", "{{", "}}"),
+            ("\"%%\"", "%%", "%%"),
+            ("{ start = \"<\", end = \">\" }", "<", ">"),
+        ];
+        for (escape, start, end) in &forms {
+            let toml = format!(
+                "[[config]]\nsource = \"a\"\ntarget = \"b\"\nescape = {}\n",
+                escape
+            );
+            fs::write(&config_path, toml).unwrap();
+            let config = Config::load(&config_path).unwrap();
+            let regex = config.file_configurations[0].1.escape_regex().unwrap();
+            let text = format!(" {}KEY{}", start, end);
+            assert_eq!(&regex.captures(&text).unwrap()[2], "KEY");
+        }
+        fs::write(&config_path, "default_escape = \"{{ - }}\"\n").unwrap();
+        assert!(Config::load(&config_path).is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("preprocessor.toml");
+        let toml = r##"
+            subsitutions = { NAME = "dope" }
+            [substitutions]
+            any_key = "is fine"
+            [[config]]
+            source = "a"
+            target = "b"
+            [[config]]
+            source = "c"
+            target = "d"
+            preffix = "#~"
+            [config.substitutions]
+            other_key = "is fine too"
+        "##;
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        assert_eq!(config.file_configurations.len(), 2);
+        let opt = Opt::from_iter(&["dope", "--strict-config"]);
+        match Config::load_with(&config_path, &opt) {
+            Err(Error::Multiple(errors)) => {
+                let errors = errors.to_string();
+                assert!(errors.contains("\"subsitutions\""), "{}", errors);
+                assert!(errors.contains("\"config[1].preffix\""), "{}", errors);
+                assert_eq!(errors.lines().count(), 2);
+            }
+            other => panic!("Expected unknown keys, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("preprocessor.toml");
+        let load = |toml: &str| {
+            fs::write(&config_path, toml).unwrap();
+            Config::load(&config_path)
+        };
+        assert_eq!(load("").unwrap().shell, None);
+        let shell = load("shell = \"bash\"").unwrap().shell.unwrap();
+        assert_eq!(shell, Shell::Program("bash".into()));
+        if cfg!(not(windows)) {
+            assert_eq!(shell.invocation(), vec!["bash", "-c"]);
+        }
+        let shell = load("shell = [\"bash\", \"-o\", \"pipefail\", \"-c\"]")
+            .unwrap()
+            .shell;
+        assert_eq!(
+            shell.unwrap().invocation(),
+            vec!["bash", "-o", "pipefail", "-c"]
+        );
+        match load("shell = []") {
+            Err(Error::EmptyShell) => {}
+            other => panic!("Expected an empty shell error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shell_per_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let write = |name: &str, shell: &str| {
+            fs::write(root.join(name), "{{$(echo $0)}}").unwrap();
+            let toml = format!(
+                "shell = {:?}\ndefault_escape = \"{{{{ }}}}\"\n\
+                 [[config]]\nsource = {:?}\ntarget = \"{}.target\"\n",
+                shell, name, name
+            );
+            let config_path = root.join(format!("{}.toml", name));
+            fs::write(&config_path, toml).unwrap();
+            config_path
+        };
+        let bash = write("bash", "bash");
+        let sh = write("sh", "sh");
+        let opt = Opt::from_iter(&["dope"]);
+        let read = |name: &str| fs::read_to_string(root.join(name)).unwrap();
+        // Loading another configuration does not change the shell of the first
+        for config_path in &[&bash, &sh] {
+            let config = Config::load(config_path).unwrap();
+            config.process_files(&opt).unwrap();
+        }
+        assert_eq!(read("bash.target"), "bash");
+        assert_eq!(read("sh.target"), "sh");
+        // Neither does merging them
+        fs::remove_file(root.join("bash.target")).unwrap();
+        fs::remove_file(root.join("sh.target")).unwrap();
+        let config = Config::load_all(&[&bash, &sh], &opt).unwrap();
+        config.process_files(&opt).unwrap();
+        assert_eq!(read("bash.target"), "bash");
+        assert_eq!(read("sh.target"), "sh");
+    }
+
+    #[test]
+    fn test_paths_from_env() {
+        ::std::env::set_var("DOPE_TEST_PATH_DIR", "dir");
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let toml = "[[config]]\nsource = \"$DOPE_TEST_PATH_DIR/s\"\ntarget = \"${DOPE_TEST_PATH_DIR}/t\"\n";
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        // Paths are expanded once on load, later changes do not matter
+        ::std::env::remove_var("DOPE_TEST_PATH_DIR");
+        let fc = &config.file_configurations[0].1;
+        assert_eq!(fc.source_path(root), root.join("dir/s"));
+        assert_eq!(fc.target_path(root), root.join("dir/t"));
+    }
+
+    #[test]
+    fn test_escape_from_env() {
+        ::std::env::set_var("DOPE_TEST_ESCAPE_START", "<%");
+        ::std::env::set_var("DOPE_TEST_ESCAPE_END", "%>");
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("source"), "name = <%NAME%>").unwrap();
+        let toml = r#"
+            default_escape = ["$DOPE_TEST_ESCAPE_START", "${DOPE_TEST_ESCAPE_END}"]
+            [substitutions]
+            NAME = "dope"
+            [[config]]
+            source = "source"
+            target = "target"
+        "#;
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config_path.to_str().unwrap()]);
+        config.process_files(&opt).unwrap();
+        let target = fs::read_to_string(root.join("target")).unwrap();
+        assert_eq!(target, "name = dope");
+    }
+
+    #[test]
+    fn test_process_files_transactional() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut toml = String::new();
+        for nr in 0..3 {
+            toml += &format!(
+                "[[config]]\nsource = \"source{0}\"\ntarget = \"target{0}\"\n",
+                nr
+            );
+        }
+        // The second source is missing
+        fs::write(root.join("source0"), "0").unwrap();
+        fs::write(root.join("source2"), "2").unwrap();
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let config_arg = config_path.to_str().unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config_arg, "--transactional"]);
+        match config.process_files(&opt) {
+            Err(Error::TransactionFailed(1)) => {}
+            other => panic!("Expected a failed transaction, got {:?}", other),
+        }
+        for nr in 0..3 {
+            assert!(fs::symlink_metadata(root.join(format!("target{}", nr))).is_err());
+        }
+        // Once everything succeeds, all files are linked
+        fs::write(root.join("source1"), "1").unwrap();
+        config.process_files(&opt).unwrap();
+        for nr in 0..3 {
+            let target = root.join(format!("target{}", nr));
+            assert_eq!(fs::read_to_string(target).unwrap(), format!("{}", nr));
+        }
+    }
+
+    #[test]
+    fn test_process_files_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut toml = String::new();
+        for nr in 0..3 {
+            toml += &format!(
+                "[[config]]\nsource = \"source{0}\"\ntarget = \"target{0}\"\n",
+                nr
+            );
+        }
+        // The second source is missing
+        fs::write(root.join("source0"), "0").unwrap();
+        fs::write(root.join("source2"), "2").unwrap();
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let config_arg = config_path.to_str().unwrap();
+        // With --panic, processing stops at the missing source
+        let opt = Opt::from_iter(&["dope", "-c", config_arg, "--panic"]);
+        match config.process_files(&opt) {
+            Err(Error::FailedToReadSourceFile(..)) => {}
+            other => panic!("Expected the missing source, got {:?}", other),
+        }
+        assert!(root.join("target0").exists());
+        assert!(fs::symlink_metadata(root.join("target2")).is_err());
+        // Otherwise, the other files are processed anyway
+        let opt = Opt::from_iter(&["dope", "-c", config_arg]);
+        match config.process_files(&opt) {
+            Err(Error::FilesFailed(1, 3, sources)) => assert_eq!(sources, "source1"),
+            other => panic!("Expected one failed file, got {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(root.join("target2")).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let toml = "[[config]]\nsource = \"a\"\ntarget = \"t\"\nprefix = \"\"\n\
+                    [[config]]\nname = \"m\"\nsource = \"missing\"\ntarget = \"t\"\n";
+        fs::write(root.join("a"), "a").unwrap();
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let config_arg = config_path.to_str().unwrap();
+        let opt = Opt::from_iter(&["dope", "--check", "-c", config_arg]);
+        // All problems are reported together
+        match config.validate(&opt) {
+            Err(Error::Multiple(ErrorList(errors))) => {
+                assert_eq!(errors.len(), 3, "{:?}", errors);
+                assert!(matches!(errors[0], Error::EmptyPrefix(_)));
+                assert!(matches!(errors[1], Error::FailedToReadSourceFile(_, _)));
+                assert!(matches!(errors[2], Error::DuplicateTarget(_, _, _)));
+            }
+            other => panic!("Expected multiple errors, got {:?}", other),
+        }
+        // Nothing was processed
+        assert!(!root.join("a.preprocessed").exists());
+        // The empty prefix is fine without instructions and a skipped file is not checked
+        let args = ["dope", "-c", config_arg, "--no-instructions", "--skip", "m"];
+        config.validate(&Opt::from_iter(&args)).unwrap();
+    }
+
+    #[test]
+    fn test_select_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let toml = "[[config]]\nname = \"a\"\nsource = \"a\"\ntarget = \"ta\"\n\
+                    [[config]]\nname = \"b\"\nsource = \"b\"\ntarget = \"tb\"\n\
+                    [[config]]\nsource = \"c\"\ntarget = \"tc\"\nenabled = false\n";
+        for name in &["a", "b", "c"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let config_arg = config_path.to_str().unwrap();
+        let processed = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(&["-c", config_arg]);
+            let (reports, result) = config.process_files_reported(&Opt::from_iter(args));
+            result.unwrap();
+            let sources: Vec<_> = reports
+                .iter()
+                .map(|report| report.source.file_name().unwrap().to_owned())
+                .collect();
+            sources
+        };
+        assert_eq!(processed(&["dope", "--skip", "a"]), vec!["b"]);
+        assert_eq!(processed(&["dope", "--only", "a"]), vec!["a"]);
+        // The disabled file is neither preprocessed nor linked
+        assert_eq!(processed(&["dope"]), vec!["a", "b"]);
+        assert!(!root.join("c.preprocessed").exists());
+        assert!(fs::symlink_metadata(root.join("tc")).is_err());
+    }
+
+    #[test]
+    fn test_process_files_parallel() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut toml = String::new();
+        for nr in 0..4 {
+            fs::write(root.join(format!("source{}", nr)), format!("{}", nr)).unwrap();
+            toml += &format!(
+                "[[config]]\nsource = \"source{0}\"\ntarget = \"target{0}\"\n",
+                nr
+            );
+        }
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config_path.to_str().unwrap(), "-j", "4"]);
+        config.process_files(&opt).unwrap();
+        for nr in 0..4 {
+            let target = root.join(format!("target{}", nr));
+            assert_eq!(fs::read_to_string(target).unwrap(), format!("{}", nr));
+        }
+    }
+
+    #[test]
+    fn test_load_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config_paths = vec![];
+        for (nr, topic) in ["git", "vim"].iter().enumerate() {
+            let root = dir.path().join(topic);
+            fs::create_dir(&root).unwrap();
+            fs::write(root.join("source"), "name = {{NAME}}").unwrap();
+            let toml = format!(
+                "default_escape = [\"{{{{\", \"}}}}\"]\n\
+                 [substitutions]\nNAME = \"{}\"\n\
+                 [[config]]\nsource = \"source\"\ntarget = \"target\"\n",
+                nr
+            );
+            let config_path = root.join("preprocessor.toml");
+            fs::write(&config_path, toml).unwrap();
+            config_paths.push(config_path);
+        }
+        let opt = Opt::from_iter(&["dope"]);
+        let config = Config::load_all(&config_paths, &opt).unwrap();
+        config.process_files(&opt).unwrap();
+        // Paths are relative to their own configuration file, the last substitution wins
+        for topic in &["git", "vim"] {
+            let target = dir.path().join(topic).join("target");
+            assert_eq!(fs::read_to_string(target).unwrap(), "name = 1");
+        }
+        // Conflicts fail in strict mode
+        let opt = Opt::from_iter(&["dope", "--strict"]);
+        match Config::load_all(&config_paths, &opt) {
+            Err(Error::ConflictingSubstitution(key, _)) => assert_eq!(key, "NAME"),
+            other => panic!("Expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_substitutions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut toml = String::from("default_escape = [\"{{\", \"}}\"]\n");
+        toml += "[substitutions]\nNAME = \"global\"\n";
+        for nr in 0..2 {
+            fs::write(root.join(format!("source{}", nr)), "name = {{NAME}}").unwrap();
+            toml += &format!(
+                "[[config]]\nsource = \"source{0}\"\ntarget = \"target{0}\"\n",
+                nr
+            );
+        }
+        // Only the second file overrides the name
+        toml += "[config.substitutions]\nNAME = \"file\"\n";
+        let config_path = root.join("preprocessor.toml");
+        fs::write(&config_path, toml).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config_path.to_str().unwrap()]);
+        config.process_files(&opt).unwrap();
+        let target = |nr| fs::read_to_string(root.join(format!("target{}", nr))).unwrap();
+        assert_eq!(target(0), "name = global");
+        assert_eq!(target(1), "name = file");
+    }
+
+    #[test]
+    fn test_substitutions_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("preprocessor.toml");
+        let toml = "substitutions_file = \"secrets.toml\"\n\
+                    [substitutions]\nUSER = \"max\"\nPASSWORD = \"none\"\n\
+                    TOKEN = \"$ENV:DOPE_TEST_TOKEN\"\n";
+        fs::write(&config_path, toml).unwrap();
+        fs::write(dir.path().join("secrets.toml"), "PASSWORD = \"secret\"\n").unwrap();
+        std::env::set_var("DOPE_TEST_TOKEN", "token");
+        let config = Config::load(&config_path).unwrap();
+        // The file wins over the table, variables are read at load time
+        assert_eq!(config.substitutions["USER"], "max");
+        assert_eq!(config.substitutions["PASSWORD"], "secret");
+        assert_eq!(config.substitutions["TOKEN"], "token");
+        std::env::remove_var("DOPE_TEST_TOKEN");
+        match Config::load(&config_path) {
+            Err(Error::MissingEnvSubstitution(key, _)) => assert_eq!(key, "TOKEN"),
+            other => panic!("Expected a missing variable, got {:?}", other),
+        }
+    }
+}
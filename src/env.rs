@@ -1,55 +1,249 @@
 //! Handle environment variables
 //!
-//! **Note**: Supports Unix, nothing else...
-//!
 //! Environment variables can be used when replacing stuff. They may also appear
 //! in paths in the configuration.
 //!
 //! # Unix
 //! To use an environment variable, one can use `$YOUR_ENV_VAR` or `${YOUR_ENV_VAR}`.
-//! `YOUR_ENV_VAR` may only contain the characters `a-z`, `A-Z` and `_` (Underscore).
+//! `YOUR_ENV_VAR` may only contain the characters `a-z`, `A-Z`, `0-9` and `_` (Underscore)
+//! and must not start with a digit.
 //! **Note**: All used variables must expand to valid Unicode!
+//!
+//! # Windows
+//! Additionally to the Unix syntax, `%YOUR_ENV_VAR%` is understood.
+//! Commands (`$(...)`) are run by `cmd /C`.
+//!
+//! # Implicit variables
+//! `DOPE_OS`, `DOPE_ARCH` and `DOPE_HOSTNAME` are defined, unless they are set in the
+//! environment, see [`implicit_var`].
+//!
+//! # Commands
+//! The output of a command is cached for the rest of the process, every command
+//! runs only once per shell, see [`CommandSettings`]. A trailing `!`, like `$(date!)`, runs the command without
+//! the `!` every time instead.
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env::var as resolve_env;
 use std::env::VarError;
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::io::Read;
+use std::path::{is_separator, Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// Interval in which a running command is checked for completion,
+/// when a command timeout is set.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Commands ending with this are not cached.
+const UNCACHED_SUFFIX: char = '!';
+
+/// A cached command, identified by the shell running it and the command itself.
+type CacheKey = (Option<Vec<String>>, String);
 
 lazy_static! {
-    static ref RE_DOLLAR: Regex = Regex::new(r"([^\\]|^)\$([a-zA-Z_]+)").unwrap();
-    static ref RE_DOLLAR_BRACES: Regex = Regex::new(r"([^\\]|^)\$\{([a-zA-Z_]+)\}").unwrap();
+    static ref RE_DOLLAR: Regex = Regex::new(r"([^\\]|^)\$([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+    static ref RE_DOLLAR_BRACES: Regex = Regex::new(r"([^\\]|^)\$\{([a-zA-Z_][a-zA-Z0-9_]*)\}").unwrap();
     static ref RE_DOLLAR_PARENS: Regex = Regex::new(r"([^\\]|^)\$\((.+?[^\\])\)").unwrap();
+    static ref RE_PERCENT: Regex = Regex::new(r"([^\\]|^)%([a-zA-Z_][a-zA-Z0-9_]*)%").unwrap();
+    /// Outputs of successful commands, see [`run_subst`].
+    static ref COMMAND_CACHE: Mutex<HashMap<CacheKey, String>> = Mutex::new(HashMap::new());
+    /// The name of this machine, see [`implicit_var`].
+    static ref HOSTNAME: Option<String> = hostname();
+}
+
+/// How `$(...)` commands and hooks are run and environment variables are expanded.
+///
+/// The default runs them in `sh -c` (`cmd /C` on Windows) without a timeout and
+/// expands unset variables to nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandSettings {
+    /// The shell, given as program followed by its arguments. The command is
+    /// passed as last argument. `None` uses the default shell.
+    pub shell: Option<Vec<String>>,
+    /// The maximum duration a command may run, it is killed afterwards.
+    /// `None` disables the timeout.
+    pub timeout: Option<Duration>,
+    /// Fail on unset environment variables, instead of expanding them to nothing.
+    pub strict_env: bool,
+}
+
+/// Expand all `$(...)` commands, run with `settings`, and afterwards all
+/// environment variables in the given string.
+///
+/// # Errors
+/// See [`expand_subst`] and [`expand_env`].
+pub fn expand(s: &str, settings: &CommandSettings) -> Result<String> {
+    let s = expand_subst(s, settings)?;
+    expand_env(&s, settings.strict_env)
+}
+
+/// Expand all environment variables in the given string.
+///
+/// # Errors
+/// Fails on an unset variable, if `strict` is set.
+pub fn expand_env(s: &str, strict: bool) -> Result<String> {
+    let mut unset = vec![];
+    let simples_expanded = RE_DOLLAR.replace_all(s, env_replacer(&mut unset));
+    let all_envs_expanded =
+        RE_DOLLAR_BRACES.replace_all(&simples_expanded, env_replacer(&mut unset));
+    let expanded = if cfg!(windows) {
+        expand_percent(&all_envs_expanded, &mut unset)
+    } else {
+        all_envs_expanded.as_ref().to_owned()
+    };
+    match unset.into_iter().next() {
+        Some(key) if strict => Err(Error::UndefinedEnvVar(key)),
+        _ => Ok(expanded),
+    }
+}
+
+/// Expand all `%VAR%`s in the given string, as done by `cmd`.
+///
+/// The names of unset variables are added to `unset`.
+fn expand_percent(s: &str, unset: &mut Vec<String>) -> String {
+    RE_PERCENT.replace_all(s, env_replacer(unset)).into_owned()
 }
 
-pub fn expand<'a>(s: &'a str) -> String {
-    let s = expand_subst(s);
-    expand_env(&s)
+/// Expand all `$(...)` commands in the given string, run with `settings`.
+///
+/// # Errors
+/// Fails, if a command could not be run or exceeded the command timeout.
+pub fn expand_subst<'a>(s: &'a str, settings: &CommandSettings) -> Result<Cow<'a, str>> {
+    if !RE_DOLLAR_PARENS.is_match(s) {
+        return Ok(Cow::from(s));
+    }
+    let mut expanded = String::with_capacity(s.len());
+    let mut last_match = 0;
+    for captures in RE_DOLLAR_PARENS.captures_iter(s) {
+        let whole = captures.get(0).unwrap();
+        expanded.push_str(&s[last_match..whole.start()]);
+        expanded.push_str(&captures[1]);
+        expanded.push_str(&run_subst(&captures[2], settings)?);
+        last_match = whole.end();
+    }
+    expanded.push_str(&s[last_match..]);
+    Ok(Cow::from(expanded))
 }
 
-pub fn expand_env<'a>(s: &'a str) -> String {
-    let simples_expanded = RE_DOLLAR.replace_all(s.as_ref(), env_replacer());
-    let all_envs_expanded = RE_DOLLAR_BRACES.replace_all(&simples_expanded, env_replacer());
-    all_envs_expanded.as_ref().to_owned()
+/// The commands of all `$(...)` expansions in `s`, without a trailing `!`.
+///
+/// Nothing is run.
+pub fn referenced_commands(s: &str) -> Vec<String> {
+    RE_DOLLAR_PARENS
+        .captures_iter(s)
+        .map(|captures| {
+            let command = &captures[2];
+            command
+                .strip_suffix(UNCACHED_SUFFIX)
+                .unwrap_or(command)
+                .into()
+        })
+        .collect()
 }
 
-pub fn expand_subst<'a>(s: &'a str) -> Cow<'a, str> {
-    RE_DOLLAR_PARENS.replace_all(s, subst_replacer())
+/// The names of all environment variables referenced in `s`, outside of commands.
+///
+/// Nothing is expanded.
+pub fn referenced_env_vars(s: &str) -> Vec<String> {
+    let without_commands = RE_DOLLAR_PARENS.replace_all(s, "$1");
+    let mut regexes = vec![&*RE_DOLLAR, &*RE_DOLLAR_BRACES];
+    if cfg!(windows) {
+        regexes.push(&*RE_PERCENT);
+    }
+    regexes
+        .iter()
+        .flat_map(|regex| regex.captures_iter(&without_commands))
+        .map(|captures| captures[2].into())
+        .collect()
 }
 
-pub fn expand_env_path<'a>(p: &'a Path) -> PathBuf {
+/// Expand a leading `~` and all environment variables in the given path.
+///
+/// # Errors
+/// See [`expand_env`].
+pub fn expand_env_path(p: &Path, strict: bool) -> Result<PathBuf> {
     let s = p.to_string_lossy();
-    expand_env(&s).into()
+    expand_env(&expand_tilde(&s), strict).map(PathBuf::from)
+}
+
+/// Replace a leading `~` with the home directory of the current user.
+///
+/// A leading `~name` is replaced with the home directory of the user `name`, on unix
+/// only. A `~` anywhere else and unknown users are kept, i.e. `a/~/b` is unchanged.
+pub fn expand_tilde(path: &str) -> Cow<'_, str> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Cow::from(path),
+    };
+    let (name, rest) = rest.split_at(rest.find(is_separator).unwrap_or(rest.len()));
+    let home = if name.is_empty() {
+        resolve_env("HOME")
+            .or_else(|_| resolve_env("USERPROFILE"))
+            .ok()
+    } else {
+        user_home_dir(name)
+    };
+    match home {
+        Some(home) => Cow::from(format!("{}{}", home, rest)),
+        None => Cow::from(path),
+    }
+}
+
+/// Look up the home directory of the user `name` in the user database.
+#[cfg(unix)]
+fn user_home_dir(name: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    let name = CString::new(name).ok()?;
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // Safety: `passwd` consists of integers and pointers, zeros are valid
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // Safety: all pointers are valid, the strings of `passwd` point into `buffer`
+        let code = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match code {
+            // The buffer is too small for the entry
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if !result.is_null() => {
+                // Safety: `pw_dir` is a nul terminated string in `buffer`
+                let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+                return home.to_str().ok().map(String::from);
+            }
+            _ => return None,
+        }
+    }
 }
 
-fn env_replacer() -> impl FnMut(&Captures) -> String {
-    |captures| {
+/// Other users are not looked up on non-unix systems.
+#[cfg(not(unix))]
+fn user_home_dir(_name: &str) -> Option<String> {
+    None
+}
+
+/// Replace variables with their values, the names of unset variables are added to `unset`.
+fn env_replacer(unset: &mut Vec<String>) -> impl FnMut(&Captures) -> String + '_ {
+    move |captures| {
         let key = &captures[2];
         let repl = match resolve_env(key) {
             Ok(repl) => repl,
-            Err(VarError::NotPresent) => String::new(),
+            Err(VarError::NotPresent) => implicit_var(key).unwrap_or_else(|| {
+                unset.push(key.into());
+                String::new()
+            }),
             Err(VarError::NotUnicode(_)) => {
                 warn!("{:?} does not contain valid unicode", key);
                 String::new()
@@ -60,36 +254,212 @@ fn env_replacer() -> impl FnMut(&Captures) -> String {
     }
 }
 
-fn subst_replacer() -> impl FnMut(&Captures) -> String {
-    |captures| {
-        let prefix = &captures[1];
-        let command = &captures[2];
-        let output = if cfg!(unix) {
-            Command::new("sh")
-                .arg("-c")
-                .arg(command)
-                .output()
-                // TODO
-                .expect("failed to execute process")
-        } else {
-            Command::new("cmd")
-                .arg("/C")
-                .arg(command)
-                .output()
-                // TODO
-                .expect("failed to execute process")
-        };
-        if output.status.success() {
-            let output = String::from_utf8_lossy(&output.stdout);
-            format!("{}{}", prefix, output.trim_end_matches("\n"))
-        } else {
-            // TODO
-            warn!("Process {:?} exited abnormally", command);
-            String::new()
+/// The value of an implicit variable, used when `key` is not set in the environment.
+///
+/// - `DOPE_OS`: The operating system, i.e. `linux`, `macos` or `windows`.
+/// - `DOPE_ARCH`: The cpu architecture, i.e. `x86_64` or `aarch64`.
+/// - `DOPE_HOSTNAME`: The name of this machine, if it can be determined.
+pub fn implicit_var(key: &str) -> Option<String> {
+    match key {
+        "DOPE_OS" => Some(std::env::consts::OS.into()),
+        "DOPE_ARCH" => Some(std::env::consts::ARCH.into()),
+        "DOPE_HOSTNAME" => HOSTNAME.clone(),
+        _ => None,
+    }
+}
+
+/// Look up the name of this machine.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buffer: Vec<u8> = vec![0; 256];
+    // Safety: the length passed is the length of `buffer`
+    let code = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if code != 0 {
+        return None;
+    }
+    // The name may be truncated without a terminating nul
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    buffer.truncate(len);
+    String::from_utf8(buffer).ok()
+}
+
+/// Windows stores the name of this machine in the environment.
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    resolve_env("COMPUTERNAME").ok()
+}
+
+/// Run the given command and return its trimmed standard output.
+///
+/// The output of a successful command is cached per shell, unless the command
+/// ends with `!`.
+fn run_subst(command: &str, settings: &CommandSettings) -> Result<String> {
+    let (command, cached) = match command.strip_suffix(UNCACHED_SUFFIX) {
+        Some(command) => (command, false),
+        None => (command, true),
+    };
+    let key = (settings.shell.clone(), command.to_string());
+    if cached {
+        if let Some(output) = COMMAND_CACHE.lock().unwrap().get(&key) {
+            return Ok(output.clone());
+        }
+    }
+    let output = run_command(command, settings)?;
+    if output.status.success() {
+        let output = String::from_utf8_lossy(&output.stdout);
+        let output: String = output.trim_end_matches(&['\r', '\n'][..]).into();
+        if cached {
+            COMMAND_CACHE.lock().unwrap().insert(key, output.clone());
+        }
+        Ok(output)
+    } else {
+        warn!("{}", describe_failure(command, &output));
+        Ok(String::new())
+    }
+}
+
+/// Run the hook `command` with `settings`, after expanding environment variables.
+///
+/// Unlike `$(...)`, hooks are never cached and their output is discarded.
+///
+/// # Errors
+/// Fails, if the command could not be run, exceeded the command timeout or exited
+/// with an error.
+pub fn run_hook(command: &str, settings: &CommandSettings) -> Result<()> {
+    let command = expand_env(command, settings.strict_env)?;
+    let output = run_command(&command, settings)?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut details = output.status.to_string();
+    if !stderr.trim().is_empty() {
+        details.push_str(":\n");
+        details.push_str(stderr.trim());
+    }
+    Err(Error::HookFailed(command, details))
+}
+
+/// Describe a failed command with its exit status and the output on stderr, if any.
+fn describe_failure(command: &str, output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    let mut message = format!(
+        "Command {:?} failed with {}, inserting nothing",
+        command, output.status
+    );
+    if !stderr.is_empty() {
+        message.push_str(":\n");
+        message.push_str(stderr);
+    }
+    message
+}
+
+/// Run the given command in the shell of `settings` and collect its output.
+///
+/// If a timeout is given and the command does not finish in time,
+/// it will be killed and an error is returned.
+fn run_command(command: &str, settings: &CommandSettings) -> Result<Output> {
+    let mut child = shell(command, settings)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::FailedToRunCommand(command.into(), e))?;
+    let timeout = match settings.timeout {
+        Some(timeout) => timeout,
+        None => {
+            return child
+                .wait_with_output()
+                .map_err(|e| Error::FailedToRunCommand(command.into(), e))
+        }
+    };
+    // Drain the pipes in the background, the child might block on a full pipe otherwise
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if start.elapsed() >= timeout => {
+                kill(&mut child);
+                return Err(Error::CommandTimedOut(command.into(), timeout));
+            }
+            Ok(None) => thread::sleep(COMMAND_POLL_INTERVAL),
+            Err(e) => {
+                kill(&mut child);
+                return Err(Error::FailedToRunCommand(command.into(), e));
+            }
         }
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Construct an invocation of the shell of `settings` running `command`.
+fn shell(command: &str, settings: &CommandSettings) -> Command {
+    match &settings.shell {
+        Some(invocation) => custom_shell(invocation, command),
+        None => default_shell(command),
     }
 }
 
+/// Construct an invocation of `program args... command` from the `invocation`.
+///
+/// # Panics
+/// Panics, if `invocation` is empty.
+fn custom_shell(invocation: &[String], command: &str) -> Command {
+    let (program, args) = invocation.split_first().expect("BUG: empty shell");
+    let mut shell = Command::new(program);
+    shell.args(args).arg(command);
+    shell
+}
+
+/// Construct a shell invocation running `command`.
+#[cfg(not(windows))]
+fn default_shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Construct a shell invocation running `command`.
+///
+/// `cmd` does not follow the usual quoting rules, the command is passed verbatim.
+#[cfg(windows)]
+fn default_shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut shell = Command::new("cmd");
+    shell
+        .args(&["/D", "/S", "/C"])
+        .raw_arg(format!("\"{}\"", command));
+    shell
+}
+
+/// Read everything from the given pipe in a separate thread.
+fn read_in_background<R>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buffer = vec![];
+        if let Some(mut pipe) = pipe {
+            // A broken pipe only means, that we got less output
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// Kill the child and reap it. Failures are ignored, the child may already be gone.
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,6 +467,7 @@ mod tests {
     #[cfg(unix)]
     #[test]
     fn test_expand_env() {
+        let expand_env = |s| expand_env(s, false).unwrap();
         ::std::env::set_var("DOTFILE_TESTING_STUFF", "FUBAR");
         assert_eq!(expand_env("$DOTFILE_TESTING_STUFF"), "FUBAR");
         assert_eq!(expand_env(" $DOTFILE_TESTING_STUFF "), " FUBAR ");
@@ -105,11 +476,69 @@ mod tests {
             "  "
         );
         assert_eq!(expand_env(r"\$HOME"), r"\$HOME");
+        // Digits are part of the name, but cannot start it
+        ::std::env::set_var("DOTFILE_TESTING_VAR1", "ONE");
+        ::std::env::set_var("DOTFILE_TESTING_A_B_2", "TWO");
+        assert_eq!(expand_env("$DOTFILE_TESTING_VAR1"), "ONE");
+        assert_eq!(expand_env("${DOTFILE_TESTING_A_B_2}"), "TWO");
+        assert_eq!(
+            expand_env(r"\$DOTFILE_TESTING_VAR1"),
+            r"\$DOTFILE_TESTING_VAR1"
+        );
+        assert_eq!(expand_env("$1abc ${2abc}"), "$1abc ${2abc}");
+    }
+
+    #[test]
+    fn test_expand_env_strict() {
+        let missing = "$SOME_VERY_UNLIKELY_VARIABLE_THAT_COULD_DESTROY_THIS_TEST";
+        assert_eq!(expand_env(missing, false).unwrap(), "");
+        match expand_env(missing, true) {
+            Err(Error::UndefinedEnvVar(key)) => {
+                assert_eq!(key, &missing[1..]);
+            }
+            other => panic!("Expected an undefined variable, got {:?}", other),
+        }
+        ::std::env::set_var("DOTFILE_TESTING_STRICT", "FUBAR");
+        assert_eq!(
+            expand_env("${DOTFILE_TESTING_STRICT}", true).unwrap(),
+            "FUBAR"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_implicit_vars() {
+        assert_eq!(expand_env("$DOPE_OS", false).unwrap(), std::env::consts::OS);
+        assert!(!expand_env("$DOPE_HOSTNAME", false).unwrap().is_empty());
+        assert_eq!(implicit_var("DOPE_UNKNOWN"), None);
+        // The environment wins
+        ::std::env::set_var("DOPE_ARCH", "custom");
+        assert_eq!(expand_env("${DOPE_ARCH}", true).unwrap(), "custom");
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = resolve_env("HOME").unwrap();
+        assert_eq!(expand_tilde("~"), home);
+        assert_eq!(expand_tilde("~/.config"), format!("{}/.config", home));
+        assert_eq!(expand_tilde("~root/.config"), "/root/.config");
+        // Only a leading `~` is expanded
+        assert_eq!(expand_tilde("a/~/b"), "a/~/b");
+        assert_eq!(expand_tilde("backup~"), "backup~");
+        assert_eq!(
+            expand_tilde("~some_very_unlikely_user/x"),
+            "~some_very_unlikely_user/x"
+        );
+        assert_eq!(
+            expand_env_path(Path::new("~/$HOME"), false).unwrap(),
+            PathBuf::from(format!("{}/{}", home, home))
+        );
     }
 
     #[cfg(unix)]
     #[test]
     fn test_expand_subst() {
+        let expand_subst = |s| expand_subst(s, &CommandSettings::default()).unwrap();
         assert_eq!(expand_subst("$(echo 'Hello World')"), "Hello World");
         assert_eq!(expand_subst(" $(echo 'Hello World') "), " Hello World ");
         assert_eq!(expand_subst(" $(echo -n 'Hello World') "), " Hello World ");
@@ -117,5 +546,125 @@ mod tests {
             expand_subst(" \\$(echo -n 'Hello World') "),
             " \\$(echo -n 'Hello World') "
         );
+        assert_eq!(expand_subst("$(echo a) $(echo b)"), "a b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_subst_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        // Counts its own runs
+        let command = format!("echo >> {0:?}; wc -l < {0:?}", dir.path().join("runs"));
+        let settings = CommandSettings::default();
+        let expand = |s: &str| expand_subst(s, &settings).unwrap().trim().to_string();
+        assert_eq!(expand(&format!("$({})", command)), "1");
+        assert_eq!(expand(&format!("$({})", command)), "1");
+        assert_eq!(expand(&format!("$({}!)", command)), "2");
+        assert_eq!(expand(&format!("$({}!)", command)), "3");
+        assert_eq!(expand(&format!("$({})", command)), "1");
+    }
+
+    #[test]
+    fn test_references() {
+        let s = r"$HOME/${XDG_DIR} \$ESCAPED $(ls $NOT_A_VAR) $(date!)";
+        assert_eq!(referenced_env_vars(s), vec!["HOME", "XDG_DIR"]);
+        assert_eq!(referenced_commands(s), vec!["ls $NOT_A_VAR", "date"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_command_timeout() {
+        let timeout = |timeout| CommandSettings {
+            shell: None,
+            timeout: Some(timeout),
+            ..CommandSettings::default()
+        };
+        let output = run_command("echo fast", &timeout(Duration::from_secs(10))).unwrap();
+        assert_eq!(output.stdout, b"fast\n");
+        match run_command("sleep 10", &timeout(Duration::from_millis(50))) {
+            Err(e @ Error::CommandTimedOut(_, _)) => {
+                assert!(e.to_string().contains("sleep 10"));
+            }
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_describe_failure() {
+        let command = "echo oops >&2; exit 3";
+        let settings = CommandSettings::default();
+        let output = run_command(command, &settings).unwrap();
+        let message = describe_failure(command, &output);
+        assert!(message.contains("exit status: 3"), "{}", message);
+        assert!(message.ends_with(":\noops"), "{}", message);
+        let output = run_command("false", &settings).unwrap();
+        assert!(describe_failure("false", &output).ends_with("inserting nothing"));
+        assert_eq!(expand_subst("$(false!)", &settings).unwrap(), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_custom_shell() {
+        let invocation = vec!["bash".to_string(), "-c".to_string()];
+        let output = custom_shell(&invocation, "[[ dope == d* ]] && echo bash")
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, b"bash\n");
+        // The same command is cached per shell
+        let settings = CommandSettings {
+            shell: Some(vec!["bash".into(), "-c".into()]),
+            ..CommandSettings::default()
+        };
+        let command = "$(echo $0)";
+        assert_eq!(expand_subst(command, &settings).unwrap(), "bash");
+        let default = CommandSettings::default();
+        assert_eq!(expand_subst(command, &default).unwrap(), "sh");
+    }
+
+    #[test]
+    fn test_expand_percent() {
+        let expand_percent = |s| expand_percent(s, &mut vec![]);
+        ::std::env::set_var("DOTFILE_TESTING_PERCENT", "FUBAR");
+        assert_eq!(expand_percent("%DOTFILE_TESTING_PERCENT%"), "FUBAR");
+        assert_eq!(expand_percent(" %DOTFILE_TESTING_PERCENT% "), " FUBAR ");
+        assert_eq!(expand_percent("%SOME_VERY_UNLIKELY_VARIABLE%"), "");
+        assert_eq!(expand_percent(r"\%HOME%"), r"\%HOME%");
+        assert_eq!(expand_percent("100%"), "100%");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_expand_env_windows() {
+        let expand_env = |s| expand_env(s, false).unwrap();
+        ::std::env::set_var("DOTFILE_TESTING_STUFF", "FUBAR");
+        assert_eq!(expand_env("%DOTFILE_TESTING_STUFF%"), "FUBAR");
+        assert_eq!(expand_env("$DOTFILE_TESTING_STUFF"), "FUBAR");
+        assert_eq!(expand_env(r"\%HOME%"), r"\%HOME%");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_expand_subst_windows() {
+        let expand_subst = |s| expand_subst(s, &CommandSettings::default()).unwrap();
+        assert_eq!(expand_subst("$(echo Hello World)"), "Hello World");
+        assert_eq!(expand_subst(" $(echo \"quoted\") "), " \"quoted\" ");
+        assert_eq!(expand_subst("$(echo a) $(echo b)"), "a b");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_run_command_timeout_windows() {
+        let timeout = |timeout| CommandSettings {
+            shell: None,
+            timeout: Some(timeout),
+            ..CommandSettings::default()
+        };
+        let output = run_command("echo fast", &timeout(Duration::from_secs(10))).unwrap();
+        assert_eq!(output.stdout, b"fast\r\n");
+        match run_command("ping -n 10 127.0.0.1", &timeout(Duration::from_millis(50))) {
+            Err(Error::CommandTimedOut(_, _)) => {}
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
     }
 }
@@ -1,22 +1,50 @@
+// The `Fail` derive predates the `non_local_definitions` lint
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use regex::Error as RegexError;
 use toml::de::Error as TomlDeError;
+use toml::ser::Error as TomlSerError;
 
+use std::fmt;
 use std::io::Error as IOError;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Debug, Fail)]
 pub enum Error {
+    #[fail(display = "{}", _0)]
+    Multiple(ErrorList),
     #[fail(display = "Failed to load configuration file: {}", _0)]
     FailedToLoadConfiguration(#[cause] IOError),
     #[fail(display = "Failed to parse configuration file: {}", _0)]
     FailedToParseConfiguration(#[cause] TomlDeError),
-    #[fail(display = "Failed to parse regex: {}", _0)]
-    FailedToParseRegex(#[cause] RegexError),
+    #[fail(display = "Failed to check configuration for unknown keys: {}", _0)]
+    FailedToCheckConfiguration(#[cause] TomlSerError),
+    #[fail(display = "Unknown key {:?} in configuration file {:?}", _0, _1)]
+    UnknownConfigKey(String, PathBuf),
+    #[fail(display = "Substitution {:?} is redefined in {:?}", _0, _1)]
+    ConflictingSubstitution(String, PathBuf),
+    #[fail(display = "Failed to parse escape regex for {:?}: {}", _0, _1)]
+    FailedToParseRegex(String, #[cause] RegexError),
+    #[fail(display = "Failed to parse prefix regex for {:?}: {}", _0, _1)]
+    FailedToParsePrefixRegex(String, #[cause] RegexError),
+    #[fail(display = "Variable {:?} for substitution {:?} is not set", _1, _0)]
+    MissingEnvSubstitution(String, String),
+    #[fail(display = "Unknown substitution {:?}", _0)]
+    UnknownSubstitution(String),
+    #[fail(display = "Substitution cycle: {}", _0)]
+    SubstitutionCycle(String),
+    #[fail(display = "Escape start and end must not be empty for {:?}", _0)]
+    EmptyEscape(String),
+    #[fail(display = "Comment character must not be empty for {:?}", _0)]
+    EmptyCommentChar(String),
     #[fail(display = "Failed to read source file {:?}: {}", _0, _1)]
     FailedToReadSourceFile(String, #[cause] IOError),
+    #[fail(display = "Failed to create directory {:?}: {}", _0, _1)]
+    FailedToCreateDirectory(String, #[cause] IOError),
     #[fail(display = "Failed to open temp file {:?}: {}", _0, _1)]
     FailedToOpenTempFile(String, #[cause] IOError),
     #[fail(display = "Failed to write temp file {:?}: {}", _0, _1)]
@@ -26,19 +54,125 @@ pub enum Error {
         _1, _0, _2
     )]
     FailedToCreateTargetLink(String, String, #[cause] IOError),
+    #[fail(
+        display = "Failed to hardlink {:?} to {:?}: Different file systems, consider using `copy_fallback`",
+        _1, _0
+    )]
+    CrossDeviceHardlink(String, String),
+    #[fail(
+        display = "Failed to symlink {:?} to {:?}: Missing privileges, enable the Developer Mode, run as administrator or consider using `copy_fallback`",
+        _1, _0
+    )]
+    MissingSymlinkPrivilege(String, String),
+    #[fail(display = "No file configuration for source {:?}", _0)]
+    UnknownSource(PathBuf),
+    #[fail(
+        display = "Transaction failed for {} file(s), no links were changed",
+        _0
+    )]
+    TransactionFailed(usize),
+    #[fail(display = "{} of {} configurations failed: {}", _0, _1, _2)]
+    FilesFailed(usize, usize, String),
+    #[fail(display = "Environment variable {:?} is not set", _0)]
+    UndefinedEnvVar(String),
+    #[fail(display = "No source file for target {:?}", _0)]
+    EmptySource(PathBuf),
+    #[fail(
+        display = "Preprocessed file {:?} is missing, did preprocessing run?",
+        _0
+    )]
+    MissingPreprocessedFile(PathBuf),
+    #[fail(display = "The prefix must not be empty for {:?}", _0)]
+    EmptyPrefix(String),
+    #[fail(display = "Target {:?} is used by {:?} and {:?}", _0, _1, _2)]
+    DuplicateTarget(PathBuf, PathBuf, PathBuf),
     #[fail(display = "Target already exists: {:?}", _0)]
     TargetAlreadyExists(PathBuf),
+    #[fail(
+        display = "Source {:?} and target {:?} collide, the source, its preprocessed file and the target must differ",
+        _0, _1
+    )]
+    SourceTargetCollision(PathBuf, PathBuf),
+    #[fail(
+        display = "Refusing to remove {:?}, it does not link to the preprocessed file",
+        _0
+    )]
+    ForeignTarget(PathBuf),
+    #[fail(display = "Failed to remove {:?}: {}", _0, _1)]
+    FailedToRemoveFile(String, #[cause] IOError),
     #[fail(display = "Unrecognized preprocessor instruction: {:?}", _0)]
     UnrecognizedPreprocessorInstruction(String),
-    #[fail(display = "Stray command found in line {}: {}", _0, _1)]
+    #[fail(display = "Stray command found in line {}: {:?}", _0, _1)]
     StrayCmdFound(usize, String),
     #[fail(display = "Failed to read user input: {}", _0)]
     FailedToReadUserInput(IOError),
-    #[fail(display = "Missing ending command for line {}: {}", _0, _1)]
-    MissingEndingInstruction(usize, String),
+    #[fail(display = "Hook {:?} failed with {}", _0, _1)]
+    HookFailed(String, String),
+    #[fail(display = "Failed to read stdin: {}", _0)]
+    FailedToReadStdin(#[cause] IOError),
+    #[fail(
+        display = "Instruction {:?} in line {} must follow line {}",
+        _1, _0, _2
+    )]
+    InstructionOutOfOrder(usize, String, usize),
+    #[fail(display = "Missing {} for {:?} in line {}", _2, _1, _0)]
+    MissingEndingInstruction(usize, String, &'static str),
+    #[fail(
+        display = "No default answer for question {:?} in non-interactive mode",
+        _0
+    )]
+    MissingDefaultAnswer(String),
+    #[fail(display = "Invalid default answer {:?} for question {:?}", _1, _0)]
+    InvalidDefaultAnswer(String, String),
+    #[fail(
+        display = "Invalid answer {:?} for question {:?} in the answers file",
+        _1, _0
+    )]
+    InvalidAnswer(String, String),
+    #[fail(
+        display = "Invalid answer {:?} for question {:?} with tag {:?}",
+        _2, _0, _1
+    )]
+    InvalidTagAnswer(String, String, String),
+    #[fail(display = "Processing aborted in line {}: {}", _0, _1)]
+    AbortRequested(usize, String),
+    #[fail(display = "Failed to run command {:?}: {}", _0, _1)]
+    FailedToRunCommand(String, #[cause] IOError),
+    #[fail(display = "Command {:?} timed out after {:?}", _0, _1)]
+    CommandTimedOut(String, Duration),
+    #[fail(display = "The shell must not be empty")]
+    EmptyShell,
+    #[fail(display = "{:?} already exists, use `--force` to overwrite it", _0)]
+    ConfigAlreadyExists(PathBuf),
+    #[fail(display = "Failed to write configuration file {:?}: {}", _0, _1)]
+    FailedToWriteConfig(PathBuf, #[cause] IOError),
+}
+
+/// Errors reported together, see [`Error::Multiple`].
+#[derive(Debug)]
+pub struct ErrorList(pub Vec<Error>);
+
+/// Shows all errors, one per line.
+impl fmt::Display for ErrorList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<_> = self.0.iter().map(Error::to_string).collect();
+        write!(f, "{}", errors.join("\n"))
+    }
 }
 
 impl Error {
+    /// Combine `errors`, unless there is only one.
+    ///
+    /// # Panics
+    /// Panics, if `errors` is empty.
+    pub fn from_errors(mut errors: Vec<Error>) -> Self {
+        assert!(!errors.is_empty(), "BUG: no errors to combine");
+        if errors.len() == 1 {
+            errors.remove(0)
+        } else {
+            Error::Multiple(ErrorList(errors))
+        }
+    }
     pub fn as_load_config(ioe: IOError) -> Self {
         Error::FailedToLoadConfiguration(ioe)
     }
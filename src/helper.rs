@@ -1,9 +1,26 @@
+use crate::command_reader::Answers;
+use crate::config::Escape;
 use crate::error::{Error, Result};
-use std::path::Path;
+use crate::Opt;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+lazy_static! {
+    /// Temporary links and their targets, see [`link_atomically`].
+    static ref PENDING_LINKS: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(vec![]);
+}
 
 /// Construct a linker function for unix systems.
+///
+/// Creating symbolic links needs no privileges on unix, `copy_fallback` is ignored.
 #[cfg(unix)]
-pub fn get_link_function<P, Q>() -> impl FnMut(P, Q) -> Result<()>
+pub fn get_link_function<P, Q>(_copy_fallback: bool) -> impl FnMut(P, Q) -> Result<()>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
@@ -18,17 +35,496 @@ where
 }
 
 /// Construct a linker function for windows systems.
+///
+/// Directories need a directory symlink. Creating symbolic links requires the
+/// Developer Mode or administrator privileges. Without them, files are copied,
+/// if `copy_fallback` is set.
 #[cfg(windows)]
-pub fn get_link_function<P, Q>() -> impl FnMut(P, Q) -> Result<()>
+pub fn get_link_function<P, Q>(copy_fallback: bool) -> impl FnMut(P, Q) -> Result<()>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    |src, dst| {
-        std::os::windows::fs::symlink_file(src, dst).map_err(|e| {
-            let src_string = src.as_ref().to_string_lossy().into();
-            let dst_string = dst.as_ref().to_string_lossy().into();
-            Error::FailedToCreateTargetLink(src_string, dst_string, e)
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    /// The error code, if the privilege to create symbolic links is missing.
+    const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+    move |src, dst| {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        // Relative links are resolved from the directory of the link
+        let resolved = match dst.parent() {
+            Some(dir) => dir.join(src),
+            None => src.to_path_buf(),
+        };
+        let is_dir = resolved.is_dir();
+        let result = if is_dir {
+            symlink_dir(src, dst)
+        } else {
+            symlink_file(src, dst)
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => {
+                if copy_fallback && !is_dir {
+                    warn!(
+                        "Missing the privilege to create symbolic links, copying {:?} to {:?} instead",
+                        resolved, dst
+                    );
+                    fs::copy(&resolved, dst)
+                        .map(|_| ())
+                        .map_err(|e| Error::as_failed_link(&resolved, dst, e))
+                } else {
+                    let src_string = src.to_string_lossy().into();
+                    let dst_string = dst.to_string_lossy().into();
+                    Err(Error::MissingSymlinkPrivilege(src_string, dst_string))
+                }
+            }
+            Err(e) => Err(Error::as_failed_link(src, dst, e)),
+        }
+    }
+}
+
+/// Parse a `KEY=VALUE` pair.
+///
+/// Only the first `=` separates key and value, the value may contain more.
+pub fn parse_key_value(s: &str) -> ::std::result::Result<(String, String), String> {
+    match s.find('=') {
+        Some(idx) if idx > 0 => Ok((s[..idx].into(), s[idx + 1..].into())),
+        _ => Err(format!("Expected `KEY=VALUE`, got {:?}", s)),
+    }
+}
+
+/// Parse the escapes given with `--escape`, see [`Escape::parse`].
+pub fn parse_escape(s: &str) -> ::std::result::Result<Escape, String> {
+    Escape::parse(s).ok_or_else(|| format!("Expected a start and an end, got {:?}", s))
+}
+
+/// Load the answers file given with `--answers`.
+///
+/// The file is a TOML table of questions and their answers. Answers may be strings,
+/// numbers of options or booleans for yes-no-questions.
+pub fn load_answers(path: &str) -> ::std::result::Result<Answers, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+    let table: HashMap<String, toml::Value> =
+        toml::from_str(&content).map_err(|e| format!("{:?}: {}", path, e))?;
+    table
+        .into_iter()
+        .map(|(question, answer)| match answer {
+            toml::Value::String(answer) => Ok((question, answer)),
+            toml::Value::Integer(nr) => Ok((question, nr.to_string())),
+            toml::Value::Boolean(true) => Ok((question, "yes".into())),
+            toml::Value::Boolean(false) => Ok((question, "no".into())),
+            other => Err(format!(
+                "Invalid answer {} for question {:?}",
+                other, question
+            )),
+        })
+        .collect()
+}
+
+/// Create a hard link at `dst` pointing to `src`.
+///
+/// Hard links cannot cross file system boundaries. If `copy_fallback` is set,
+/// `src` is copied to `dst` in that case, otherwise an error is returned.
+pub fn hard_link_or_copy<P, Q>(src: P, dst: Q, copy_fallback: bool) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    hard_link_or_copy_with(src, dst, copy_fallback, |src, dst| fs::hard_link(src, dst))
+}
+
+/// Like [`hard_link_or_copy`], but uses the given function to create the hard link.
+fn hard_link_or_copy_with<P, Q, F>(src: P, dst: Q, copy_fallback: bool, link: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnOnce(&Path, &Path) -> io::Result<()>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    match link(src, dst) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::CrossesDevices && copy_fallback => {
+            warn!(
+                "Cannot hardlink {:?} to {:?} across devices, copying instead",
+                dst, src
+            );
+            fs::copy(src, dst)
+                .map(|_| ())
+                .map_err(|e| Error::as_failed_link(src, dst, e))
+        }
+        Err(ref e) if e.kind() == ErrorKind::CrossesDevices => {
+            let src_string = src.to_string_lossy().into();
+            let dst_string = dst.to_string_lossy().into();
+            Err(Error::CrossDeviceHardlink(src_string, dst_string))
+        }
+        Err(e) => Err(Error::as_failed_link(src, dst, e)),
+    }
+}
+
+/// Create a link at `dst` pointing to `src` using `link`, replacing `dst` atomically.
+///
+/// The link is created next to `dst` first and then renamed to `dst`. An interrupt
+/// thus never leaves `dst` missing, see [`recover_interrupted`] for the cleanup.
+pub fn link_atomically<P, Q, F>(src: P, dst: Q, link: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnOnce(&Path, &Path) -> Result<()>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let temp = pending_link_path(dst);
+    // Remove the leftovers of an earlier, interrupted run
+    if fs::symlink_metadata(&temp).is_ok() {
+        fs::remove_file(&temp).map_err(|e| Error::as_failed_link(src, &temp, e))?;
+    }
+    lock_pending_links().push((temp.clone(), dst.into()));
+    let result = link(src, &temp)
+        .and_then(|_| fs::rename(&temp, dst).map_err(|e| Error::as_failed_link(src, dst, e)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    lock_pending_links().retain(|(pending, _)| pending != &temp);
+    result
+}
+
+/// Remove the temporary links of all interrupted [`link_atomically`] calls.
+///
+/// Returns a message for every affected target.
+pub fn recover_interrupted() -> Vec<String> {
+    recover_links(mem::take(&mut *lock_pending_links()))
+}
+
+/// Remove the given temporary links and describe the state of their targets.
+fn recover_links(pending: Vec<(PathBuf, PathBuf)>) -> Vec<String> {
+    pending
+        .into_iter()
+        .map(|(temp, target)| match fs::remove_file(&temp) {
+            Ok(()) => format!("Interrupted while linking {:?}, it was left unchanged", target),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                format!("Interrupted while linking {:?}, it was already replaced", target)
+            }
+            Err(e) => format!(
+                "Interrupted while linking {:?}, it was left unchanged, but {:?} must be removed manually: {}",
+                target, temp, e
+            ),
         })
+        .collect()
+}
+
+/// Lock the pending links, even if another thread panicked.
+fn lock_pending_links() -> std::sync::MutexGuard<'static, Vec<(PathBuf, PathBuf)>> {
+    PENDING_LINKS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The path of the temporary link that will replace `dst`.
+fn pending_link_path(dst: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(dst.file_name().unwrap_or_default());
+    name.push(".dope-link");
+    dst.with_file_name(name)
+}
+
+/// Check whether both paths point to the same file.
+///
+/// This is always false on non-unix systems.
+pub fn is_same_file<P, Q>(a: P, b: Q) -> bool
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        false
+    }
+}
+
+/// Create all missing parent directories of `path`.
+pub fn create_parent_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    match path.as_ref().parent() {
+        Some(parent) if parent != Path::new("") => fs::create_dir_all(parent)
+            .map_err(|e| Error::FailedToCreateDirectory(parent.to_string_lossy().into(), e)),
+        _ => Ok(()),
+    }
+}
+
+/// Give `target` the permissions of `source`.
+///
+/// The owner may always write `target`, so it can be replaced later. This does
+/// nothing on non-unix systems.
+pub fn copy_mode<P, Q>(source: P, target: Q) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(source)?.permissions().mode() | 0o200;
+        fs::set_permissions(target, fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (source, target);
+        Ok(())
+    }
+}
+
+/// Shorten `path` for logs and reports.
+///
+/// The path is shown relative to `--relative-to` or, if not given, relative to
+/// the directory of the configuration file. See [`relative_path`].
+pub fn display_path<P: AsRef<Path>>(path: P, opt: &Opt) -> PathBuf {
+    let base = match opt.relative_to {
+        Some(ref base) => base.as_path(),
+        None => opt.config_root(),
+    };
+    relative_path(path, base)
+}
+
+/// Make `path` relative to `base`, if `path` is inside of `base`.
+///
+/// Both paths are canonicalized where possible, but a link in the last component
+/// of `path` is kept, see [`canonicalize_parent`]. Paths outside of `base` are
+/// returned absolute.
+pub fn relative_path<P, Q>(path: P, base: Q) -> PathBuf
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (path, base) = (path.as_ref(), base.as_ref());
+    let path = canonicalize_parent(path);
+    let base = canonicalize_lenient(base);
+    match path.strip_prefix(&base) {
+        Ok(relative) if relative == Path::new("") => PathBuf::from("."),
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+/// The path leading from the directory `dir` to `path`, i.e. `../b/file`
+/// from `/a/c` to `/a/b/file`.
+///
+/// Both paths should be absolute and canonicalized. `path` is returned unaltered,
+/// if they share no root, i.e. on different drives.
+pub fn path_from_dir<P, Q>(path: P, dir: Q) -> PathBuf
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (path, dir) = (path.as_ref(), dir.as_ref());
+    let mut path_components = path.components().peekable();
+    let mut dir_components = dir.components().peekable();
+    if path_components.peek() != dir_components.peek() {
+        return path.to_path_buf();
+    }
+    // Skip the common ancestors
+    while path_components.peek().is_some() && path_components.peek() == dir_components.peek() {
+        path_components.next();
+        dir_components.next();
+    }
+    let mut relative: PathBuf = dir_components.map(|_| Component::ParentDir).collect();
+    relative.extend(path_components);
+    relative
+}
+
+/// Turn `path` into a relative path, that stays below any directory it is joined to.
+///
+/// Only the normal components of `path` are kept, i.e. `/home/user/.bashrc`
+/// becomes `home/user/.bashrc`.
+pub fn mirror_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref()
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// Canonicalize `path`, even if its last component does not exist (yet).
+///
+/// The path is returned unaltered, if even the parent cannot be canonicalized.
+pub fn canonicalize_lenient(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent == Path::new("") {
+                Path::new(".")
+            } else {
+                parent
+            };
+            match parent.canonicalize() {
+                Ok(parent) => parent.join(name),
+                Err(_) => path.to_path_buf(),
+            }
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Canonicalize the parent of `path`, but keep its last component, even if it is a link.
+pub fn canonicalize_parent(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent != Path::new("") => {
+            canonicalize_lenient(parent).join(name)
+        }
+        (Some(_), Some(name)) => canonicalize_lenient(Path::new(".")).join(name),
+        _ => canonicalize_lenient(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_parse_key_value() {
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(parse_key_value("A=b"), Ok(pair("A", "b")));
+        assert_eq!(parse_key_value("A=b=c"), Ok(pair("A", "b=c")));
+        assert_eq!(parse_key_value("A="), Ok(pair("A", "")));
+        assert!(parse_key_value("A").is_err());
+        assert!(parse_key_value("=b").is_err());
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, "content").unwrap();
+        hard_link_or_copy(&src, &dst, false).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "content");
+        if cfg!(unix) {
+            assert!(is_same_file(&src, &dst));
+        }
+    }
+
+    #[test]
+    fn test_hard_link_cross_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, "content").unwrap();
+        let cross_device = |_: &Path, _: &Path| Err(io::Error::from(ErrorKind::CrossesDevices));
+        // Without fallback
+        match hard_link_or_copy_with(&src, &dst, false, cross_device) {
+            Err(Error::CrossDeviceHardlink(_, _)) => {}
+            other => panic!("Expected a cross device error, got {:?}", other),
+        }
+        assert!(!dst.exists());
+        // With fallback
+        hard_link_or_copy_with(&src, &dst, true, cross_device).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "content");
+        assert!(!is_same_file(&src, &dst));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_link_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let target = dir.path().join("target");
+        fs::write(&old, "old").unwrap();
+        fs::write(&new, "new").unwrap();
+        std::os::unix::fs::symlink(&old, &target).unwrap();
+        link_atomically(&new, &target, |src, dst| get_link_function(false)(src, dst)).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!pending_link_path(&target).exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_recover_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let target = dir.path().join("target");
+        fs::write(&old, "old").unwrap();
+        fs::write(&new, "new").unwrap();
+        std::os::unix::fs::symlink(&old, &target).unwrap();
+        // Interrupted after creating the new link, before replacing the target
+        let temp = pending_link_path(&target);
+        std::os::unix::fs::symlink(&new, &temp).unwrap();
+        let messages = recover_links(vec![(temp.clone(), target.clone())]);
+        assert_eq!(
+            messages,
+            vec![format!(
+                "Interrupted while linking {:?}, it was left unchanged",
+                target
+            )]
+        );
+        assert!(fs::symlink_metadata(&temp).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        fs::create_dir(base.join("sub")).unwrap();
+        fs::write(base.join("sub/file"), "").unwrap();
+        assert_eq!(
+            relative_path(base.join("sub/file"), &base),
+            PathBuf::from("sub/file")
+        );
+        // Non-existing files are fine
+        assert_eq!(
+            relative_path(base.join("sub/new"), &base),
+            PathBuf::from("sub/new")
+        );
+        assert_eq!(relative_path(&base, &base), PathBuf::from("."));
+        // Paths outside of base stay absolute
+        assert_eq!(relative_path(&base, base.join("sub")), base);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_relative_path_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        fs::create_dir(base.join("d")).unwrap();
+        fs::write(base.join("src.preprocessed"), "").unwrap();
+        std::os::unix::fs::symlink(base.join("src.preprocessed"), base.join("d/src")).unwrap();
+        // The link is named, not the file it points to
+        assert_eq!(
+            relative_path(base.join("d/src"), &base),
+            PathBuf::from("d/src")
+        );
+    }
+
+    #[test]
+    fn test_path_from_dir() {
+        assert_eq!(
+            path_from_dir("/a/b/file", "/a/c"),
+            PathBuf::from("../b/file")
+        );
+        assert_eq!(path_from_dir("/a/file", "/a"), PathBuf::from("file"));
+        assert_eq!(path_from_dir("/file", "/a/b"), PathBuf::from("../../file"));
+        assert_eq!(path_from_dir("relative", "/a"), PathBuf::from("relative"));
+    }
+
+    #[test]
+    fn test_display_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        let file = base.join("file");
+        let config = base.join("preprocessor.toml");
+        let config = config.to_str().unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config]);
+        assert_eq!(display_path(&file, &opt), PathBuf::from("file"));
+        let relative_to = base.join("sub");
+        fs::create_dir(&relative_to).unwrap();
+        let relative_to = relative_to.to_str().unwrap();
+        let opt = Opt::from_iter(&["dope", "-c", config, "--relative-to", relative_to]);
+        assert_eq!(display_path(&file, &opt), file);
     }
 }
@@ -0,0 +1,558 @@
+//! **dope**, the **do**tfile-**p**r**e**processor.
+//!
+//! Preprocess and link dotfiles. The binary is a thin wrapper around [`run`],
+//! [`process_string`] preprocesses a string without touching the file system.
+use structopt::StructOpt;
+
+use std::fs;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[macro_use]
+mod logging;
+pub mod command;
+pub mod command_reader;
+pub mod config;
+pub mod env;
+pub mod error;
+mod helper;
+pub mod report;
+
+pub use command::Command;
+pub use command_reader::CommandReader;
+pub use config::{Config, Dependencies, Escape, FileConfig, Prefix, Shell, Source, Substitutions};
+pub use env::{expand, expand_env, expand_env_path, expand_subst, expand_tilde, CommandSettings};
+pub use error::{Error, Result};
+pub use report::{FileReport, Format};
+
+use logging::{report, Level};
+
+/// The configuration written by `--init`.
+const INIT_TEMPLATE: &str = include_str!("init.toml");
+
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "dotfile-preprocessor")]
+pub struct Opt {
+    /// Specify the TOML configuration file. May be given more than once.
+    /// Defaults to `./preprocessor.toml`, if no `--config-dir` is given.
+    #[structopt(long = "config", short, number_of_values = 1, value_name = "file")]
+    config_files: Vec<PathBuf>,
+    /// Load every `*.toml` file in this directory as a configuration file.
+    #[structopt(long, value_name = "dir")]
+    config_dir: Option<PathBuf>,
+    /// Fail, if configuration files define the same substitution differently or a
+    /// file uses an unknown substitution.
+    #[structopt(long)]
+    strict: bool,
+    /// Fail on unknown keys in configuration files, i.e. misspelled options, instead of
+    /// ignoring them with a warning.
+    #[structopt(long)]
+    strict_config: bool,
+    /// Only print errors.
+    #[structopt(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print more details, may be given twice.
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Panic on the first error, instead of continuing with the next configuration file.
+    #[structopt(long, short)]
+    panic: bool,
+    /// Do not prompt, but use the default answer for every question.
+    /// Fails for questions without a default.
+    #[structopt(
+        long = "non-interactive",
+        short = "y",
+        visible_aliases = &["yes", "no-input"]
+    )]
+    non_interactive: bool,
+    /// Only process the file configurations with this `name`. May be given more than once.
+    #[structopt(long, number_of_values = 1, value_name = "name")]
+    only: Vec<String>,
+    /// Do not process the file configurations with this `name`. May be given more than once.
+    #[structopt(long, number_of_values = 1, value_name = "name")]
+    skip: Vec<String>,
+    /// Select the files to process from a list, instead of processing all.
+    /// Ignored with `--non-interactive`.
+    #[structopt(long)]
+    interactive_select: bool,
+    /// Set a substitution, overriding the configuration file. Expects `KEY=VALUE`.
+    #[structopt(
+        long = "set",
+        short = "D",
+        visible_alias = "define",
+        number_of_values = 1,
+        parse(try_from_str = helper::parse_key_value)
+    )]
+    set: Vec<(String, String)>,
+    /// Answer questions with the answers in this TOML file, i.e. `"Laptop?" = "yes"`.
+    /// Options are answered with their number. Other questions are asked as usual.
+    #[structopt(
+        long,
+        value_name = "file",
+        parse(try_from_str = helper::load_answers)
+    )]
+    answers: Option<command_reader::Answers>,
+    /// Answer all questions with the given tag, i.e. `ui=yes` for `ASK[ui] Animations?`.
+    /// Answers in the `--answers` file win. May be given more than once.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        value_name = "tag=answer",
+        parse(try_from_str = helper::parse_key_value)
+    )]
+    answer_tag: Vec<(String, String)>,
+    /// Fail on environment variables that are not set, instead of expanding them
+    /// to nothing.
+    #[structopt(long)]
+    strict_env: bool,
+    /// Exit with a failure, if any warning was emitted.
+    #[structopt(long)]
+    fail_on_warning: bool,
+    /// Preprocess without writing or linking anything. Reports the answers to all questions.
+    #[structopt(long)]
+    dry_run: bool,
+    /// Show paths in logs relative to this directory, if possible.
+    /// Defaults to the directory of the configuration file.
+    #[structopt(long, value_name = "dir")]
+    relative_to: Option<PathBuf>,
+    /// Number of files to process in parallel. Defaults to the number of cpus.
+    /// Use `--jobs 1` for sequential processing.
+    #[structopt(long, short)]
+    jobs: Option<NonZeroUsize>,
+    /// Link files only if all files were preprocessed successfully.
+    /// Links are rolled back, if linking any file fails.
+    #[structopt(long)]
+    transactional: bool,
+    /// The output format, `human` or `json`. JSON reports are printed to stdout at the end,
+    /// as one array with an object per file, and replace the human log messages.
+    #[structopt(long, default_value = "human", value_name = "format")]
+    format: Format,
+    /// Print one JSON object per line instead of an array. Implies `--format json`.
+    #[structopt(long)]
+    jsonl: bool,
+    /// List the environment variables, commands and substitutions every source file
+    /// depends on, instead of processing the files. Nothing is expanded or run.
+    #[structopt(long)]
+    list_vars: bool,
+    /// Do not insert substitutions, escaped keys are kept as they are.
+    #[structopt(long)]
+    no_substitute: bool,
+    /// Do not evaluate preprocessor instructions, instruction lines and the header
+    /// are kept as they are. Together with `--no-substitute`, sources are copied verbatim.
+    #[structopt(long)]
+    no_instructions: bool,
+    /// Preprocess stdin and write the result to stdout, without any configuration file.
+    /// Substitutions are given by `--set`, questions are answered with their defaults.
+    #[structopt(long)]
+    stdin: bool,
+    /// The instruction prefix used with `--stdin`, i.e. `#~`.
+    #[structopt(long, requires = "stdin", value_name = "prefix")]
+    prefix: Option<String>,
+    /// The escapes used with `--stdin`, separated by whitespace, i.e. `"{{ }}"`.
+    #[structopt(
+        long,
+        requires = "stdin",
+        value_name = "escape",
+        parse(try_from_str = helper::parse_escape)
+    )]
+    escape: Option<Escape>,
+    /// Check the configuration, that all sources can be read and no two files share a
+    /// target, without processing any file.
+    #[structopt(long)]
+    check: bool,
+    /// Print the removed lines and applied substitutions of each file after processing.
+    #[structopt(long)]
+    stats: bool,
+    /// Write a commented starter `preprocessor.toml` to the current directory and exit.
+    #[structopt(long)]
+    init: bool,
+    /// Overwrite an existing `preprocessor.toml` with `--init`.
+    #[structopt(long, requires = "init")]
+    force: bool,
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// List the instructions found in a source file, using its configured prefix.
+    Commands {
+        /// The source file, as configured in the configuration file.
+        source: PathBuf,
+    },
+    /// Remove the targets linking to preprocessed files and the preprocessed files.
+    /// Targets, that are not linked by dope, are never removed.
+    Clean {
+        /// Move `<target>.bak` back to the target, if it exists.
+        #[structopt(long)]
+        restore_backup: bool,
+    },
+}
+
+impl Opt {
+    /// Whether JSON reports are printed instead of log messages.
+    fn json(&self) -> bool {
+        self.format == Format::Json || self.jsonl
+    }
+    /// The most verbose log level to print.
+    ///
+    /// JSON output only keeps the errors, unless `--verbose` is given.
+    fn log_level(&self) -> Level {
+        match (self.quiet || self.json(), self.verbose) {
+            (true, 0) => Level::Error,
+            (false, 0) => Level::Info,
+            (_, 1) => Level::Debug,
+            (_, _) => Level::Trace,
+        }
+    }
+    /// Expand `~` and environment variables in `--config` and `--config-dir`.
+    ///
+    /// The shell does not expand them everywhere, i.e. in quotes.
+    fn expand_config_paths(&mut self) -> Result<()> {
+        for path in &mut self.config_files {
+            *path = expand_env_path(path, self.strict_env)?;
+        }
+        if let Some(ref mut dir) = self.config_dir {
+            *dir = expand_env_path(dir, self.strict_env)?;
+        }
+        Ok(())
+    }
+    /// All configuration files to load, in order.
+    ///
+    /// The files in `--config-dir` come first, sorted by name.
+    fn config_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        if let Some(ref dir) = self.config_dir {
+            for entry in fs::read_dir(dir).map_err(Error::as_load_config)? {
+                let path = entry.map_err(Error::as_load_config)?.path();
+                if path.extension() == Some("toml".as_ref()) && path.is_file() {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+        }
+        paths.extend(self.config_files.iter().cloned());
+        if self.config_dir.is_none() && paths.is_empty() {
+            paths.push(PathBuf::from("./preprocessor.toml"));
+        }
+        Ok(paths)
+    }
+    /// The directory paths in logs are shown relative to by default.
+    ///
+    /// This is `--config-dir` or the directory of the first configuration file.
+    fn config_root(&self) -> &Path {
+        let root = match (&self.config_dir, self.config_files.first()) {
+            (Some(dir), _) => Some(dir.as_path()),
+            (None, Some(file)) => file.parent(),
+            (None, None) => None,
+        };
+        match root {
+            Some(root) if root != Path::new("") => root,
+            _ => Path::new("."),
+        }
+    }
+}
+
+/// Run dope with the given options and return the exit code.
+pub fn run(mut opt: Opt) -> i32 {
+    logging::set_level(opt.log_level());
+    // Clean up half created links on Ctrl-C
+    let handler = ctrlc::set_handler(|| {
+        for message in helper::recover_interrupted() {
+            warn!("{}", message);
+        }
+        error!("Interrupted");
+        process::exit(130);
+    });
+    if let Err(e) = handler {
+        warn!("Failed to install the interrupt handler: {}", e);
+    }
+    if opt.stdin {
+        let success = process_stdin(&opt).inspect_err(report).is_ok();
+        return exit_code(success, &opt);
+    }
+    if opt.init {
+        return match init(Path::new("."), opt.force) {
+            Ok(path) => {
+                info!("Created {:?}", path);
+                0
+            }
+            Err(e) => {
+                report(&e);
+                1
+            }
+        };
+    }
+    if let Err(e) = opt.expand_config_paths() {
+        report(&e);
+        return 1;
+    }
+    // Load TOML configuration files
+    let config = match opt
+        .config_paths()
+        .and_then(|paths| Config::load_all(&paths, &opt))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            report(&e);
+            return 1;
+        }
+    };
+    if let Some(Subcommand::Commands { ref source }) = opt.subcommand {
+        let instructions = config
+            .find_source(source)
+            .and_then(|(root, fc)| fc.list_instructions(root));
+        return match instructions {
+            Ok(instructions) => {
+                instructions.iter().for_each(|line| println!("{}", line));
+                0
+            }
+            Err(e) => {
+                report(&e);
+                1
+            }
+        };
+    }
+    if opt.check {
+        let success = config.validate(&opt).inspect_err(report).is_ok();
+        if success {
+            info!("The configuration is valid");
+        }
+        return exit_code(success, &opt);
+    }
+    if opt.list_vars {
+        let success = list_dependencies(&config, &opt);
+        return exit_code(success, &opt);
+    }
+    if let Some(Subcommand::Clean { restore_backup }) = opt.subcommand {
+        let success = config.clean_files(restore_backup, &opt).is_ok();
+        return exit_code(success, &opt);
+    }
+    // Process files
+    // All errors should have already been reported at this point
+    let (reports, result) = config.process_files_reported(&opt);
+    if opt.json() {
+        report::print_reports(&reports, opt.jsonl);
+    } else if opt.stats {
+        report::print_stats(&reports, &opt);
+    }
+    let success = result.is_ok();
+    exit_code(success, &opt)
+}
+
+/// Settings for [`process_string`].
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// The line prefix of instructions, instructions are kept without one.
+    pub prefix: Option<String>,
+    /// The escape surrounding substitutions, nothing is substituted without one.
+    pub escape: Option<Escape>,
+    /// How `$(...)` commands are run and unset environment variables are handled.
+    pub command_settings: CommandSettings,
+}
+
+/// Preprocess `content` like a source file with the given `options`.
+///
+/// Nothing is read from or written to the file system. Questions are not asked,
+/// their default answers are used.
+///
+/// # Errors
+/// Fails, if an instruction is invalid or fails, see [`Error`].
+pub fn process_string(
+    content: &str,
+    substitutions: &Substitutions,
+    options: &ProcessOptions,
+) -> Result<String> {
+    let opt = Opt {
+        non_interactive: true,
+        ..Opt::default()
+    };
+    let prefix = options.prefix.as_deref().map(Prefix::from);
+    let mut fc = FileConfig::new("", "");
+    fc.supplement(&options.escape, true, &prefix, &None, None, &None);
+    fc.set_command_settings(options.command_settings.clone());
+    fc.preprocess_content(content, substitutions, &opt)
+}
+
+/// Preprocess stdin with `--prefix`, `--escape` and the `--set` substitutions and
+/// write the result to stdout, see [`process_string`].
+fn process_stdin(opt: &Opt) -> Result<()> {
+    let mut content = String::new();
+    io::stdin()
+        .read_to_string(&mut content)
+        .map_err(Error::FailedToReadStdin)?;
+    let substitutions = opt.set.iter().cloned().collect();
+    let options = ProcessOptions {
+        prefix: opt.prefix.clone(),
+        escape: opt.escape.clone(),
+        command_settings: CommandSettings {
+            strict_env: opt.strict_env,
+            ..CommandSettings::default()
+        },
+    };
+    let output = process_string(&content, &substitutions, &options)?;
+    print!("{}", output);
+    Ok(())
+}
+
+/// Write the starter configuration to `preprocessor.toml` in `dir` and return its path.
+///
+/// An existing file is only overwritten with `force`.
+fn init(dir: &Path, force: bool) -> Result<PathBuf> {
+    let path = dir.join("preprocessor.toml");
+    if path.exists() && !force {
+        return Err(Error::ConfigAlreadyExists(path));
+    }
+    fs::write(&path, INIT_TEMPLATE).map_err(|e| Error::FailedToWriteConfig(path.clone(), e))?;
+    Ok(path)
+}
+
+/// Print the dependencies of all selected source files, each followed by one indented
+/// line per dependency.
+///
+/// Returns whether all source files could be scanned.
+fn list_dependencies(config: &Config, opt: &Opt) -> bool {
+    let mut success = true;
+    let selected = config
+        .file_configurations
+        .iter()
+        .filter(|(_, fc)| fc.is_selected(opt));
+    for (root, fc) in selected {
+        match fc.dependencies(root) {
+            Ok(dependencies) => {
+                let source = helper::display_path(fc.source_path(root), opt);
+                println!("{}", source.display());
+                dependencies
+                    .lines()
+                    .iter()
+                    .for_each(|line| println!("    {}", line));
+            }
+            Err(e) => {
+                report(&e);
+                success = false;
+            }
+        }
+    }
+    success
+}
+
+/// Determine the exit code of the run.
+///
+/// Warnings fail the run only if `--fail-on-warning` is given.
+fn exit_code(success: bool, opt: &Opt) -> i32 {
+    if !success || (opt.fail_on_warning && logging::warning_emitted()) {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fail_on_warning() {
+        let lenient = Opt::from_iter(&["dope"]);
+        let strict = Opt::from_iter(&["dope", "--fail-on-warning"]);
+        warn!("This is a test warning");
+        assert_eq!(exit_code(true, &lenient), 0);
+        assert_eq!(exit_code(true, &strict), 1);
+        assert_eq!(exit_code(false, &lenient), 1);
+    }
+
+    #[test]
+    fn test_process_string() {
+        let escape = Escape {
+            start: "{{".into(),
+            end: "}}".into(),
+        };
+        let mut substitutions = Substitutions::new();
+        substitutions.insert("NAME".into(), "dope".into());
+        let options = ProcessOptions {
+            prefix: Some("#~".into()),
+            escape: Some(escape),
+            command_settings: CommandSettings::default(),
+        };
+        let content = "#~ IF a == b\nhidden\n#~ ENDIF\nname = {{NAME}}";
+        let output = process_string(content, &substitutions, &options);
+        assert_eq!(output.unwrap(), "name = dope");
+        // Unset variables fail only if chosen
+        let content = "{{$DOPE_TEST_UNSET_VARIABLE}}";
+        let output = process_string(content, &substitutions, &options);
+        assert_eq!(output.unwrap(), "");
+        let strict = ProcessOptions {
+            command_settings: CommandSettings {
+                strict_env: true,
+                ..CommandSettings::default()
+            },
+            ..options.clone()
+        };
+        assert!(process_string(content, &substitutions, &strict).is_err());
+        // Commands run in the given shell
+        if cfg!(unix) {
+            let options = ProcessOptions {
+                command_settings: CommandSettings {
+                    shell: Some(vec!["bash".into(), "-c".into()]),
+                    ..CommandSettings::default()
+                },
+                ..options
+            };
+            let output = process_string("{{$(echo $0)}}", &substitutions, &options);
+            assert_eq!(output.unwrap(), "bash");
+        }
+    }
+
+    #[test]
+    fn test_set_aliases() {
+        let args = &["dope", "--set", "A=1", "-D", "B=2", "--define", "C=3=4"];
+        let set = Opt::from_iter(args).set;
+        let expected = [("A", "1"), ("B", "2"), ("C", "3=4")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(set, expected);
+        assert!(Opt::from_iter_safe(&["dope", "-D", "EDITOR"]).is_err());
+    }
+
+    #[test]
+    fn test_expand_config_paths() {
+        let home = PathBuf::from(expand_tilde("~").into_owned());
+        let mut opt = Opt::from_iter(&["dope", "-c", "~/dotfiles/preprocessor.toml"]);
+        opt.expand_config_paths().unwrap();
+        let expected = home.join("dotfiles/preprocessor.toml");
+        assert_eq!(opt.config_paths().unwrap(), vec![expected]);
+        assert_eq!(opt.config_root(), home.join("dotfiles"));
+    }
+
+    #[test]
+    fn test_init() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = init(dir.path(), false).unwrap();
+        let opt = Opt::from_iter(&["dope", "--strict-config"]);
+        let config = Config::load_all(&[&path], &opt).unwrap();
+        assert_eq!(config.file_configurations.len(), 1);
+        assert!(init(dir.path(), false).is_err());
+        fs::write(&path, "").unwrap();
+        init(dir.path(), true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), INIT_TEMPLATE);
+        assert!(Opt::from_iter_safe(&["dope", "--force"]).is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        let level = |args: &[&str]| Opt::from_iter(args).log_level();
+        assert_eq!(level(&["dope"]), Level::Info);
+        assert_eq!(level(&["dope", "-q"]), Level::Error);
+        assert_eq!(level(&["dope", "-v"]), Level::Debug);
+        assert_eq!(level(&["dope", "-vvv"]), Level::Trace);
+        assert!(Opt::from_iter_safe(&["dope", "-q", "-v"]).is_err());
+        assert_eq!(level(&["dope", "--format", "json"]), Level::Error);
+        assert_eq!(level(&["dope", "--jsonl", "-v"]), Level::Debug);
+        // Errors are always printed
+        assert!(logging::enabled(Level::Error));
+    }
+}
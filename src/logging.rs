@@ -1,21 +1,69 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::error::Error;
+
+/// Whether any warning has been emitted.
+pub static WARNING_EMITTED: AtomicBool = AtomicBool::new(false);
+
+/// The most verbose [`Level`] that is printed.
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// The importance of a log message. Errors are always printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Print messages up to the given `level`, see [`Level`].
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::SeqCst);
+}
+
+/// Whether messages of the given `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level as usize <= LEVEL.load(Ordering::SeqCst)
+}
+
+/// Whether any warning has been emitted during this run.
+pub fn warning_emitted() -> bool {
+    WARNING_EMITTED.load(Ordering::SeqCst)
+}
+
 macro_rules! warn {
     ( $($s:expr),* ) => {
-        log!( $($s),* ; "WARN"; |s: &str| s.yellow() )
+        {
+            $crate::logging::WARNING_EMITTED.store(true, ::std::sync::atomic::Ordering::SeqCst);
+            log!( $($s),* ; Warn; "WARN"; |s: &str| s.yellow() )
+        }
     }
 }
 macro_rules! error {
     ( $($s:expr),* ) => {
-        log!( $($s),* ; "ERR "; |s: &str| s.red().bold() )
+        log!( $($s),* ; Error; "ERR "; |s: &str| s.red().bold() )
     }
 }
 macro_rules! info {
     ( $($s:expr),* ) => {
-        log!( $($s),* ; "INFO"; |s: &str| s.dimmed() )
+        log!( $($s),* ; Info; "INFO"; |s: &str| s.dimmed() )
+    }
+}
+macro_rules! debug {
+    ( $($s:expr),* ) => {
+        log!( $($s),* ; Debug; "DBG "; |s: &str| s.dimmed() )
+    }
+}
+macro_rules! trace {
+    ( $($s:expr),* ) => {
+        log!( $($s),* ; Trace; "TRCE"; |s: &str| s.dimmed().italic() )
     }
 }
 macro_rules! log {
-    ( $($s:expr),* ; $prefix:expr; $fn:expr ) => {
-        {
+    ( $($s:expr),* ; $level:ident; $prefix:expr; $fn:expr ) => {
+        if $crate::logging::enabled($crate::logging::Level::$level) {
             use textwrap::Wrapper;
             use colored::Colorize;
             let s = format!($($s),*);
@@ -37,3 +85,11 @@ macro_rules! log {
         }
     }
 }
+
+/// Log `e` as error. Each of [`Error::Multiple`] is logged on its own.
+pub fn report(e: &Error) {
+    match e {
+        Error::Multiple(errors) => errors.0.iter().for_each(report),
+        e => error!("{}", e),
+    }
+}
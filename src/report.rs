@@ -0,0 +1,202 @@
+//! Machine readable reports of processed files, see `--format json`.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::FileConfig;
+use crate::error::Error;
+use crate::helper::display_path;
+use crate::Opt;
+
+/// The output format of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Log messages for humans.
+    #[default]
+    Human,
+    /// A JSON array with one [`FileReport`] per file.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown format {:?}, expected human or json", s)),
+        }
+    }
+}
+
+/// The outcome of processing a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    /// The expanded source path.
+    pub source: PathBuf,
+    /// The expanded target path.
+    pub target: PathBuf,
+    /// The path of the preprocessed file.
+    pub temp: PathBuf,
+    /// Whether the target was linked.
+    pub linked: bool,
+    /// The source lines, that are not part of the output.
+    pub skipped_lines: Vec<usize>,
+    /// The number of replaced substitution keys.
+    pub substitutions: usize,
+    /// All errors, that occured while processing the file.
+    pub errors: Vec<String>,
+}
+
+impl FileReport {
+    /// Create an empty report for `fc`, with paths relative to `root`.
+    pub fn new(root: &Path, fc: &FileConfig) -> Self {
+        FileReport {
+            source: fc.source_path(root),
+            target: fc.target_path(root),
+            temp: fc.temp_path(root),
+            linked: false,
+            skipped_lines: vec![],
+            substitutions: 0,
+            errors: vec![],
+        }
+    }
+    /// Record `e`, every error of an [`Error::Multiple`] on its own.
+    pub fn add_error(&mut self, e: &Error) {
+        match e {
+            Error::Multiple(errors) => errors.0.iter().for_each(|e| self.add_error(e)),
+            e => self.errors.push(e.to_string()),
+        }
+    }
+    /// Encode the report as a JSON object.
+    pub fn to_json(&self) -> String {
+        let skipped_lines: Vec<_> = self.skipped_lines.iter().map(usize::to_string).collect();
+        let errors: Vec<_> = self.errors.iter().map(|e| json_string(e)).collect();
+        format!(
+            r#"{{"source":{},"target":{},"temp":{},"linked":{},"skipped_lines":[{}],"substitutions":{},"errors":[{}]}}"#,
+            json_string(&self.source.to_string_lossy()),
+            json_string(&self.target.to_string_lossy()),
+            json_string(&self.temp.to_string_lossy()),
+            self.linked,
+            skipped_lines.join(","),
+            self.substitutions,
+            errors.join(",")
+        )
+    }
+}
+
+/// Print the `reports` to stdout as one JSON array or, with `lines`, one object per line.
+pub fn print_reports(reports: &[FileReport], lines: bool) {
+    let objects: Vec<_> = reports.iter().map(FileReport::to_json).collect();
+    if lines {
+        objects.iter().for_each(|object| println!("{}", object));
+    } else {
+        println!("[{}]", objects.join(","));
+    }
+}
+
+/// Print the removed lines and applied substitutions of all `reports` as a table to
+/// stdout, see `--stats`.
+pub fn print_stats(reports: &[FileReport], opt: &Opt) {
+    print!("{}", stats_table(reports, opt));
+}
+
+/// Format the table printed by [`print_stats`], one row per report and a total.
+fn stats_table(reports: &[FileReport], opt: &Opt) -> String {
+    let mut rows: Vec<_> = reports
+        .iter()
+        .map(|report| {
+            let source = display_path(&report.source, opt).display().to_string();
+            (source, report.skipped_lines.len(), report.substitutions)
+        })
+        .collect();
+    let removed = rows.iter().map(|(_, removed, _)| removed).sum();
+    let substitutions = rows.iter().map(|(_, _, substitutions)| substitutions).sum();
+    rows.push(("Total".into(), removed, substitutions));
+    let width = rows
+        .iter()
+        .map(|(source, _, _)| source.len())
+        .max()
+        .unwrap_or(0);
+    let mut table = format!(
+        "{:<width$}  Removed  Substitutions\n",
+        "File",
+        width = width
+    );
+    for (source, removed, substitutions) in rows {
+        table.push_str(&format!(
+            "{:<width$}  {:>7}  {:>13}\n",
+            source,
+            removed,
+            substitutions,
+            width = width
+        ));
+    }
+    table
+}
+
+/// Encode `s` as a JSON string, including the quotes.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_to_json() {
+        let report = FileReport {
+            source: "dir/source".into(),
+            target: "target".into(),
+            temp: "dir/source.preprocessed".into(),
+            linked: false,
+            skipped_lines: vec![2, 3],
+            substitutions: 4,
+            errors: vec!["Missing \"ENDIF\"\n\tin line 1\u{1}".into()],
+        };
+        assert_eq!(
+            report.to_json(),
+            r#"{"source":"dir/source","target":"target","temp":"dir/source.preprocessed","linked":false,"skipped_lines":[2,3],"substitutions":4,"errors":["Missing \"ENDIF\"\n\tin line 1\u0001"]}"#
+        );
+    }
+
+    #[test]
+    fn test_stats_table() {
+        let opt = Opt::from_iter(&["dope", "--relative-to", "/dotfiles"]);
+        let report = |source: &str, skipped_lines, substitutions| FileReport {
+            source: source.into(),
+            target: "target".into(),
+            temp: "temp".into(),
+            linked: true,
+            skipped_lines,
+            substitutions,
+            errors: vec![],
+        };
+        let reports = [
+            report("/dotfiles/git/config", vec![1, 2, 5], 12),
+            report("/dotfiles/vimrc", vec![], 1),
+        ];
+        let expected = "File        Removed  Substitutions\n\
+                        git/config        3             12\n\
+                        vimrc             0              1\n\
+                        Total             3             13\n";
+        assert_eq!(stats_table(&reports, &opt), expected);
+    }
+}
@@ -0,0 +1,124 @@
+//! Tests of the whole pipeline, from loading the configuration to linking the targets.
+
+use dotfile_preprocessor::{run, Config, Opt};
+use structopt::StructOpt;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const CONFIG: &str = r##"
+default_escape = ["{{", "}}"]
+default_prefix = "#~"
+
+[substitutions]
+NAME = "dope"
+
+[[config]]
+source = "source"
+target = "target"
+"##;
+
+const SOURCE: &str = "\
+#~ IFDEF $DOPE_PIPELINE_DEFINED
+defined = true
+#~ ELSE
+defined = false
+#~ ENDIF
+#~ ASK [y] Is this a laptop?
+battery = true
+#~ ENDASK
+#~ ASK Which color?
+#~ OPTION red
+color = red
+#~ OPTION* green
+color = green
+#~ ENDASK
+name = {{NAME}}
+";
+
+/// Write the configuration and the source to `dir`, returning the configuration path.
+fn setup(dir: &Path) -> String {
+    std::env::set_var("DOPE_PIPELINE_DEFINED", "yes");
+    let config_path = dir.join("preprocessor.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+    fs::write(dir.join("source"), SOURCE).unwrap();
+    config_path.to_str().unwrap().into()
+}
+
+#[test]
+fn test_process_and_link() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = setup(dir.path());
+    let opt = Opt::from_iter(&["dope", "-c", &config_path, "--non-interactive"]);
+    let config = Config::load(&config_path).unwrap();
+    config.process_files(&opt).unwrap();
+    let temp = dir.path().join("source.preprocessed");
+    let expected = "defined = true\nbattery = true\ncolor = green\nname = dope\n";
+    assert_eq!(fs::read_to_string(&temp).unwrap(), expected);
+    assert_eq!(
+        fs::read_to_string(dir.path().join("target")).unwrap(),
+        expected
+    );
+    #[cfg(unix)]
+    assert_eq!(fs::read_link(dir.path().join("target")).unwrap(), temp);
+}
+
+#[test]
+fn test_run_with_answers_and_clean() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = setup(dir.path());
+    let answers = dir.path().join("answers.toml");
+    fs::write(
+        &answers,
+        "\"Is this a laptop?\" = false\n\"Which color?\" = 1\n",
+    )
+    .unwrap();
+    let answers = answers.to_str().unwrap();
+    let args = &["dope", "-c", &config_path, "-y", "--answers", answers];
+    assert_eq!(run(Opt::from_iter(args)), 0);
+    let target = dir.path().join("target");
+    let expected = "defined = true\ncolor = red\nname = dope\n";
+    assert_eq!(fs::read_to_string(&target).unwrap(), expected);
+    // Cleaning removes the target and the preprocessed file
+    assert_eq!(
+        run(Opt::from_iter(&["dope", "-c", &config_path, "clean"])),
+        0
+    );
+    assert!(fs::symlink_metadata(&target).is_err());
+    assert!(!dir.path().join("source.preprocessed").exists());
+    assert!(dir.path().join("source").exists());
+}
+
+#[test]
+fn test_run_missing_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = setup(dir.path());
+    // Without the marked option, the question has no default
+    let source = SOURCE.replace("OPTION* green", "OPTION green");
+    fs::write(dir.path().join("source"), source).unwrap();
+    assert_eq!(run(Opt::from_iter(&["dope", "-c", &config_path, "-y"])), 1);
+    assert!(fs::symlink_metadata(dir.path().join("target")).is_err());
+}
+
+#[test]
+fn test_stdin_filter() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dope"))
+        .args(["--stdin", "--prefix", "#~", "--escape", "{{ }}"])
+        .args(["-D", "NAME=dope"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = "#~ IF a == b\nhidden\n#~ ENDIF\nname = {{NAME}}\n";
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "name = dope\n");
+}